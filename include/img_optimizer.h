@@ -0,0 +1,60 @@
+#ifndef IMG_OPTIMIZER_H
+#define IMG_OPTIMIZER_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum OptimizerEncoder {
+  OPTIMIZER_ENCODER_WEBP,
+  OPTIMIZER_ENCODER_MOZJPEG,
+  OPTIMIZER_ENCODER_AVIF,
+  OPTIMIZER_ENCODER_JPEGXL,
+  OPTIMIZER_ENCODER_PNG,
+} OptimizerEncoder;
+
+typedef struct OptimizerOutput {
+  uint8_t *data;
+  size_t len;
+} OptimizerOutput;
+
+/**
+ * # Safety
+ *
+ * `input` must point to `input_len` readable bytes and `output` must be writable.
+ */
+int optimizer_compress(const uint8_t *input,
+                       size_t input_len,
+                       OptimizerEncoder encoder,
+                       float quality,
+                       OptimizerOutput *output);
+
+/**
+ * # Safety
+ *
+ * `input` must point to `input_len` readable bytes and `output` must be writable.
+ */
+int optimizer_resize_and_compress(const uint8_t *input,
+                                  size_t input_len,
+                                  uint32_t width,
+                                  uint32_t height,
+                                  OptimizerEncoder encoder,
+                                  float quality,
+                                  OptimizerOutput *output);
+
+/**
+ * # Safety
+ *
+ * `output` must have been filled in by this library and not freed already.
+ */
+void optimizer_free(OptimizerOutput output);
+
+void optimizer_set_limits(uint64_t max_pixels, uint64_t max_memory);
+
+const char *optimizer_last_error(void);
+
+#endif /* IMG_OPTIMIZER_H */
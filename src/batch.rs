@@ -0,0 +1,436 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use image::{DynamicImage, ImageFormat, Pixel};
+use log::debug;
+use serde::Serialize;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::optimizer::Encoder;
+
+// Logos, icons and screenshots rarely use more colors than this
+const FLAT_COLOR_LIMIT: usize = 256;
+
+pub struct SourceFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    output_dirs: Vec<PathBuf>,
+}
+
+fn build_glob_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+impl SourceFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> anyhow::Result<SourceFilter> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(include)?)
+        };
+        Ok(SourceFilter {
+            include,
+            exclude: build_glob_set(exclude)?,
+            output_dirs: vec![],
+        })
+    }
+
+    pub fn skip_output_dir(&mut self, dir: PathBuf) {
+        self.output_dirs.push(dir);
+    }
+
+    // Never pick up results of an earlier run when walking a tree
+    fn walks_into(&self, entry: &DirEntry) -> bool {
+        entry.depth() == 0
+            || !entry.file_type().is_dir()
+            || (entry.file_name() != "optimized"
+                && !self.output_dirs.iter().any(|dir| entry.path() == dir))
+    }
+
+    pub fn is_output(&self, path: &Path) -> bool {
+        path.components()
+            .any(|component| component.as_os_str() == "optimized")
+            || self.output_dirs.iter().any(|dir| path.starts_with(dir))
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let Some(file_name) = path.file_name() else {
+            return false;
+        };
+        if self.exclude.is_match(file_name) {
+            return false;
+        }
+        match &self.include {
+            Some(include) => include.is_match(file_name),
+            // Without explicit patterns only pick up files the decoder understands
+            None => {
+                ImageFormat::from_path(path).is_ok()
+                    || is_heif_path(path)
+                    || path
+                        .extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+            }
+        }
+    }
+}
+
+fn is_heif_path(path: &Path) -> bool {
+    cfg!(feature = "heif")
+        && path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("heic") || ext.eq_ignore_ascii_case("heif"))
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '[', '{'])
+}
+
+// The literal directory prefix of a pattern, which is all that needs walking
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+fn expand_glob(pattern: &str, filter: &SourceFilter) -> anyhow::Result<Vec<String>> {
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+    let base = glob_base(pattern);
+    let root = if base.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        base.as_path()
+    };
+
+    let mut matches = vec![];
+    let walker = WalkDir::new(root).sort_by_file_name().into_iter();
+    for entry in walker.filter_entry(|entry| filter.walks_into(entry)) {
+        let entry = entry?;
+        let path = if base.as_os_str().is_empty() {
+            entry.path().strip_prefix(".").unwrap_or(entry.path())
+        } else {
+            entry.path()
+        };
+        if entry.file_type().is_file() && matcher.is_match(path) && filter.matches(path) {
+            matches.push(path.to_string_lossy().into_owned());
+        }
+    }
+    if matches.is_empty() {
+        return Err(anyhow!("No files match {pattern}"));
+    }
+    Ok(matches)
+}
+
+pub fn collect_sources(inputs: &[String], filter: &SourceFilter) -> anyhow::Result<Vec<String>> {
+    let mut sources = vec![];
+    for input in inputs {
+        if is_glob(input) && !Path::new(input).exists() {
+            sources.extend(expand_glob(input, filter)?);
+            continue;
+        }
+        if !Path::new(input).is_dir() {
+            sources.push(input.clone());
+            continue;
+        }
+        let walker = WalkDir::new(input).sort_by_file_name().into_iter();
+        for entry in walker.filter_entry(|entry| filter.walks_into(entry)) {
+            let entry = entry?;
+            if entry.file_type().is_file() && filter.matches(entry.path()) {
+                sources.push(entry.path().to_string_lossy().into_owned());
+            }
+        }
+    }
+    Ok(sources)
+}
+
+pub fn read_source_list(list_path: &str) -> anyhow::Result<Vec<String>> {
+    let contents = if list_path == "-" {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        contents
+    } else {
+        fs::read_to_string(list_path)?
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+pub fn watch_roots(inputs: &[String]) -> Vec<PathBuf> {
+    inputs
+        .iter()
+        .filter(|input| *input != "-")
+        .map(|input| {
+            if is_glob(input) && !Path::new(input).exists() {
+                glob_base(input)
+            } else {
+                PathBuf::from(input)
+            }
+        })
+        .collect()
+}
+
+pub fn common_root(inputs: &[String]) -> PathBuf {
+    let mut dirs = inputs.iter().map(|input| {
+        let path = Path::new(input);
+        if is_glob(input) && !path.exists() {
+            glob_base(input)
+        } else if path.is_dir() {
+            path.to_path_buf()
+        } else {
+            path.parent().unwrap_or(Path::new("")).to_path_buf()
+        }
+    });
+    let Some(first) = dirs.next() else {
+        return PathBuf::new();
+    };
+    dirs.fold(first, |root, dir| {
+        root.components()
+            .zip(dir.components())
+            .take_while(|(a, b)| a == b)
+            .map(|(component, _)| component)
+            .collect()
+    })
+}
+
+fn is_flat_graphic(img: &DynamicImage) -> bool {
+    let mut colors = HashSet::new();
+    for pixel in img.to_rgba8().pixels() {
+        colors.insert(pixel.to_rgba().0);
+        if colors.len() > FLAT_COLOR_LIMIT {
+            return false;
+        }
+    }
+    true
+}
+
+pub fn choose_encoder(img_src: &str, img: &DynamicImage) -> (Encoder, bool) {
+    let choice = if is_flat_graphic(img) {
+        (Encoder::WebP, true)
+    } else {
+        (Encoder::MozJpeg, false)
+    };
+    debug!("Auto-selected {} for {img_src}", choice.0.name());
+    choice
+}
+
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    succeeded: usize,
+    failed: Vec<(String, anyhow::Error)>,
+}
+
+impl BatchReport {
+    pub fn record(&mut self, img_src: &str, result: anyhow::Result<()>) {
+        match result {
+            Ok(()) => self.succeeded += 1,
+            Err(err) => self.failed.push((img_src.to_string(), err)),
+        }
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &anyhow::Error)> {
+        self.failed.iter().map(|(src, err)| (src.as_str(), err))
+    }
+
+    // For CI, which wants the failures without scraping the log
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct Failure<'a> {
+            path: &'a str,
+            error: String,
+        }
+        #[derive(Serialize)]
+        struct Report<'a> {
+            succeeded: usize,
+            failed: Vec<Failure<'a>>,
+        }
+        let report = Report {
+            succeeded: self.succeeded,
+            failed: self
+                .failures()
+                .map(|(path, err)| Failure {
+                    path,
+                    error: format!("{err:#}"),
+                })
+                .collect(),
+        };
+        let json = serde_json::to_string_pretty(&report)?;
+        fs::write(path, json).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match (self.succeeded, self.failed.len()) {
+            (_, 0) => 0,
+            (0, _) => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl Display for BatchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ok, {} failed", self.succeeded, self.failed.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::testing;
+
+    fn selected(dir: &Path, include: &[&str], exclude: &[&str]) -> Vec<String> {
+        let patterns =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let filter = SourceFilter::new(&patterns(include), &patterns(exclude)).unwrap();
+        let sources = collect_sources(&[dir.to_string_lossy().into_owned()], &filter).unwrap();
+        sources
+            .iter()
+            .map(|src| {
+                let relative = Path::new(src).strip_prefix(dir).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filters_pick_sources_while_walking() {
+        let dir = testing::temp_dir("source-filter");
+        for name in [
+            "a.jpg",
+            "b.png",
+            "notes.txt",
+            "skip-me.jpg",
+            "nested/c.JPG",
+            "optimized/a_640.jpg",
+        ] {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        assert_eq!(
+            selected(&dir, &[], &[]),
+            ["a.jpg", "b.png", "nested/c.JPG", "skip-me.jpg"]
+        );
+        // Exclusions win over inclusions
+        assert_eq!(
+            selected(&dir, &["*.jpg", "*.JPG"], &["skip-*"]),
+            ["a.jpg", "nested/c.JPG"]
+        );
+        assert_eq!(
+            selected(&dir, &["*.png", "*.txt"], &[]),
+            ["b.png", "notes.txt"]
+        );
+    }
+
+    #[test]
+    fn source_lists_skip_comments_and_blank_lines() {
+        let list = testing::temp_dir("source-list").join("images.txt");
+        fs::write(
+            &list,
+            "# hero images\nhome/hero.jpg\n\n  about/team.png  \n   \n#old/skip.jpg\n",
+        )
+        .unwrap();
+        let sources = read_source_list(list.to_str().unwrap()).unwrap();
+        assert_eq!(sources, ["home/hero.jpg", "about/team.png"]);
+    }
+
+    #[test]
+    fn common_root_is_shared_by_every_input() {
+        let inputs = |inputs: &[&str]| {
+            inputs
+                .iter()
+                .map(|input| input.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            common_root(&inputs(&["src/a/b/c.jpg", "src/a/d.jpg"])),
+            PathBuf::from("src/a")
+        );
+        assert_eq!(
+            common_root(&inputs(&["src/a/**/*.jpg", "src/e/f.png"])),
+            PathBuf::from("src")
+        );
+        assert_eq!(common_root(&inputs(&["photo.jpg"])), PathBuf::new());
+    }
+
+    #[test]
+    fn flat_graphics_pick_lossless_webp_and_photos_mozjpeg() {
+        let logo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            if (x / 16 + y / 16) % 2 == 0 {
+                image::Rgb([230, 60, 20])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        }));
+        let (encoder, lossless) = choose_encoder("logo.png", &logo);
+        assert_eq!((encoder, lossless), (Encoder::WebP, true));
+
+        let (encoder, lossless) = choose_encoder("photo.png", &testing::gradient(64, 48));
+        assert_eq!((encoder, lossless), (Encoder::MozJpeg, false));
+    }
+
+    fn report(succeeded: usize, failed: usize) -> BatchReport {
+        let mut report = BatchReport::default();
+        for i in 0..succeeded {
+            report.record(&format!("ok{i}.jpg"), Ok(()));
+        }
+        for i in 0..failed {
+            report.record(&format!("bad{i}.jpg"), Err(anyhow!("corrupt header")));
+        }
+        report
+    }
+
+    #[test]
+    fn exit_codes_tell_partial_and_total_failure_apart() {
+        assert_eq!(report(0, 0).exit_code(), 0);
+        assert_eq!(report(3, 0).exit_code(), 0);
+        assert_eq!(report(12, 3).exit_code(), 2);
+        assert_eq!(report(0, 2).exit_code(), 1);
+        assert_eq!(report(12, 3).to_string(), "12 ok, 3 failed");
+    }
+
+    #[test]
+    fn reports_list_each_failure() {
+        let report = report(1, 2);
+        let failures: Vec<_> = report
+            .failures()
+            .map(|(src, err)| (src, err.to_string()))
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                ("bad0.jpg", "corrupt header".to_string()),
+                ("bad1.jpg", "corrupt header".to_string())
+            ]
+        );
+
+        let path = testing::temp_dir("batch-report").join("report.json");
+        report.write(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(json["succeeded"], 1);
+        assert_eq!(json["failed"][1]["path"], "bad1.jpg");
+        assert_eq!(json["failed"][1]["error"], "corrupt header");
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use image::DynamicImage;
+
+use crate::error::Result;
+use crate::optimizer::{Compressor, Encoder, Optimizer};
+use crate::summary::format_bytes;
+
+pub struct BenchRun {
+    pub encoder: Encoder,
+    // Lossless encoders run once, there's no quality to vary
+    pub quality: Option<f32>,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+pub struct Bench {
+    pixels: usize,
+    runs: Vec<BenchRun>,
+}
+
+// Runs one encode at a time, so the timings don't compete with each other for cores
+pub fn run(img: &DynamicImage, encoders: &[Encoder], qualities: &[f32]) -> Result<Bench> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let mut optimizer = Optimizer::new(img.clone(), "bench");
+    let mut runs = vec![];
+    for encoder in encoders {
+        // Flattened or not exactly as an optimize with this encoder would be
+        optimizer.set_encoder(encoder.clone());
+        let pixels = optimizer.source_pixels();
+        let qualities = if *encoder == Encoder::Png {
+            vec![None]
+        } else {
+            qualities.iter().copied().map(Some).collect()
+        };
+        for quality in qualities {
+            let mut compressor = Compressor::new(quality.unwrap_or(100.0));
+            compressor.set_encoder(encoder.clone());
+            let start = Instant::now();
+            let encoded = compressor.encode(pixels.as_bytes(), width, height, &[])?;
+            runs.push(BenchRun {
+                encoder: encoder.clone(),
+                quality,
+                bytes: encoded.len(),
+                elapsed: start.elapsed(),
+            });
+        }
+    }
+    Ok(Bench {
+        pixels: width * height,
+        runs,
+    })
+}
+
+impl Bench {
+    pub fn runs(&self) -> &[BenchRun] {
+        &self.runs
+    }
+}
+
+impl Display for Bench {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<8}  {:>7}  {:>10}  {:>6}  {:>10}",
+            "encoder", "quality", "size", "bpp", "time"
+        )?;
+        for run in &self.runs {
+            let quality = run
+                .quality
+                .map(|quality| format!("{quality}"))
+                .unwrap_or("-".to_string());
+            let bits_per_pixel = run.bytes as f64 * 8.0 / self.pixels.max(1) as f64;
+            writeln!(
+                f,
+                "{:<8}  {:>7}  {:>10}  {:>6.3}  {:>10}",
+                run.encoder.name(),
+                quality,
+                format_bytes(run.bytes as u64),
+                bits_per_pixel,
+                format!("{:.1?}", run.elapsed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn each_encoder_runs_at_each_quality_and_png_once() {
+        let encoders = [Encoder::MozJpeg, Encoder::WebP, Encoder::Png];
+        for img in [
+            testing::gradient(32, 24),
+            DynamicImage::ImageRgba8(testing::gradient(32, 24).to_rgba8()),
+        ] {
+            let bench = run(&img, &encoders, &[50.0, 90.0]).unwrap();
+            let runs: Vec<_> = bench
+                .runs()
+                .iter()
+                .map(|run| (run.encoder.clone(), run.quality))
+                .collect();
+            assert_eq!(
+                runs,
+                [
+                    (Encoder::MozJpeg, Some(50.0)),
+                    (Encoder::MozJpeg, Some(90.0)),
+                    (Encoder::WebP, Some(50.0)),
+                    (Encoder::WebP, Some(90.0)),
+                    (Encoder::Png, None),
+                ]
+            );
+            assert!(bench.runs().iter().all(|run| run.bytes > 0));
+        }
+    }
+}
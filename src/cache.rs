@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::manifest::OutputRecord;
+use crate::sink::OutputSink;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    key: String,
+    outputs: Vec<OutputRecord>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache(BTreeMap<String, CacheEntry>);
+
+// Changes to the source, its sidecar config or the settings all invalidate the entry. The source
+// is length prefixed so its bytes and the sidecar's can't run into each other.
+pub fn source_key(img_src: &str, settings: &str) -> anyhow::Result<String> {
+    let source = fs::read(img_src).with_context(|| format!("failed to read {img_src}"))?;
+    let mut hasher = Sha256::new();
+    hasher.update(settings);
+    hasher.update((source.len() as u64).to_le_bytes());
+    hasher.update(source);
+    if let Ok(sidecar) = fs::read(format!("{img_src}.toml")) {
+        hasher.update(sidecar);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl Cache {
+    // A missing or unreadable cache only means everything gets optimized again
+    pub fn load(path: &Path) -> Cache {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    // Outputs are looked up where they were written, so S3 runs skip unchanged sources too
+    pub fn fresh(
+        &self,
+        img_src: &str,
+        key: &str,
+        sink: &dyn OutputSink,
+    ) -> Option<Vec<OutputRecord>> {
+        let entry = self.0.get(img_src).filter(|entry| entry.key == key)?;
+        let outputs_exist = entry
+            .outputs
+            .iter()
+            .all(|output| sink.exists(Path::new(&output.path)));
+        outputs_exist.then(|| entry.outputs.clone())
+    }
+
+    pub fn insert(&mut self, img_src: &str, key: String, outputs: Vec<OutputRecord>) {
+        self.0
+            .insert(img_src.to_string(), CacheEntry { key, outputs });
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string(self)?;
+        fs::write(path, json).with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use crate::sink::FileSink;
+    use crate::testing;
+
+    // Stands in for a remote store, holding only the paths it was given
+    struct RemoteSink(Vec<&'static str>);
+
+    impl OutputSink for RemoteSink {
+        fn write(&self, _path: &Path, _bytes: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn exists(&self, output: &Path) -> bool {
+            self.0.iter().any(|path| Path::new(path) == output)
+        }
+    }
+
+    fn record(path: &str) -> OutputRecord {
+        OutputRecord {
+            path: path.to_string(),
+            width: 32,
+            height: 24,
+            format: "webp".to_string(),
+            quality: Some(75.0),
+            bytes: 100,
+            placeholder: None,
+            dominant_color: None,
+            palette: None,
+            psnr: None,
+            dssim: None,
+        }
+    }
+
+    #[test]
+    fn outputs_are_looked_up_in_the_sink() {
+        let mut cache = Cache::default();
+        cache.insert(
+            "a.jpg",
+            "7".to_string(),
+            vec![record("s3://bucket/a_32.webp")],
+        );
+        let uploaded = RemoteSink(vec!["s3://bucket/a_32.webp"]);
+        assert!(cache.fresh("a.jpg", "7", &uploaded).is_some());
+        assert!(cache.fresh("a.jpg", "7", &RemoteSink(vec![])).is_none());
+        assert!(cache.fresh("a.jpg", "8", &uploaded).is_none());
+        assert!(cache.fresh("b.jpg", "7", &uploaded).is_none());
+    }
+
+    #[test]
+    fn local_outputs_must_still_exist() {
+        let dir = testing::temp_dir("cache-local");
+        let output = dir.join("a_32.webp");
+        let mut cache = Cache::default();
+        cache.insert(
+            "a.jpg",
+            "7".to_string(),
+            vec![record(&output.to_string_lossy())],
+        );
+        assert!(cache.fresh("a.jpg", "7", &FileSink).is_none());
+        fs::write(&output, b"webp").unwrap();
+        assert!(cache.fresh("a.jpg", "7", &FileSink).is_some());
+    }
+
+    #[test]
+    fn keys_follow_the_source_and_sidecar() {
+        let dir = testing::temp_dir("cache-keys");
+        let source = dir.join("a.jpg").to_string_lossy().into_owned();
+        fs::write(&source, b"jpeg").unwrap();
+        let key = source_key(&source, "settings").unwrap();
+        // SHA-256 of the settings, the length prefixed source and no sidecar
+        let mut hasher = Sha256::new();
+        hasher.update("settings");
+        hasher.update(4u64.to_le_bytes());
+        hasher.update(b"jpeg");
+        assert_eq!(key, format!("{:x}", hasher.finalize()));
+        assert_ne!(source_key(&source, "other").unwrap(), key);
+
+        fs::write(format!("{source}.toml"), "quality = 50").unwrap();
+        assert_ne!(source_key(&source, "settings").unwrap(), key);
+    }
+}
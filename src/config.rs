@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::optimizer::Encoder;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, deserialize_with = "quality")]
+    pub quality: Option<f32>,
+    pub widths: Option<Vec<usize>>,
+    pub encoder: Option<Encoder>,
+    pub out_dir: Option<PathBuf>,
+    pub name_template: Option<String>,
+    pub sharpen: Option<f32>,
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+// Overrides for one image from a hero.jpg.toml next to it. Only settings that can differ within a
+// batch, so keys like out_dir or presets are rejected rather than silently ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sidecar {
+    #[serde(default, deserialize_with = "quality")]
+    pub quality: Option<f32>,
+    pub widths: Option<Vec<usize>>,
+    pub encoder: Option<Encoder>,
+    pub focal_point: Option<(f32, f32)>,
+    pub sharpen: Option<f32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub widths: Option<Vec<usize>>,
+    #[serde(default, deserialize_with = "quality")]
+    pub quality: Option<f32>,
+    pub encoder: Option<Encoder>,
+}
+
+// TOML allows nan and inf too, which no encoder can take
+fn quality<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+    match Option::<f32>::deserialize(deserializer)? {
+        Some(quality) if !(0.0..=100.0).contains(&quality) => Err(D::Error::custom(format!(
+            "quality must be between 0 and 100, got {quality}"
+        ))),
+        quality => Ok(quality),
+    }
+}
+
+fn builtin_preset(name: &str) -> Option<Preset> {
+    let (widths, quality, encoder) = match name {
+        "thumbnail" => (vec![150, 300], 70.0, Encoder::WebP),
+        "avatar" => (vec![64, 128, 256], 80.0, Encoder::WebP),
+        "gallery" => (vec![480, 960, 1440], 78.0, Encoder::WebP),
+        "hero" => (vec![640, 1280, 1920, 2560], 82.0, Encoder::MozJpeg),
+        _ => return None,
+    };
+    Some(Preset {
+        widths: Some(widths),
+        quality: Some(quality),
+        encoder: Some(encoder),
+    })
+}
+
+// Checked in order, in the working directory
+const PROJECT_CONFIGS: [&str; 2] = ["optimizer.toml", ".imageopt.json"];
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("failed to parse {}", path.display()))
+        } else {
+            toml::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+        }
+    }
+
+    // Presets from the config file take precedence over built-in ones of the same name
+    pub fn preset(&self, name: &str) -> anyhow::Result<Config> {
+        let preset = self
+            .presets
+            .get(name)
+            .cloned()
+            .or_else(|| builtin_preset(name))
+            .ok_or(anyhow!("Unknown preset {name}"))?;
+        Ok(Config {
+            quality: preset.quality,
+            widths: preset.widths,
+            encoder: preset.encoder,
+            ..Config::default()
+        })
+    }
+
+    pub fn discover() -> anyhow::Result<Option<Config>> {
+        let Some(path) = PROJECT_CONFIGS
+            .iter()
+            .map(Path::new)
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+        Config::load(path).map(Some)
+    }
+}
+
+impl Sidecar {
+    pub fn load(img_src: &str) -> anyhow::Result<Option<Sidecar>> {
+        let path = format!("{img_src}.toml");
+        let path = Path::new(&path);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn sidecars_override_one_image() {
+        let dir = testing::temp_dir("sidecar");
+        let src = dir.join("hero.jpg").to_string_lossy().into_owned();
+        assert!(Sidecar::load(&src).unwrap().is_none());
+
+        fs::write(
+            format!("{src}.toml"),
+            "quality = 90\nwidths = [1920]\nencoder = \"avif\"\n",
+        )
+        .unwrap();
+        let sidecar = Sidecar::load(&src).unwrap().unwrap();
+        assert_eq!(sidecar.quality, Some(90.0));
+        assert_eq!(sidecar.widths, Some(vec![1920]));
+        assert_eq!(sidecar.encoder, Some(Encoder::Avif));
+    }
+
+    #[test]
+    fn sidecars_reject_batch_wide_settings() {
+        let dir = testing::temp_dir("sidecar-batch-wide");
+        let src = dir.join("hero.jpg").to_string_lossy().into_owned();
+        for key in ["out_dir = \"dist\"", "name_template = \"{stem}.{ext}\""] {
+            fs::write(format!("{src}.toml"), key).unwrap();
+            let err = Sidecar::load(&src).unwrap_err();
+            assert!(format!("{err:#}").contains("unknown field"), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn qualities_out_of_range_are_rejected() {
+        for quality in ["nan", "inf", "101.0", "-5.0"] {
+            let parsed = toml::from_str::<Sidecar>(&format!("quality = {quality}"));
+            assert!(parsed.is_err(), "{quality}");
+        }
+        let sidecar: Sidecar = toml::from_str("quality = 55.5").unwrap();
+        assert_eq!(sidecar.quality, Some(55.5));
+    }
+
+    #[test]
+    fn presets_from_the_config_win_over_builtin_ones() {
+        let config: Config = toml::from_str("[presets.hero]\nwidths = [800]\n").unwrap();
+        let hero = config.preset("hero").unwrap();
+        assert_eq!(hero.widths, Some(vec![800]));
+        assert_eq!(hero.quality, None);
+        assert_eq!(config.preset("avatar").unwrap().quality, Some(80.0));
+        assert!(config.preset("missing").is_err());
+    }
+}
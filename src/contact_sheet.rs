@@ -0,0 +1,126 @@
+use std::path::Path;
+
+use anyhow::Context;
+use image::{imageops, DynamicImage, GenericImageView, Rgb, RgbImage};
+
+use crate::utils::{self, ensure_parent_directory_exists, ResizeFilter};
+
+const CELL_SIZE: u32 = 160;
+
+pub fn thumbnail(img: &DynamicImage) -> anyhow::Result<DynamicImage> {
+    let (width, height) = img.dimensions();
+    // Fit the longest side into a cell, never upscaling small sources
+    let scale = (CELL_SIZE as f32 / width.max(height) as f32).min(1.0);
+    let fit = |length: u32| ((length as f32 * scale).round() as usize).max(1);
+    Ok(utils::resize_image(
+        img,
+        fit(width),
+        fit(height),
+        ResizeFilter::default(),
+    )?)
+}
+
+pub struct ContactSheet {
+    columns: u32,
+    thumbnails: Vec<DynamicImage>,
+}
+
+impl ContactSheet {
+    pub fn new(columns: u32) -> ContactSheet {
+        ContactSheet {
+            columns,
+            thumbnails: vec![],
+        }
+    }
+
+    pub fn add(&mut self, thumbnail: DynamicImage) {
+        self.thumbnails.push(thumbnail);
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let count = self.thumbnails.len() as u32;
+        let columns = self.columns.min(count).max(1);
+        let rows = count.div_ceil(columns).max(1);
+        let mut sheet =
+            RgbImage::from_pixel(columns * CELL_SIZE, rows * CELL_SIZE, Rgb([255, 255, 255]));
+
+        for (index, thumbnail) in self.thumbnails.iter().enumerate() {
+            let index = index as u32;
+            let (full_w, full_h) = thumbnail.dimensions();
+            let (width, height) = (full_w.min(CELL_SIZE), full_h.min(CELL_SIZE));
+            // Center each thumbnail inside its cell, cropping the middle of any too large for it
+            let cropped =
+                thumbnail.crop_imm((full_w - width) / 2, (full_h - height) / 2, width, height);
+            let x = (index % columns) * CELL_SIZE + (CELL_SIZE - width) / 2;
+            let y = (index / columns) * CELL_SIZE + (CELL_SIZE - height) / 2;
+            imageops::overlay(&mut sheet, &cropped.to_rgb8(), x.into(), y.into());
+        }
+
+        let context = || format!("failed to write {}", path.display());
+        ensure_parent_directory_exists(path).with_context(context)?;
+        sheet.save(path).with_context(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn solid(width: u32, height: u32, color: [u8; 3]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb(color)))
+    }
+
+    #[test]
+    fn thumbnails_fit_a_cell_without_upscaling() {
+        assert_eq!(
+            thumbnail(&solid(320, 240, [0; 3])).unwrap().dimensions(),
+            (160, 120)
+        );
+        assert_eq!(
+            thumbnail(&solid(240, 480, [0; 3])).unwrap().dimensions(),
+            (80, 160)
+        );
+        assert_eq!(
+            thumbnail(&solid(80, 100, [0; 3])).unwrap().dimensions(),
+            (80, 100)
+        );
+    }
+
+    #[test]
+    fn two_images_make_a_single_row_sheet() {
+        let dir = testing::temp_dir("contact-sheet");
+        let mut sheet = ContactSheet::new(4);
+        sheet.add(thumbnail(&solid(320, 240, [255, 0, 0])).unwrap());
+        sheet.add(thumbnail(&solid(80, 100, [0, 0, 255])).unwrap());
+        let path = dir.join("sheet.png");
+        sheet.write(&path).unwrap();
+
+        let written = image::open(&path).unwrap().into_rgb8();
+        assert_eq!(written.dimensions(), (2 * CELL_SIZE, CELL_SIZE));
+        assert_eq!(written.get_pixel(80, 80), &Rgb([255, 0, 0]));
+        assert_eq!(written.get_pixel(240, 80), &Rgb([0, 0, 255]));
+        // Letterboxing around the smaller thumbnail
+        assert_eq!(written.get_pixel(165, 5), &Rgb([255, 255, 255]));
+
+        // Images added without thumbnailing are cropped to their cell
+        let mut oversized = ContactSheet::new(2);
+        oversized.add(solid(400, 100, [255, 0, 0]));
+        oversized.add(solid(10, 10, [0, 0, 255]));
+        oversized.write(&path).unwrap();
+        let written = image::open(&path).unwrap().into_rgb8();
+        assert_eq!(written.dimensions(), (2 * CELL_SIZE, CELL_SIZE));
+        assert_eq!(written.get_pixel(0, 80), &Rgb([255, 0, 0]));
+        assert_eq!(written.get_pixel(165, 80), &Rgb([255, 255, 255]));
+        assert_eq!(written.get_pixel(240, 80), &Rgb([0, 0, 255]));
+
+        let mut column = ContactSheet::new(1);
+        column.add(solid(10, 10, [0; 3]));
+        column.add(solid(10, 10, [0; 3]));
+        column.write(&path).unwrap();
+        assert_eq!(
+            image::image_dimensions(&path).unwrap(),
+            (CELL_SIZE, 2 * CELL_SIZE)
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use std::io::Cursor;
+
+use image::{
+    codecs::jpeg::JpegDecoder, io::Reader, DynamicImage, GenericImageView, ImageDecoder,
+    ImageFormat,
+};
+use log::info;
+
+#[cfg(not(feature = "heif"))]
+use crate::error::OptimizeError;
+use crate::error::Result;
+use crate::optimizer::Optimizer;
+use crate::utils::{self, DecodeLimits, MetadataSegment};
+
+pub struct DecodedSource {
+    pub img: DynamicImage,
+    // The source's own upright size, larger than img's when it was decoded at a reduced scale
+    pub dimensions: (u32, u32),
+    pub format: Option<ImageFormat>,
+    // The EXIF orientation has already been applied and reset
+    pub metadata: Vec<MetadataSegment>,
+}
+
+// The one decode path behind the CLI, the server, FFI, WASM and the in-memory API. The name is
+// only a hint for formats without a signature, in-memory callers pass None.
+pub fn decode_source(
+    bytes: &[u8],
+    name: Option<&str>,
+    limits: &DecodeLimits,
+) -> Result<DecodedSource> {
+    decode_source_scaled(bytes, name, limits, None)
+}
+
+// A JPEG decodes at the smallest DCT scale, down to 1/8, that still covers min_size upright, so
+// a 6000x4000 photo bound for 700px outputs holds 1500x1000 pixels rather than 24 million. A PNG
+// is box shrunk a row at a time as it decodes, by up to 16. CMYK and interlaced sources and every
+// other format decode at full size.
+pub fn decode_source_scaled(
+    bytes: &[u8],
+    name: Option<&str>,
+    limits: &DecodeLimits,
+    min_size: Option<(u32, u32)>,
+) -> Result<DecodedSource> {
+    let name = name.unwrap_or_default();
+    let (img, format, mut metadata) = if utils::is_heif(bytes) {
+        decode_heif(name, bytes, limits)?
+    } else if utils::is_svg(name, bytes) {
+        // A raster at the SVG's own size, for everything that needs pixels rather than outputs
+        let (width, height) = utils::svg_size(bytes)?;
+        limits.check((width, height))?;
+        let img = DynamicImage::ImageRgba8(utils::render_svg(bytes, width, height)?);
+        (img, None, vec![])
+    } else {
+        decode_raster(name, bytes, limits, min_size)?
+    };
+    let img = match utils::take_exif_orientation(&mut metadata) {
+        Some(orientation) => utils::apply_orientation(img, orientation),
+        None => img,
+    };
+    let dimensions = match min_size {
+        Some(_) => header_size(bytes).unwrap_or(img.dimensions()),
+        None => img.dimensions(),
+    };
+    Ok(DecodedSource {
+        img: utils::reduce_to_8_bit(img),
+        dimensions,
+        format,
+        metadata,
+    })
+}
+
+fn decode_raster(
+    name: &str,
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    min_size: Option<(u32, u32)>,
+) -> Result<(DynamicImage, Option<ImageFormat>, Vec<MetadataSegment>)> {
+    let mut reader = Reader::new(Cursor::new(bytes));
+    if let Ok(format) = ImageFormat::from_path(name) {
+        reader.set_format(format);
+    }
+    let mut reader = reader
+        .with_guessed_format()
+        .map_err(image::ImageError::from)?;
+    let format = reader.format();
+    if let Some(format) = format {
+        let header = Reader::with_format(Cursor::new(bytes), format);
+        let dimensions = header.into_dimensions()?;
+        limits.check(dimensions)?;
+        if let (ImageFormat::Png, Some(min_size)) = (format, min_size) {
+            if let Some(img) = decode_png_scaled(bytes, dimensions, min_size)? {
+                return Ok((img, Some(format), vec![]));
+            }
+        }
+    }
+    reader.limits(limits.image_limits());
+    if format != Some(ImageFormat::Jpeg) {
+        return Ok((reader.decode()?, format, vec![]));
+    }
+    let mut metadata = utils::read_jpeg_metadata(bytes);
+    let img = match utils::decode_cmyk_jpeg(bytes, &mut metadata)? {
+        Some(img) => {
+            info!("Converted {name} from CMYK");
+            img
+        }
+        None => match min_size {
+            Some(min_size) => decode_jpeg_scaled(bytes, &metadata, min_size, limits)?,
+            None => reader.decode()?,
+        },
+    };
+    Ok((img, format, metadata))
+}
+
+fn decode_jpeg_scaled(
+    bytes: &[u8],
+    metadata: &[MetadataSegment],
+    min_size: (u32, u32),
+    limits: &DecodeLimits,
+) -> Result<DynamicImage> {
+    let mut decoder = JpegDecoder::new(Cursor::new(bytes))?;
+    let (width, height) = decoder.dimensions();
+    let (min_w, min_h) = if transposes(utils::take_exif_orientation(&mut metadata.to_vec())) {
+        (min_size.1, min_size.0)
+    } else {
+        min_size
+    };
+    // The decoder rounds the scaled sides up, so asking for exactly these picks that scale
+    let (width, height) = [8, 4, 2]
+        .into_iter()
+        .map(|factor| (width.div_ceil(factor), height.div_ceil(factor)))
+        .find(|&(w, h)| w >= min_w && h >= min_h)
+        .unwrap_or((width, height));
+    decoder.scale(width as u16, height as u16)?;
+    decoder.set_limits(limits.image_limits())?;
+    Ok(DynamicImage::from_decoder(decoder)?)
+}
+
+// The largest whole box shrink, at most 16x, that still covers min_size. PNGs carry no
+// orientation, so the sides are compared as they are.
+fn decode_png_scaled(
+    bytes: &[u8],
+    (width, height): (u32, u32),
+    (min_w, min_h): (u32, u32),
+) -> Result<Option<DynamicImage>> {
+    let factor = (width / min_w.max(1)).min(height / min_h.max(1)).min(16);
+    if factor < 2 {
+        return Ok(None);
+    }
+    utils::decode_png_shrunk(bytes, factor as usize)
+}
+
+// The upright size from a JPEG or PNG header, without decoding it. None for anything else.
+pub fn header_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.starts_with(b"\x89PNG") {
+        let header = Reader::with_format(Cursor::new(bytes), ImageFormat::Png);
+        return header.into_dimensions().ok();
+    }
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let header = Reader::with_format(Cursor::new(bytes), ImageFormat::Jpeg);
+    let (width, height) = header.into_dimensions().ok()?;
+    let orientation = utils::take_exif_orientation(&mut utils::read_jpeg_metadata(bytes));
+    Some(if transposes(orientation) {
+        (height, width)
+    } else {
+        (width, height)
+    })
+}
+
+// Orientations 5 to 8 turn the image a quarter, swapping its sides
+fn transposes(orientation: Option<u16>) -> bool {
+    matches!(orientation, Some(5..=8))
+}
+
+// Twice the scale of the largest target, the margin the resize's own box shrink keeps, so outputs
+// from a reduced decode still come out of the filtered resize. Without targets that's the source.
+pub fn decode_size_for((width, height): (u32, u32), targets: &[(usize, usize)]) -> (u32, u32) {
+    let scale = targets
+        .iter()
+        .map(|&(w, h)| (w as f64 / width as f64).max(h as f64 / height as f64))
+        .fold(0.0, f64::max)
+        * 2.0;
+    if scale == 0.0 || scale >= 1.0 {
+        return (width, height);
+    }
+    (
+        (width as f64 * scale).ceil() as u32,
+        (height as f64 * scale).ceil() as u32,
+    )
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(
+    _name: &str,
+    bytes: &[u8],
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, Option<ImageFormat>, Vec<MetadataSegment>)> {
+    let (img, metadata) = utils::decode_heif(bytes, limits)?;
+    Ok((img, None, metadata))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(
+    _name: &str,
+    _bytes: &[u8],
+    _limits: &DecodeLimits,
+) -> Result<(DynamicImage, Option<ImageFormat>, Vec<MetadataSegment>)> {
+    Err(OptimizeError::InvalidOption(
+        "HEIF images need a build with --features heif".to_string(),
+    ))
+}
+
+// What every in-memory entry point starts from. Outputs never carry the source's ICC profile,
+// so the pixels are converted to sRGB as the CLI does by default.
+pub fn optimizer_for_bytes(bytes: &[u8], limits: &DecodeLimits) -> Result<Optimizer> {
+    let decoded = decode_source(bytes, None, limits)?;
+    let mut optimizer = Optimizer::new(decoded.img, "input");
+    if let Some(format) = decoded.format {
+        optimizer.set_source_format(format);
+    }
+    optimizer.set_source_metadata(decoded.metadata);
+    if decoded.format == Some(ImageFormat::Gif) {
+        if let Some(animation) = utils::decode_gif_animation(bytes)? {
+            optimizer.set_animation(animation);
+        }
+    }
+    if utils::is_svg("", bytes) {
+        optimizer.set_svg_source(bytes.to_vec());
+    }
+    optimizer.convert_to_srgb()?;
+    Ok(optimizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+    use crate::error::OptimizeError;
+    use crate::testing;
+
+    #[test]
+    fn exif_orientation_is_applied_and_reset() {
+        let jpeg = testing::jpeg_with_orientation(&testing::gradient(40, 20), 6);
+        let mut decoded = decode_source(&jpeg, None, &DecodeLimits::default()).unwrap();
+        assert_eq!(decoded.img.dimensions(), (20, 40));
+        assert_eq!(utils::take_exif_orientation(&mut decoded.metadata), Some(1));
+    }
+
+    #[test]
+    fn limits_are_checked_before_decoding() {
+        let png = testing::encode(&testing::gradient(64, 48), ImageFormat::Png);
+        let limits = DecodeLimits {
+            max_pixels: Some(1000),
+            max_memory: None,
+        };
+        let err = decode_source(&png, None, &limits).err().unwrap();
+        assert!(
+            matches!(err, OptimizeError::TooLarge { width: 64, .. }),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn sixteen_bit_sources_come_out_as_8_bit() {
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(8, 8, Rgb([65535, 0, 0])));
+        let png = testing::encode(&img, ImageFormat::Png);
+        let decoded = decode_source(&png, None, &DecodeLimits::default()).unwrap();
+        assert_eq!(decoded.format, Some(ImageFormat::Png));
+        assert_eq!(
+            decoded.img.as_rgb8().unwrap().get_pixel(0, 0),
+            &Rgb([255, 0, 0])
+        );
+    }
+
+    #[test]
+    fn jpegs_decode_at_the_smallest_scale_covering_the_outputs() {
+        let jpeg = testing::encode(&testing::gradient(512, 384), ImageFormat::Jpeg);
+        let min_size = decode_size_for((512, 384), &[(100, 75), (50, 38)]);
+        assert_eq!(min_size, (200, 150));
+        let decoded =
+            decode_source_scaled(&jpeg, None, &DecodeLimits::default(), Some(min_size)).unwrap();
+        assert_eq!(decoded.img.dimensions(), (256, 192));
+        assert_eq!(decoded.dimensions, (512, 384));
+
+        let small = decode_size_for((512, 384), &[(32, 24)]);
+        let decoded =
+            decode_source_scaled(&jpeg, None, &DecodeLimits::default(), Some(small)).unwrap();
+        assert_eq!(decoded.img.dimensions(), (64, 48));
+    }
+
+    #[test]
+    fn scaled_decodes_follow_the_exif_orientation() {
+        let jpeg = testing::jpeg_with_orientation(&testing::gradient(512, 256), 6);
+        assert_eq!(header_size(&jpeg), Some((256, 512)));
+        let min_size = decode_size_for((256, 512), &[(64, 128)]);
+        let decoded =
+            decode_source_scaled(&jpeg, None, &DecodeLimits::default(), Some(min_size)).unwrap();
+        assert_eq!(decoded.img.dimensions(), (128, 256));
+        assert_eq!(decoded.dimensions, (256, 512));
+    }
+
+    #[test]
+    fn sources_without_smaller_targets_decode_in_full() {
+        assert_eq!(decode_size_for((512, 384), &[]), (512, 384));
+        assert_eq!(decode_size_for((512, 384), &[(400, 300)]), (512, 384));
+        let bmp = testing::encode(&testing::gradient(64, 48), ImageFormat::Bmp);
+        let decoded =
+            decode_source_scaled(&bmp, None, &DecodeLimits::default(), Some((8, 6))).unwrap();
+        assert_eq!(decoded.img.dimensions(), (64, 48));
+    }
+
+    #[test]
+    fn pngs_are_box_shrunk_while_decoding() {
+        let png = testing::encode(&testing::gradient(512, 384), ImageFormat::Png);
+        assert_eq!(header_size(&png), Some((512, 384)));
+        let min_size = decode_size_for((512, 384), &[(50, 38)]);
+        let decoded =
+            decode_source_scaled(&png, None, &DecodeLimits::default(), Some(min_size)).unwrap();
+        assert_eq!(decoded.img.dimensions(), (102, 76));
+        assert_eq!(decoded.dimensions, (512, 384));
+        assert_eq!(decoded.format, Some(ImageFormat::Png));
+
+        // Gray widens to RGB and a transparent half keeps its alpha without tinting the rest
+        let gray_alpha = DynamicImage::ImageLumaA8(ImageBuffer::from_fn(64, 48, |x, _| {
+            image::LumaA(if x < 32 { [0, 0] } else { [200, 255] })
+        }));
+        let png = testing::encode(&gray_alpha, ImageFormat::Png);
+        let decoded =
+            decode_source_scaled(&png, None, &DecodeLimits::default(), Some((8, 6))).unwrap();
+        let rgba = decoded.img.as_rgba8().unwrap();
+        assert_eq!(rgba.dimensions(), (8, 6));
+        assert_eq!(rgba.get_pixel(1, 3)[3], 0);
+        assert_eq!(rgba.get_pixel(6, 3), &image::Rgba([200, 200, 200, 255]));
+    }
+
+    #[test]
+    fn in_memory_optimizers_see_the_upright_image() {
+        let jpeg = testing::jpeg_with_orientation(&testing::gradient(40, 20), 8);
+        let optimizer = optimizer_for_bytes(&jpeg, &DecodeLimits::default()).unwrap();
+        assert_eq!(optimizer.dimensions(), (20, 40));
+    }
+}
@@ -0,0 +1,88 @@
+use std::{fmt::Display, io, path::PathBuf};
+
+use image::{error::UnsupportedErrorKind, ExtendedColorType, ImageError};
+use thiserror::Error;
+
+use crate::optimizer::Encoder;
+
+#[derive(Debug, Error)]
+pub enum OptimizeError {
+    #[error("Error decoding image")]
+    Decode(#[source] ImageError),
+    #[error("Unsupported color type {0:?}")]
+    UnsupportedColorType(ExtendedColorType),
+    #[error("Error compressing image with {}: {message}", .encoder.name())]
+    Encode { encoder: Encoder, message: String },
+    // The image crate's own encoders, used when outputs keep the source format
+    #[error("Error encoding {}", .path.display())]
+    ImageEncode {
+        path: PathBuf,
+        #[source]
+        source: ImageError,
+    },
+    #[error("{0}")]
+    Resize(String),
+    #[error("{0}")]
+    ColorProfile(String),
+    #[error("{0}")]
+    Placeholder(String),
+    #[error("failed to write {}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{0}")]
+    InvalidPath(String),
+    #[error("{0}")]
+    InvalidTarget(String),
+    #[error("{0}")]
+    InvalidOption(String),
+    #[error("{0}")]
+    Unreachable(String),
+    #[error("{0}")]
+    Verification(String),
+    #[error("Unable to encode image within {0} bytes")]
+    OverBudget(usize),
+    #[error("Unable to reach SSIM {0} at any quality")]
+    SsimUnreachable(f64),
+    #[error("Image is {width}x{height}, over the limit of {limit}")]
+    TooLarge {
+        width: u32,
+        height: u32,
+        limit: String,
+    },
+    #[error("Optimizing {0} was cancelled")]
+    Cancelled(String),
+    #[error("Optimizing {0} exceeded its deadline")]
+    DeadlineExceeded(String),
+}
+
+pub type Result<T, E = OptimizeError> = std::result::Result<T, E>;
+
+impl OptimizeError {
+    pub(crate) fn encode(encoder: Encoder, message: impl Display) -> OptimizeError {
+        OptimizeError::Encode {
+            encoder,
+            message: message.to_string(),
+        }
+    }
+
+    pub(crate) fn write(path: impl Into<PathBuf>, source: io::Error) -> OptimizeError {
+        OptimizeError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+impl From<ImageError> for OptimizeError {
+    fn from(err: ImageError) -> OptimizeError {
+        if let ImageError::Unsupported(unsupported) = &err {
+            if let UnsupportedErrorKind::Color(color) = unsupported.kind() {
+                return OptimizeError::UnsupportedColorType(color);
+            }
+        }
+        OptimizeError::Decode(err)
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::decode;
+use crate::error::{OptimizeError, Result};
+use crate::optimizer::{self, Encoder, Fit};
+use crate::utils::DecodeLimits;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub enum OptimizerEncoder {
+    Webp,
+    Mozjpeg,
+    Avif,
+    Jpegxl,
+    Png,
+}
+
+impl From<OptimizerEncoder> for Encoder {
+    fn from(encoder: OptimizerEncoder) -> Encoder {
+        match encoder {
+            OptimizerEncoder::Webp => Encoder::WebP,
+            OptimizerEncoder::Mozjpeg => Encoder::MozJpeg,
+            OptimizerEncoder::Avif => Encoder::Avif,
+            OptimizerEncoder::Jpegxl => Encoder::JpegXl,
+            OptimizerEncoder::Png => Encoder::Png,
+        }
+    }
+}
+
+// Owned by the caller once filled in, and released with optimizer_free
+#[repr(C)]
+pub struct OptimizerOutput {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+// Set once by optimizer_set_limits and shared by every thread, zero means no limit
+static MAX_PIXELS: AtomicU64 = AtomicU64::new(0);
+static MAX_MEMORY: AtomicU64 = AtomicU64::new(0);
+
+fn decode_limits() -> DecodeLimits {
+    let limit = |value: &AtomicU64| Some(value.load(Ordering::Relaxed)).filter(|&value| value > 0);
+    DecodeLimits {
+        max_pixels: limit(&MAX_PIXELS),
+        max_memory: limit(&MAX_MEMORY),
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs would truncate the message on the C side anyway
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn optimize(
+    input: &[u8],
+    size: Option<(u32, u32)>,
+    encoder: OptimizerEncoder,
+    quality: f32,
+) -> Result<Vec<u8>> {
+    let mut optimizer = decode::optimizer_for_bytes(input, &decode_limits())?;
+    let dimensions = optimizer.dimensions();
+    optimizer.set_encoder(encoder.into());
+    optimizer.set_quality(quality);
+    // Zero leaves that side to follow the aspect ratio, same as leaving out --width or --height
+    let targets = match size {
+        Some((0, 0)) | None => vec![],
+        Some((width, 0)) => optimizer::responsive_dimensions(dimensions, &[width as usize]),
+        Some((0, height)) => optimizer::height_dimensions(dimensions, &[height as usize]),
+        Some((width, height)) => {
+            optimizer.set_fit(Fit::Cover);
+            optimizer::exact_dimensions(
+                dimensions,
+                &[(width as usize, height as usize)],
+                Fit::Cover,
+            )
+        }
+    };
+    optimizer.set_targets(targets)?;
+    optimizer.optimize_to_bytes()
+}
+
+// Returns 0 and fills in output on success, or -1 with the reason in optimizer_last_error
+fn run(
+    input: *const u8,
+    input_len: usize,
+    output: *mut OptimizerOutput,
+    optimize: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> c_int {
+    if input.is_null() || output.is_null() {
+        set_last_error("input and output must not be null".to_string());
+        return -1;
+    }
+    // SAFETY: the caller guarantees input points to input_len readable bytes
+    let input = unsafe { slice::from_raw_parts(input, input_len) };
+    // Unwinding into C is undefined behaviour, so encoder panics become errors here
+    let result = panic::catch_unwind(AssertUnwindSafe(|| optimize(input))).unwrap_or_else(|_| {
+        Err(OptimizeError::Unreachable(
+            "the optimizer panicked".to_string(),
+        ))
+    });
+    match result {
+        Ok(optimized) => {
+            let optimized = Box::into_raw(optimized.into_boxed_slice());
+            // SAFETY: output was checked for null and the caller guarantees it is writable
+            unsafe {
+                *output = OptimizerOutput {
+                    data: optimized.cast(),
+                    len: optimized.len(),
+                };
+            }
+            0
+        }
+        Err(err) => {
+            set_last_error(error_chain(&err));
+            -1
+        }
+    }
+}
+
+fn error_chain(err: &OptimizeError) -> String {
+    let mut message = err.to_string();
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        message.push_str(&format!(": {err}"));
+        source = err.source();
+    }
+    message
+}
+
+/// # Safety
+///
+/// `input` must point to `input_len` readable bytes and `output` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn optimizer_compress(
+    input: *const u8,
+    input_len: usize,
+    encoder: OptimizerEncoder,
+    quality: f32,
+    output: *mut OptimizerOutput,
+) -> c_int {
+    run(input, input_len, output, |input| {
+        optimize(input, None, encoder, quality)
+    })
+}
+
+/// # Safety
+///
+/// `input` must point to `input_len` readable bytes and `output` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn optimizer_resize_and_compress(
+    input: *const u8,
+    input_len: usize,
+    width: u32,
+    height: u32,
+    encoder: OptimizerEncoder,
+    quality: f32,
+    output: *mut OptimizerOutput,
+) -> c_int {
+    run(input, input_len, output, |input| {
+        optimize(input, Some((width, height)), encoder, quality)
+    })
+}
+
+/// # Safety
+///
+/// `output` must have been filled in by this library and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn optimizer_free(output: OptimizerOutput) {
+    if output.data.is_null() {
+        return;
+    }
+    // SAFETY: data and len came from Box::into_raw in run
+    drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(output.data, output.len)) });
+}
+
+// Sources over either limit fail before their pixels are decoded, pass 0 to lift a limit
+#[no_mangle]
+pub extern "C" fn optimizer_set_limits(max_pixels: u64, max_memory: u64) {
+    MAX_PIXELS.store(max_pixels, Ordering::Relaxed);
+    MAX_MEMORY.store(max_memory, Ordering::Relaxed);
+}
+
+// The message for the last failed call on this thread, or NULL. Valid until the next call.
+#[no_mangle]
+pub extern "C" fn optimizer_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use image::{GenericImageView, ImageFormat};
+
+    use super::*;
+    use crate::testing;
+
+    fn resize(png: &[u8]) -> std::result::Result<Vec<u8>, String> {
+        let mut output = OptimizerOutput {
+            data: ptr::null_mut(),
+            len: 0,
+        };
+        // SAFETY: png is a live slice and output a local
+        let status = unsafe {
+            optimizer_resize_and_compress(
+                png.as_ptr(),
+                png.len(),
+                32,
+                0,
+                OptimizerEncoder::Png,
+                80.0,
+                &mut output,
+            )
+        };
+        if status != 0 {
+            // SAFETY: a failed call always leaves a message behind
+            let message = unsafe { CStr::from_ptr(optimizer_last_error()) };
+            return Err(message.to_string_lossy().into_owned());
+        }
+        // SAFETY: the call succeeded, so output holds a buffer from this library
+        let encoded = unsafe { slice::from_raw_parts(output.data, output.len) }.to_vec();
+        unsafe { optimizer_free(output) };
+        Ok(encoded)
+    }
+
+    // One test, as the limits are shared by every thread
+    #[test]
+    fn resizes_within_the_limits() {
+        let png = testing::encode(&testing::gradient(64, 48), ImageFormat::Png);
+        let encoded = resize(&png).unwrap();
+        let decoded = image::load_from_memory(&encoded).unwrap();
+        assert_eq!(decoded.dimensions(), (32, 24));
+
+        optimizer_set_limits(1000, 0);
+        let message = resize(&png).unwrap_err();
+        optimizer_set_limits(0, 0);
+        assert!(message.contains("over the limit"), "{message}");
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::manifest::{Manifest, OutputRecord};
+use crate::optimizer::Encoder;
+
+// Listed in the order browsers should try them
+const MODERN_FORMATS: [&str; 3] = ["jxl", "avif", "webp"];
+
+fn mime_type(format: &str) -> String {
+    match Encoder::from_extension(format) {
+        Some(encoder) => encoder.mime_type().to_string(),
+        // Sources passed through as they were, such as a GIF
+        None => format!("image/{}", format.to_ascii_lowercase()),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+}
+
+// Extra qualities share a width, and a srcset may only list each width once
+fn by_width<'a>(outputs: &[&'a OutputRecord]) -> BTreeMap<usize, &'a OutputRecord> {
+    let mut by_width = BTreeMap::new();
+    for output in outputs {
+        by_width.entry(output.width).or_insert(*output);
+    }
+    by_width
+}
+
+fn srcset(outputs: &[&OutputRecord]) -> String {
+    by_width(outputs)
+        .values()
+        .map(|output| format!("{} {}w", escape(&output.path), output.width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn picture(outputs: &[OutputRecord]) -> Option<String> {
+    let mut by_format: BTreeMap<&str, Vec<&OutputRecord>> = BTreeMap::new();
+    let images = outputs.iter().filter(|output| output.placeholder.is_none());
+    for output in images {
+        by_format.entry(&output.format).or_default().push(output);
+    }
+    let modern = MODERN_FORMATS
+        .iter()
+        .filter_map(|format| by_format.get_key_value(format));
+    let fallback = by_format
+        .iter()
+        .find(|(format, _)| !MODERN_FORMATS.contains(format))
+        .or_else(|| modern.clone().next_back())?;
+
+    let mut html = String::from("<picture>\n");
+    for (format, outputs) in modern.filter(|(format, _)| *format != fallback.0) {
+        let _ = writeln!(
+            html,
+            "  <source type=\"{}\" srcset=\"{}\" sizes=\"100vw\">",
+            mime_type(format),
+            srcset(outputs)
+        );
+    }
+    let largest = *by_width(fallback.1).values().next_back()?;
+    let _ = writeln!(
+        html,
+        "  <img src=\"{}\" srcset=\"{}\" sizes=\"100vw\" width=\"{}\" height=\"{}\" alt=\"\">",
+        escape(&largest.path),
+        srcset(fallback.1),
+        largest.width,
+        largest.height
+    );
+    html.push_str("</picture>\n");
+    Some(html)
+}
+
+pub fn snippets(manifest: &Manifest) -> String {
+    manifest
+        .entries()
+        .filter_map(|(img_src, outputs)| {
+            picture(outputs).map(|html| format!("<!-- {img_src} -->\n{html}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str, width: usize) -> OutputRecord {
+        OutputRecord {
+            path: path.to_string(),
+            width,
+            height: width * 3 / 4,
+            format: path.rsplit('.').next().unwrap().to_string(),
+            quality: None,
+            bytes: 100,
+            placeholder: None,
+            dominant_color: None,
+            palette: None,
+            psnr: None,
+            dssim: None,
+        }
+    }
+
+    #[test]
+    fn modern_formats_become_sources_ahead_of_the_fallback() {
+        let mut placeholder = record("a&b.blurhash", 320);
+        placeholder.placeholder = Some("LKO2?U%2Tw=w".to_string());
+        let outputs = [
+            record("a&b_320.jpg", 320),
+            record("a&b_640_60.jpg", 640),
+            record("a&b_640_90.jpg", 640),
+            record("a&b_320.webp", 320),
+            record("a&b_640.webp", 640),
+            record("a&b_320.avif", 320),
+            placeholder,
+        ];
+        assert_eq!(
+            picture(&outputs).unwrap(),
+            "<picture>\n  \
+             <source type=\"image/avif\" srcset=\"a&amp;b_320.avif 320w\" sizes=\"100vw\">\n  \
+             <source type=\"image/webp\" srcset=\"a&amp;b_320.webp 320w, a&amp;b_640.webp 640w\" \
+             sizes=\"100vw\">\n  \
+             <img src=\"a&amp;b_640_60.jpg\" srcset=\"a&amp;b_320.jpg 320w, a&amp;b_640_60.jpg 640w\" \
+             sizes=\"100vw\" width=\"640\" height=\"480\" alt=\"\">\n\
+             </picture>\n"
+        );
+    }
+
+    #[test]
+    fn a_lone_modern_format_is_its_own_fallback() {
+        let outputs = [record("a_320.webp", 320), record("a_640.webp", 640)];
+        assert_eq!(
+            picture(&outputs).unwrap(),
+            "<picture>\n  \
+             <img src=\"a_640.webp\" srcset=\"a_320.webp 320w, a_640.webp 640w\" sizes=\"100vw\" \
+             width=\"640\" height=\"480\" alt=\"\">\n\
+             </picture>\n"
+        );
+        assert_eq!(picture(&[]), None);
+    }
+
+    #[test]
+    fn mime_types_come_from_the_encoders() {
+        assert_eq!(mime_type("jpg"), "image/jpeg");
+        assert_eq!(mime_type("JPEG"), "image/jpeg");
+        assert_eq!(mime_type("jxl"), "image/jxl");
+        assert_eq!(mime_type("gif"), "image/gif");
+    }
+}
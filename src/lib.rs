@@ -0,0 +1,41 @@
+//! Resize and re-encode images for the web.
+//!
+//! Configure an [`Optimizer`] through [`OptimizerBuilder`] (or its `set_*` methods), then call
+//! [`Optimizer::optimize`] to write the outputs to disk or [`Optimizer::optimize_to_bytes`] to
+//! keep a single result in memory.
+//!
+//! [`variants::optimize_bytes`] takes an upload straight from memory and returns every encoded
+//! variant alongside its [`VariantSpec`], without touching the filesystem.
+
+pub mod batch;
+pub mod bench;
+pub mod cache;
+pub mod config;
+pub mod contact_sheet;
+pub mod decode;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod html;
+pub mod manifest;
+pub mod optimizer;
+#[cfg(feature = "native")]
+pub mod serve;
+pub mod sink;
+pub mod summary;
+#[cfg(test)]
+mod testing;
+pub mod timings;
+pub mod utils;
+pub mod variants;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "native")]
+pub mod watch;
+
+pub use error::OptimizeError;
+pub use optimizer::{
+    Compressor, Encoder, Fit, Gravity, MetadataKind, MetadataPolicy, Optimizer, OptimizerBuilder,
+    Placeholder, UpscalePolicy, VariantSpec,
+};
+pub use utils::{resize, resize_image, resize_image_rgba, resize_rgba, ResizeConfig, ResizeFilter};
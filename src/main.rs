@@ -1,61 +1,1498 @@
-use std::vec;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::anyhow;
-use clap::Parser;
-use image::{self, GenericImageView};
-mod optimizer;
-mod utils;
-use optimizer::{Encoder, Optimizer};
+use anyhow::{anyhow, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use image::{self, DynamicImage, GenericImageView, ImageFormat};
+use img_optimizer_and_resizer::{
+    batch::{self, BatchReport, SourceFilter},
+    bench,
+    cache::{self, Cache},
+    config::{Config, Sidecar},
+    contact_sheet::{self, ContactSheet},
+    decode::{self, decode_source, DecodedSource},
+    html,
+    manifest::{Manifest, OutputRecord},
+    optimizer::{
+        self, Encoder, Fit, Gravity, MetadataKind, MetadataPolicy, Optimizer, Placeholder,
+        UpscalePolicy,
+    },
+    serve::{self, ImageRequest},
+    sink::{self, FileSink, OutputSink, S3Sink},
+    summary::Summary,
+    utils::{self, AvifBitDepth, ChromaSubsampling, DecodeLimits, ResizeFilter, Watermark},
+    watch,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    img_src: String,
-    #[arg(long, short)]
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(required_unless_present_any = ["from_file", "stdin", "serve"])]
+    img_src: Vec<String>,
+    #[arg(long, conflicts_with_all = ["img_src", "from_file", "watch"])]
+    stdin: bool,
+    #[arg(long, conflicts_with = "no_config")]
+    config: Option<PathBuf>,
+    #[arg(long)]
+    no_config: bool,
+    #[arg(long)]
+    preset: Option<String>,
+    #[arg(long)]
+    from_file: Option<String>,
+    #[arg(long, short, conflicts_with = "base_width")]
     widths: Option<Vec<usize>>,
+    #[arg(long)]
+    base_width: Option<usize>,
+    #[arg(
+        long,
+        visible_alias = "dpr",
+        value_delimiter = ',',
+        default_value = "1,2,3"
+    )]
+    densities: Vec<u32>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = utils::parse_scale,
+        conflicts_with_all = ["widths", "base_width"]
+    )]
+    scale: Option<Vec<f32>>,
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["widths", "base_width", "scale"])]
+    heights: Option<Vec<usize>>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["widths", "base_width", "scale", "heights"]
+    )]
+    max_dimension: Option<Vec<usize>>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = utils::parse_dimensions,
+        conflicts_with_all = ["widths", "base_width", "scale", "heights", "max_dimension"]
+    )]
+    size: Option<Vec<(usize, usize)>>,
+    #[arg(long, value_enum, requires = "size")]
+    fit: Option<Fit>,
+    #[arg(long, value_enum, requires = "size")]
+    gravity: Option<Gravity>,
+    #[arg(long, value_parser = utils::parse_focal_point, requires = "size")]
+    focal_point: Option<(f32, f32)>,
+    #[arg(long)]
+    max_height: Option<usize>,
+    #[arg(long, value_enum, default_value_t)]
+    upscale: UpscalePolicy,
+    #[arg(long, short, value_parser = parse_quality)]
+    quality: Option<QualitySpec>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_quality_value,
+        conflicts_with_all = ["quality", "max_bytes", "min_ssim", "no_quality_in_name", "lossless"]
+    )]
+    qualities: Option<Vec<f32>>,
+    #[arg(long, short, value_parser = parse_encoders)]
+    encoder: Option<Encoders>,
+    #[arg(long, conflicts_with_all = ["encoder", "lossless", "near_lossless"])]
+    auto_encoder: bool,
+    #[arg(long, value_parser = utils::parse_byte_size)]
+    max_bytes: Option<usize>,
+    #[arg(long, visible_alias = "target-ssim", conflicts_with = "max_bytes")]
+    min_ssim: Option<f64>,
+    #[arg(long)]
+    report_metrics: bool,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=6))]
+    webp_method: Option<u8>,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    webp_alpha_quality: Option<u8>,
+    #[arg(long)]
+    webp_sharp_yuv: bool,
+    #[arg(long)]
+    progressive: bool,
+    #[arg(long, value_enum)]
+    chroma_subsampling: Option<ChromaSubsampling>,
+    #[arg(
+        long,
+        help = "Let trellis quantization search the progressive scans too. Trellis \
+                quantization itself is always on in mozjpeg and can't be turned off"
+    )]
+    trellis_scans: bool,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
+    avif_speed: Option<u8>,
+    #[arg(long, value_enum)]
+    avif_bit_depth: Option<AvifBitDepth>,
+    #[arg(long)]
+    lossless: bool,
+    #[arg(long)]
+    jxl_transcode: bool,
+    #[arg(long)]
+    zopfli: bool,
+    #[arg(long, value_parser = clap::value_parser!(u32).range(2..=256))]
+    png_colors: Option<u32>,
+    #[arg(long, requires = "png_colors")]
+    dithering: Option<f32>,
+    #[arg(long, conflicts_with = "lossless", value_parser = clap::value_parser!(u8).range(0..=100))]
+    near_lossless: Option<u8>,
+    #[arg(long)]
+    name_template: Option<String>,
+    #[arg(long)]
+    no_quality_in_name: bool,
+    #[arg(long)]
+    sharpen: Option<f32>,
+    #[arg(long, value_enum)]
+    filter: Option<ResizeFilter>,
+    #[arg(long)]
+    keep_original_format: bool,
+    #[arg(long)]
+    metadata: Option<MetadataPolicy>,
+    #[arg(long, value_delimiter = ',', conflicts_with = "metadata")]
+    keep_metadata: Option<Vec<MetadataKind>>,
+    #[arg(long, conflicts_with_all = ["metadata", "keep_metadata"])]
+    strip_metadata: bool,
+    // Conversion is the default now, the flag is kept so existing scripts still parse
+    #[arg(long, hide = true)]
+    to_srgb: bool,
+    #[arg(long, conflicts_with = "to_srgb")]
+    keep_color_profile: bool,
+    #[arg(long, value_parser = utils::parse_hex_color)]
+    background: Option<[u8; 3]>,
+    #[arg(long)]
+    placeholder: Option<Placeholder>,
+    #[arg(long, conflicts_with = "saturation")]
+    grayscale: bool,
+    #[arg(long)]
+    brightness: Option<f32>,
+    #[arg(long)]
+    contrast: Option<f32>,
+    #[arg(long)]
+    saturation: Option<f32>,
+    #[arg(long)]
+    watermark: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = Gravity::SouthEast, requires = "watermark")]
+    watermark_position: Gravity,
+    #[arg(long, default_value_t = 1.0, requires = "watermark")]
+    watermark_opacity: f32,
+    #[arg(long, conflicts_with = "stdout")]
+    dominant_color: bool,
+    #[arg(
+        long,
+        value_name = "COLORS",
+        value_parser = clap::value_parser!(u32).range(1..=16),
+        conflicts_with = "stdout"
+    )]
+    palette: Option<u32>,
+    #[arg(long)]
+    verify: bool,
+    #[arg(long)]
+    timings: bool,
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    #[arg(long)]
+    error_report: Option<PathBuf>,
+    #[arg(long)]
+    max_pixels: Option<u64>,
+    #[arg(long, value_parser = utils::parse_byte_size)]
+    max_memory: Option<usize>,
+    #[arg(long, num_args = 0..=1, require_equals = true, conflicts_with = "stdout")]
+    html: Option<Option<PathBuf>>,
+    #[arg(long)]
+    contact_sheet: Option<PathBuf>,
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    contact_sheet_columns: u32,
+    #[arg(long)]
+    timeout: Option<u64>,
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    #[arg(long, conflicts_with = "verbose")]
+    quiet: bool,
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
     #[arg(long, short)]
-    quality: Option<f32>,
+    out_dir: Option<PathBuf>,
+    #[arg(long, default_value = sink::DEFAULT_CACHE_CONTROL)]
+    cache_control: String,
+    #[arg(long)]
+    preserve_structure: bool,
+    #[arg(long)]
+    overwrite: bool,
+    #[arg(long)]
+    stdout: bool,
+    #[arg(long, conflicts_with = "stdout")]
+    watch: bool,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = ["stdin", "from_file", "stdout", "watch", "dry_run"]
+    )]
+    serve: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["stdout", "watch", "manifest", "contact_sheet", "html"]
+    )]
+    dry_run: bool,
+    #[arg(long, conflicts_with_all = ["dry_run", "stdout"])]
+    cache: Option<PathBuf>,
+    #[arg(long)]
+    force: bool,
     #[arg(long, short)]
-    encoder: Option<Encoder>,
+    jobs: Option<usize>,
+    #[arg(long)]
+    include: Vec<String>,
+    #[arg(long)]
+    exclude: Vec<String>,
 }
 
-fn compute_height_preserving_aspect_ratio(
-    img_dimensions: (usize, usize),
-    target_width: usize,
-) -> usize {
-    let (w, h) = img_dimensions;
-    let factor = w / target_width;
-    h / factor
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    // Encodes one image with each encoder at several qualities to compare size and speed
+    Bench(BenchArgs),
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let img = image::open(&args.img_src)?;
-    let dimensions = img.dimensions();
+#[derive(Debug, Clone, clap::Args)]
+struct BenchArgs {
+    img_src: String,
+    #[arg(long, value_parser = parse_encoders, default_value = "all")]
+    encoders: Encoders,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_quality_value,
+        default_values_t = [50.0, 75.0, 90.0]
+    )]
+    qualities: Vec<f32>,
+    // Resize the sample first, benchmarks at full camera resolution take a while
+    #[arg(long)]
+    width: Option<usize>,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, Default)]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Encoders(Vec<Encoder>);
+
+fn parse_encoders(value: &str) -> anyhow::Result<Encoders> {
+    if value == "all" {
+        return Ok(Encoders(Encoder::value_variants().to_vec()));
+    }
+    let encoders = value
+        .split(',')
+        .map(|name| Encoder::parse_name(name.trim()).ok_or(anyhow!("Unknown encoder {name}")))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Encoders(encoders))
+}
+
+fn undecodable_encoder(args: &Args) -> Option<&Encoder> {
+    args.encoder
+        .iter()
+        .flat_map(|encoders| &encoders.0)
+        .find(|encoder| !encoder.is_decodable())
+}
+
+// Either a plain quality, per-encoder ones such as webp=78,avif=55, or a mix of both
+#[derive(Debug, Clone, Serialize)]
+struct QualitySpec {
+    base: Option<f32>,
+    per_encoder: Vec<(Encoder, f32)>,
+}
+
+// NaN and the infinities parse as floats but fail the range check, as every encoder needs them to
+fn parse_quality_value(quality: &str) -> anyhow::Result<f32> {
+    match quality.trim().parse::<f32>() {
+        Ok(parsed) if (0.0..=100.0).contains(&parsed) => Ok(parsed),
+        _ => Err(anyhow!("Quality must be between 0 and 100, got {quality}")),
+    }
+}
+
+fn parse_quality(value: &str) -> anyhow::Result<QualitySpec> {
+    let mut spec = QualitySpec {
+        base: None,
+        per_encoder: vec![],
+    };
+    for part in value.split(',') {
+        match part.split_once('=') {
+            Some((name, quality)) => {
+                let encoder =
+                    Encoder::parse_name(name.trim()).ok_or(anyhow!("Unknown encoder {name}"))?;
+                spec.per_encoder
+                    .push((encoder, parse_quality_value(quality)?));
+            }
+            None => spec.base = Some(parse_quality_value(part)?),
+        }
+    }
+    Ok(spec)
+}
+
+// Presets and project defaults only fill in what the command line left unset
+fn apply_defaults(args: &mut Args, config: Config) {
+    if args.widths.is_none()
+        && args.base_width.is_none()
+        && args.scale.is_none()
+        && args.heights.is_none()
+        && args.max_dimension.is_none()
+        && args.size.is_none()
+    {
+        args.widths = config.widths;
+    }
+    if args.quality.is_none() && args.qualities.is_none() {
+        args.quality = config.quality.map(|quality| QualitySpec {
+            base: Some(quality),
+            per_encoder: vec![],
+        });
+    }
+    if args.encoder.is_none() && !args.auto_encoder {
+        args.encoder = config.encoder.map(|encoder| Encoders(vec![encoder]));
+    }
+    if args.out_dir.is_none() {
+        args.out_dir = config.out_dir;
+    }
+    if args.name_template.is_none() {
+        args.name_template = config.name_template;
+    }
+    if args.sharpen.is_none() {
+        args.sharpen = config.sharpen;
+    }
+}
+
+fn read_source(img_src: &str) -> anyhow::Result<Vec<u8>> {
+    if img_src == "-" {
+        let mut buffer = vec![];
+        io::stdin().read_to_end(&mut buffer)?;
+        return Ok(buffer);
+    }
+    Ok(fs::read(img_src)?)
+}
+
+fn decode_limits(args: &Args) -> DecodeLimits {
+    DecodeLimits {
+        max_pixels: args.max_pixels,
+        max_memory: args.max_memory.map(|max_memory| max_memory as u64),
+    }
+}
+
+// Set up once per run and handed to every file
+struct Shared {
+    sink: Arc<dyn OutputSink>,
+    watermark: Option<Arc<Watermark>>,
+}
+
+impl Shared {
+    fn new(args: &Args) -> anyhow::Result<Shared> {
+        let sink: Arc<dyn OutputSink> = match &args.out_dir {
+            Some(out_dir) if sink::is_s3_url(out_dir) => {
+                let url = out_dir.to_string_lossy();
+                Arc::new(S3Sink::new(&url, &args.cache_control)?)
+            }
+            _ => Arc::new(FileSink),
+        };
+        let watermark = match &args.watermark {
+            Some(path) => {
+                let image = image::open(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?
+                    .to_rgba8();
+                let anchor = args
+                    .watermark_position
+                    .anchor()
+                    .ok_or(anyhow!("Smart gravity can't position a watermark"))?;
+                Some(Arc::new(Watermark {
+                    image,
+                    anchor,
+                    opacity: args.watermark_opacity,
+                }))
+            }
+            None => None,
+        };
+        Ok(Shared { sink, watermark })
+    }
+}
+
+struct Optimized {
+    outputs: Vec<OutputRecord>,
+    thumbnail: Option<DynamicImage>,
+}
+
+fn optimize_file(
+    img_src: &str,
+    source_root: &Path,
+    args: &Args,
+    shared: &Shared,
+) -> anyhow::Result<Optimized> {
+    let overrides = Sidecar::load(img_src)?.unwrap_or_default();
+    let buffer = read_source(img_src)?;
+    let svg = utils::is_svg(img_src, &buffer);
+    // The outputs only depend on the source's size, so a JPEG's or PNG's are planned from its
+    // header and it's decoded no larger than they need
+    let header = decode::header_size(&buffer);
+    let header_plan = header
+        .map(|dimensions| plan_resize(args, &overrides, dimensions, svg))
+        .transpose()?;
+    let min_size = header
+        .zip(
+            header_plan
+                .as_ref()
+                .and_then(|plan| plan.targets.as_deref()),
+        )
+        .map(|(dimensions, targets)| decode::decode_size_for(dimensions, targets));
+    let decode_start = Instant::now();
+    let DecodedSource {
+        img,
+        dimensions,
+        format,
+        metadata,
+    } = decode::decode_source_scaled(&buffer, Some(img_src), &decode_limits(args), min_size)?;
+    let decode_time = decode_start.elapsed();
+    let plan = match header_plan {
+        Some(plan) => plan,
+        None => plan_resize(args, &overrides, dimensions, svg)?,
+    };
+    let thumbnail = match args.contact_sheet {
+        Some(_) => Some(contact_sheet::thumbnail(&img)?),
+        None => None,
+    };
+    let auto_encoder = (args.auto_encoder && overrides.encoder.is_none())
+        .then(|| batch::choose_encoder(img_src, &img));
+
+    let mut optimizer = Optimizer::new(img, img_src);
+    optimizer.record_decode_time(decode_time);
+    optimizer.set_source_dimensions(dimensions);
+
+    if let Some(timeout) = args.timeout {
+        optimizer.set_deadline(decode_start + Duration::from_secs(timeout));
+    }
 
-    let mut optimizer = Optimizer::new(img, &args.img_src);
+    if let Some(format) = format {
+        optimizer.set_source_format(format);
+    }
+
+    optimizer.set_source_metadata(metadata);
+
+    if format == Some(ImageFormat::Gif) {
+        if let Some(animation) = utils::decode_gif_animation(&buffer)? {
+            optimizer.set_animation(animation);
+        }
+    }
+
+    if svg {
+        optimizer.set_svg_source(buffer.clone());
+    }
+
+    if args.jxl_transcode {
+        if format == Some(ImageFormat::Jpeg) {
+            optimizer.set_jxl_transcode_source(buffer.clone());
+        } else {
+            warn!("{img_src} is not a JPEG, skipping the lossless JPEG XL transcode");
+        }
+    }
 
-    if args.widths.is_none() && args.quality.is_none() {
-        return Err(anyhow!("Either widths or quality must be provided"));
+    if !args.keep_color_profile {
+        optimizer.convert_to_srgb()?;
     }
 
-    if let Some(target_widths) = args.widths {
-        let w: usize = dimensions.0.try_into().unwrap();
-        let h: usize = dimensions.1.try_into().unwrap();
-        let mut computed_target_dimensions = vec![];
-        for target_width in target_widths {
-            let target_height = compute_height_preserving_aspect_ratio((w, h), target_width);
-            computed_target_dimensions.push((target_width, target_height));
+    if let Some(base_width) = plan.density_base_width {
+        optimizer.set_density_base_width(base_width);
+    }
+    if let Some(fit) = plan.fit {
+        optimizer.set_fit(fit);
+        if let Some(gravity) = args.gravity {
+            optimizer.set_gravity(gravity);
+        }
+        if let Some(focal_point) = overrides.focal_point.or(args.focal_point) {
+            optimizer.set_focal_point(focal_point);
         }
-        optimizer.set_targets(computed_target_dimensions);
+    }
+    if let Some(targets) = plan.targets {
+        optimizer.set_targets(targets)?;
     }
 
-    if let Some(quality) = args.quality {
+    if let Some(quality) = overrides.quality {
         optimizer.set_quality(quality);
+    } else if let Some(spec) = &args.quality {
+        if let Some(quality) = spec.base {
+            optimizer.set_quality(quality);
+        }
+        for (encoder, quality) in &spec.per_encoder {
+            optimizer.set_encoder_quality(encoder.clone(), *quality);
+        }
+    } else if let Some(qualities) = args.qualities.clone() {
+        optimizer.set_qualities(qualities);
+    }
+
+    if let Some(max_bytes) = args.max_bytes {
+        optimizer.set_max_bytes(max_bytes);
+    }
+
+    if let Some(min_ssim) = args.min_ssim {
+        optimizer.set_min_ssim(min_ssim);
+    }
+
+    optimizer.set_report_metrics(args.report_metrics);
+
+    if let Some(encoder) = overrides.encoder {
+        optimizer.set_encoder(encoder);
+    } else if let Some(Encoders(encoders)) = args.encoder.clone() {
+        optimizer.set_encoders(encoders);
+    }
+
+    if let Some(method) = args.webp_method {
+        optimizer.set_webp_method(method);
+    }
+
+    if let Some(alpha_quality) = args.webp_alpha_quality {
+        optimizer.set_webp_alpha_quality(alpha_quality);
+    }
+
+    if args.webp_sharp_yuv {
+        optimizer.set_webp_sharp_yuv(true);
     }
 
-    if let Some(encoder) = args.encoder {
+    if let Some((encoder, lossless)) = auto_encoder {
         optimizer.set_encoder(encoder);
+        optimizer.set_lossless(lossless);
+    }
+
+    if args.progressive {
+        optimizer.set_progressive(true);
+    }
+
+    if let Some(subsampling) = args.chroma_subsampling {
+        optimizer.set_chroma_subsampling(subsampling);
+    }
+
+    if args.trellis_scans {
+        optimizer.set_trellis_scans(true);
+    }
+
+    if let Some(speed) = args.avif_speed {
+        optimizer.set_avif_speed(speed);
+    }
+
+    if let Some(bit_depth) = args.avif_bit_depth {
+        optimizer.set_avif_bit_depth(bit_depth);
+    }
+
+    if args.lossless {
+        optimizer.set_lossless(true);
+    }
+
+    if args.zopfli {
+        optimizer.set_png_zopfli(true);
+    }
+
+    if let Some(max_colors) = args.png_colors {
+        optimizer.set_png_max_colors(max_colors);
+    }
+
+    if let Some(dithering) = args.dithering {
+        optimizer.set_png_dithering(dithering);
+    }
+
+    if let Some(near_lossless) = args.near_lossless {
+        optimizer.set_webp_near_lossless(near_lossless);
+    }
+
+    if let Some(template) = &args.name_template {
+        optimizer.set_name_template(template)?;
+    }
+
+    optimizer.set_quality_in_name(!args.no_quality_in_name);
+
+    // Heavily downscaled sources tend to need a different amount, so sidecars can override it
+    if let Some(amount) = overrides.sharpen.or(args.sharpen) {
+        optimizer.set_sharpen(amount)?;
+    }
+
+    if let Some(filter) = args.filter {
+        optimizer.set_filter(filter);
+    }
+
+    optimizer.set_keep_original_format(args.keep_original_format);
+    let metadata_policy = if args.strip_metadata {
+        Some(MetadataPolicy::Strip)
+    } else if let Some(kinds) = args.keep_metadata.clone() {
+        Some(MetadataPolicy::Only(kinds))
+    } else {
+        args.metadata.clone()
+    };
+    if let Some(metadata_policy) = metadata_policy {
+        optimizer.set_metadata_policy(metadata_policy);
+    }
+
+    if let Some(placeholder) = args.placeholder.clone() {
+        optimizer.set_placeholder(placeholder);
+    }
+
+    if let Some(background) = args.background {
+        optimizer.set_background(background);
+    }
+
+    optimizer.set_verify(args.verify);
+    optimizer.set_overwrite(args.overwrite || args.force);
+    optimizer.set_grayscale(args.grayscale);
+    if let Some(brightness) = args.brightness {
+        optimizer.set_brightness(brightness);
+    }
+    if let Some(contrast) = args.contrast {
+        optimizer.set_contrast(contrast);
+    }
+    if let Some(saturation) = args.saturation {
+        optimizer.set_saturation(saturation);
+    }
+
+    optimizer.set_sink(shared.sink.clone());
+    if let Some(watermark) = &shared.watermark {
+        optimizer.set_watermark(watermark.clone());
+    }
+
+    if let Some(out_dir) = &args.out_dir {
+        optimizer.set_out_dir(out_dir.clone());
+        if args.preserve_structure {
+            optimizer.set_source_root(source_root.to_path_buf());
+        }
+    }
+
+    // Sampled after the sRGB conversion so the hex values match what browsers show
+    let palette_size = args.palette.or(args.dominant_color.then_some(1));
+    let colors: Option<Vec<_>> = palette_size.map(|count| {
+        optimizer
+            .palette(count as usize)
+            .into_iter()
+            .map(utils::format_hex_color)
+            .collect()
+    });
+
+    if args.dry_run {
+        let mut outputs = optimizer.planned_outputs()?;
+        if let Some(colors) = &colors {
+            record_colors(&mut outputs, colors, args.palette.is_some());
+        }
+        for output in &outputs {
+            println!(
+                "{} {}x{} {}",
+                output.path, output.width, output.height, output.format
+            );
+        }
+        return Ok(Optimized { outputs, thumbnail });
+    }
+
+    let result = if args.stdout || img_src == "-" {
+        write_optimized(&optimizer, io::stdout().lock())
+    } else {
+        optimizer.optimize().map_err(anyhow::Error::from)
+    };
+
+    if args.timings {
+        eprint!("{img_src}:\n{}", optimizer.timings());
+    }
+
+    result?;
+    let mut outputs = optimizer.outputs();
+    if let Some(colors) = &colors {
+        record_colors(&mut outputs, colors, args.palette.is_some());
+    }
+    Ok(Optimized { outputs, thumbnail })
+}
+
+// What the resize flags ask of a source this size, worked out from the size alone so a JPEG's
+// outputs are known before it's decoded
+#[derive(Default)]
+struct ResizePlan {
+    targets: Option<Vec<(usize, usize)>>,
+    density_base_width: Option<usize>,
+    // Only exact sizes crop or pad
+    fit: Option<Fit>,
+}
+
+fn plan_resize(
+    args: &Args,
+    overrides: &Sidecar,
+    dimensions: (u32, u32),
+    svg: bool,
+) -> anyhow::Result<ResizePlan> {
+    let mut plan = ResizePlan::default();
+    let widths = overrides.widths.as_ref().or(args.widths.as_ref());
+    let targets = if let Some(widths) = widths {
+        Some(optimizer::responsive_dimensions(dimensions, widths))
+    } else if let Some(base_width) = args.base_width {
+        plan.density_base_width = Some(base_width);
+        let widths = optimizer::density_widths(base_width, &args.densities, dimensions.0);
+        Some(optimizer::responsive_dimensions(dimensions, &widths))
+    } else if let Some(heights) = &args.heights {
+        Some(optimizer::height_dimensions(dimensions, heights))
+    } else if let Some(lengths) = &args.max_dimension {
+        Some(optimizer::max_dimension_targets(dimensions, lengths))
+    } else if let Some(sizes) = &args.size {
+        // Exact sizes crop to fill the box unless told otherwise
+        let fit = args.fit.unwrap_or(Fit::Cover);
+        plan.fit = Some(fit);
+        Some(optimizer::exact_dimensions(dimensions, sizes, fit))
+    } else {
+        args.scale
+            .as_ref()
+            .map(|percentages| optimizer::scaled_dimensions(dimensions, percentages))
+    };
+
+    if let Some(mut targets) = targets {
+        if let Some(max_height) = args.max_height {
+            targets = optimizer::limit_height(dimensions, targets, max_height);
+        }
+        // Vectors render at any size, there's nothing to upscale
+        if !svg {
+            targets = optimizer::apply_upscale_policy(dimensions, targets, &args.upscale)?;
+        }
+        plan.targets = Some(targets);
+    }
+    Ok(plan)
+}
+
+// Printed from the records once a source is done, so cache hits report theirs too and parallel
+// sources never write into the progress bar
+fn print_colors(img_src: &str, outputs: &[OutputRecord], args: &Args) {
+    let Some(output) = outputs.first() else {
+        return;
+    };
+    let colors = match (&output.palette, &output.dominant_color) {
+        (Some(palette), _) => palette.join(" "),
+        (None, Some(color)) => color.clone(),
+        (None, None) => return,
+    };
+    // Keep stdout clean when it carries the image itself
+    if args.stdout || img_src == "-" {
+        eprintln!("{img_src} {colors}");
+    } else {
+        println!("{img_src} {colors}");
+    }
+}
+
+fn record_colors(outputs: &mut [OutputRecord], colors: &[String], with_palette: bool) {
+    for output in outputs {
+        output.dominant_color = colors.first().cloned();
+        if with_palette {
+            output.palette = Some(colors.to_vec());
+        }
+    }
+}
+
+// Every option that changes what gets written, so adding -v or --jobs doesn't invalidate the
+// cache
+#[derive(Serialize)]
+struct OutputSettings<'a> {
+    widths: &'a Option<Vec<usize>>,
+    base_width: Option<usize>,
+    densities: &'a [u32],
+    scale: &'a Option<Vec<f32>>,
+    heights: &'a Option<Vec<usize>>,
+    max_dimension: &'a Option<Vec<usize>>,
+    size: &'a Option<Vec<(usize, usize)>>,
+    fit: Option<Fit>,
+    gravity: Option<Gravity>,
+    focal_point: Option<(f32, f32)>,
+    max_height: Option<usize>,
+    upscale: &'a UpscalePolicy,
+    quality: &'a Option<QualitySpec>,
+    qualities: &'a Option<Vec<f32>>,
+    encoder: &'a Option<Encoders>,
+    auto_encoder: bool,
+    max_bytes: Option<usize>,
+    min_ssim: Option<f64>,
+    report_metrics: bool,
+    webp_method: Option<u8>,
+    webp_alpha_quality: Option<u8>,
+    webp_sharp_yuv: bool,
+    progressive: bool,
+    chroma_subsampling: Option<ChromaSubsampling>,
+    trellis_scans: bool,
+    avif_speed: Option<u8>,
+    avif_bit_depth: Option<AvifBitDepth>,
+    lossless: bool,
+    jxl_transcode: bool,
+    zopfli: bool,
+    png_colors: Option<u32>,
+    dithering: Option<f32>,
+    near_lossless: Option<u8>,
+    name_template: &'a Option<String>,
+    no_quality_in_name: bool,
+    sharpen: Option<f32>,
+    filter: Option<ResizeFilter>,
+    keep_original_format: bool,
+    metadata: &'a Option<MetadataPolicy>,
+    keep_metadata: &'a Option<Vec<MetadataKind>>,
+    strip_metadata: bool,
+    to_srgb: bool,
+    keep_color_profile: bool,
+    background: Option<[u8; 3]>,
+    placeholder: &'a Option<Placeholder>,
+    grayscale: bool,
+    brightness: Option<f32>,
+    contrast: Option<f32>,
+    saturation: Option<f32>,
+    watermark_position: Gravity,
+    watermark_opacity: f32,
+    dominant_color: bool,
+    palette: Option<u32>,
+    out_dir: &'a Option<PathBuf>,
+    cache_control: &'a str,
+    preserve_structure: bool,
+}
+
+// SHA-256 of the settings and the watermark file, the same on every build and platform so a
+// cache survives upgrading the toolchain
+fn settings_digest(args: &Args) -> anyhow::Result<String> {
+    let settings = OutputSettings {
+        widths: &args.widths,
+        base_width: args.base_width,
+        densities: &args.densities,
+        scale: &args.scale,
+        heights: &args.heights,
+        max_dimension: &args.max_dimension,
+        size: &args.size,
+        fit: args.fit,
+        gravity: args.gravity,
+        focal_point: args.focal_point,
+        max_height: args.max_height,
+        upscale: &args.upscale,
+        quality: &args.quality,
+        qualities: &args.qualities,
+        encoder: &args.encoder,
+        auto_encoder: args.auto_encoder,
+        max_bytes: args.max_bytes,
+        min_ssim: args.min_ssim,
+        report_metrics: args.report_metrics,
+        webp_method: args.webp_method,
+        webp_alpha_quality: args.webp_alpha_quality,
+        webp_sharp_yuv: args.webp_sharp_yuv,
+        progressive: args.progressive,
+        chroma_subsampling: args.chroma_subsampling,
+        trellis_scans: args.trellis_scans,
+        avif_speed: args.avif_speed,
+        avif_bit_depth: args.avif_bit_depth,
+        lossless: args.lossless,
+        jxl_transcode: args.jxl_transcode,
+        zopfli: args.zopfli,
+        png_colors: args.png_colors,
+        dithering: args.dithering,
+        near_lossless: args.near_lossless,
+        name_template: &args.name_template,
+        no_quality_in_name: args.no_quality_in_name,
+        sharpen: args.sharpen,
+        filter: args.filter,
+        keep_original_format: args.keep_original_format,
+        metadata: &args.metadata,
+        keep_metadata: &args.keep_metadata,
+        strip_metadata: args.strip_metadata,
+        to_srgb: args.to_srgb,
+        keep_color_profile: args.keep_color_profile,
+        background: args.background,
+        placeholder: &args.placeholder,
+        grayscale: args.grayscale,
+        brightness: args.brightness,
+        contrast: args.contrast,
+        saturation: args.saturation,
+        watermark_position: args.watermark_position,
+        watermark_opacity: args.watermark_opacity,
+        dominant_color: args.dominant_color,
+        palette: args.palette,
+        out_dir: &args.out_dir,
+        cache_control: &args.cache_control,
+        preserve_structure: args.preserve_structure,
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&settings)?);
+    if let Some(path) = &args.watermark {
+        let watermark =
+            fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        hasher.update(watermark);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn optimize_cached(
+    img_src: &str,
+    source_root: &Path,
+    args: &Args,
+    shared: &Shared,
+    cache: Option<&Cache>,
+    settings: &str,
+) -> anyhow::Result<(Optimized, Option<String>)> {
+    let key = match cache {
+        Some(_) if img_src != "-" => Some(cache::source_key(img_src, settings)?),
+        _ => None,
+    };
+    // A contact sheet needs every image decoded, so nothing can be skipped then
+    let lookup = cache.filter(|_| !args.force && args.contact_sheet.is_none());
+    if let (Some(cache), Some(key)) = (lookup, &key) {
+        if let Some(outputs) = cache.fresh(img_src, key, shared.sink.as_ref()) {
+            info!("Skipping {img_src}, unchanged since the last run");
+            let optimized = Optimized {
+                outputs,
+                thumbnail: None,
+            };
+            return Ok((optimized, Some(key.clone())));
+        }
+    }
+    Ok((optimize_file(img_src, source_root, args, shared)?, key))
+}
+
+// Requests never upscale, asking for more than the source has returns it at full size
+fn serve_image(request: &ImageRequest, args: &Args) -> anyhow::Result<Vec<u8>> {
+    let img_src = request.path.to_string_lossy();
+    let buffer = read_source(&img_src)?;
+    let DecodedSource { img, metadata, .. } =
+        decode_source(&buffer, Some(&img_src), &decode_limits(args))?;
+    let dimensions = img.dimensions();
+
+    let mut optimizer = Optimizer::new(img, &img_src);
+    optimizer.set_source_metadata(metadata);
+    if !args.keep_color_profile {
+        optimizer.convert_to_srgb()?;
+    }
+    if let Some(filter) = args.filter {
+        optimizer.set_filter(filter);
+    }
+    optimizer.set_encoder(request.encoder.clone());
+    optimizer.set_quality(
+        request
+            .quality
+            .unwrap_or_else(|| request.encoder.default_quality()),
+    );
+
+    let targets = match (request.width, request.height) {
+        (Some(width), Some(height)) => {
+            let fit = args.fit.unwrap_or(Fit::Cover);
+            optimizer.set_fit(fit);
+            if let Some(gravity) = args.gravity {
+                optimizer.set_gravity(gravity);
+            }
+            optimizer::exact_dimensions(dimensions, &[(width, height)], fit)
+        }
+        (Some(width), None) => optimizer::responsive_dimensions(dimensions, &[width]),
+        (None, Some(height)) => optimizer::height_dimensions(dimensions, &[height]),
+        (None, None) => vec![],
+    };
+    let targets = optimizer::apply_upscale_policy(dimensions, targets, &UpscalePolicy::Skip)?;
+    optimizer.set_targets(targets)?;
+    Ok(optimizer.optimize_to_bytes()?)
+}
+
+fn write_optimized(optimizer: &Optimizer, mut out: impl Write) -> anyhow::Result<()> {
+    let optimized = optimizer.optimize_to_bytes()?;
+    out.write_all(&optimized)?;
+    Ok(out.flush()?)
+}
+
+// Log lines would tear through the bar, so it only shows when logging is quiet
+fn progress_bar(len: usize, args: &Args) -> anyhow::Result<ProgressBar> {
+    if len < 2 || args.verbose > 0 || args.quiet || args.dry_run {
+        return Ok(ProgressBar::hidden());
+    }
+    let template = "{bar:40} {pos}/{len} {elapsed_precise} (eta {eta})";
+    let style = ProgressStyle::with_template(template)?.progress_chars("=> ");
+    Ok(ProgressBar::new(len as u64).with_style(style))
+}
+
+fn run_bench(args: &BenchArgs) -> anyhow::Result<()> {
+    let buffer = read_source(&args.img_src)?;
+    let img = decode_source(&buffer, Some(&args.img_src), &DecodeLimits::default())?.img;
+    let img = match args.width {
+        Some(width) => {
+            let (_, height) = optimizer::responsive_dimensions(img.dimensions(), &[width])[0];
+            if img.color().has_alpha() {
+                utils::resize_image_rgba(&img, width, height, ResizeFilter::default())?
+            } else {
+                utils::resize_image(&img, width, height, ResizeFilter::default())?
+            }
+        }
+        None => img,
+    };
+    info!(
+        "Benchmarking {} at {}x{}",
+        args.img_src,
+        img.width(),
+        img.height()
+    );
+    print!("{}", bench::run(&img, &args.encoders.0, &args.qualities)?);
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+
+    let log_level = match args.verbose {
+        _ if args.quiet => LevelFilter::ERROR,
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        _ => LevelFilter::DEBUG,
+    };
+    // RUST_LOG still overrides the level picked by -v and --quiet
+    let filter = EnvFilter::builder()
+        .with_default_directive(log_level.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    if let Some(Command::Bench(bench_args)) = &args.command {
+        return run_bench(bench_args);
+    }
+
+    let project_config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None if args.no_config => Config::default(),
+        None => Config::discover()?.unwrap_or_default(),
+    };
+    if let Some(preset) = &args.preset {
+        let preset = project_config.preset(preset)?;
+        apply_defaults(&mut args, preset);
+    }
+    apply_defaults(&mut args, project_config);
+
+    if args.widths.is_none()
+        && args.base_width.is_none()
+        && args.scale.is_none()
+        && args.heights.is_none()
+        && args.max_dimension.is_none()
+        && args.size.is_none()
+        && args.quality.is_none()
+        && args.qualities.is_none()
+        && args.max_bytes.is_none()
+        && args.min_ssim.is_none()
+        && !args.auto_encoder
+        && args.serve.is_none()
+    {
+        return Err(anyhow!(
+            "Either widths, base-width, scale, heights, max-dimension, size, quality, max-bytes, min-ssim or auto-encoder must be provided"
+        ));
+    }
+
+    if args.widths.iter().flatten().any(|&width| width == 0) {
+        return Err(anyhow!("Widths must be greater than 0"));
+    }
+
+    if [&args.heights, &args.max_dimension]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|&length| length == 0)
+    {
+        return Err(anyhow!("Heights and max dimensions must be greater than 0"));
+    }
+
+    if args.base_width == Some(0) || args.densities.contains(&0) {
+        return Err(anyhow!("Base width and densities must be greater than 0"));
+    }
+
+    if args
+        .scale
+        .iter()
+        .flatten()
+        .any(|&percentage| percentage <= 0.0)
+    {
+        return Err(anyhow!("Scale percentages must be greater than 0"));
+    }
+
+    if let Some(min_ssim) = args.min_ssim {
+        if !(0.0..=1.0).contains(&min_ssim) {
+            return Err(anyhow!("Min SSIM must be between 0.0 and 1.0"));
+        }
+        if let Some(encoder) = undecodable_encoder(&args) {
+            return Err(anyhow!(
+                "Min SSIM can't be used with {}, its outputs can't be decoded to compare",
+                encoder.name()
+            ));
+        }
     }
 
-    optimizer.optimize()
+    let searched = args.max_bytes.is_some() || args.min_ssim.is_some();
+    if searched
+        && args
+            .name_template
+            .as_ref()
+            .is_some_and(|template| template.contains("{quality}"))
+    {
+        return Err(anyhow!(
+            "Name templates can't use {{quality}} with max bytes or min SSIM, the quality is \
+             picked for each output while encoding"
+        ));
+    }
+
+    if args.verify {
+        if let Some(encoder) = undecodable_encoder(&args) {
+            return Err(anyhow!(
+                "Verify can't be used with {}, its outputs can't be decoded",
+                encoder.name()
+            ));
+        }
+    }
+
+    if [args.brightness, args.contrast, args.saturation]
+        .into_iter()
+        .flatten()
+        .any(|factor| !factor.is_finite() || factor < 0.0)
+    {
+        return Err(anyhow!(
+            "Brightness, contrast and saturation must be 0.0 or greater"
+        ));
+    }
+
+    if !(0.0..=1.0).contains(&args.watermark_opacity) {
+        return Err(anyhow!("Watermark opacity must be between 0.0 and 1.0"));
+    }
+
+    if args
+        .dithering
+        .is_some_and(|dithering| !(0.0..=1.0).contains(&dithering))
+    {
+        return Err(anyhow!("Dithering must be between 0.0 and 1.0"));
+    }
+
+    // out-dir may come from the project config, so clap can't check this
+    if args.preserve_structure && args.out_dir.is_none() {
+        return Err(anyhow!("Preserve structure requires an out-dir"));
+    }
+
+    if args.max_height == Some(0) {
+        return Err(anyhow!("Max height must be greater than 0"));
+    }
+
+    if args.jobs == Some(0) {
+        return Err(anyhow!("Jobs must be greater than 0"));
+    }
+
+    if let Some(jobs) = args.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()?;
+    }
+
+    if let Some(addr) = &args.serve {
+        let root = args
+            .img_src
+            .first()
+            .map_or(PathBuf::from("."), PathBuf::from);
+        return serve::serve(
+            addr,
+            &root,
+            rayon::current_num_threads(),
+            &args.cache_control,
+            |request| serve_image(request, &args),
+        );
+    }
+
+    let mut filter = SourceFilter::new(&args.include, &args.exclude)?;
+    if let Some(out_dir) = &args.out_dir {
+        filter.skip_output_dir(out_dir.clone());
+    }
+    // --stdin is the spelled-out form of passing "-" as the input
+    let mut inputs = if args.stdin {
+        vec!["-".to_string()]
+    } else {
+        args.img_src.clone()
+    };
+    if let Some(list_path) = &args.from_file {
+        inputs.extend(batch::read_source_list(list_path)?);
+    }
+    let sources = batch::collect_sources(&inputs, &filter)?;
+
+    let to_stdout = args.stdout || sources.iter().any(|src| src == "-");
+    if to_stdout && sources.len() > 1 {
+        return Err(anyhow!("Only a single input can be written to stdout"));
+    }
+
+    let source_root = batch::common_root(&inputs);
+    let shared = Shared::new(&args)?;
+    let mut report = BatchReport::default();
+    let mut manifest = Manifest::default();
+    let mut contact_sheet = args
+        .contact_sheet
+        .as_ref()
+        .map(|_| ContactSheet::new(args.contact_sheet_columns));
+    let mut cache = args.cache.as_deref().map(Cache::load);
+    let settings = settings_digest(&args)?;
+    let progress = progress_bar(sources.len(), &args)?;
+    let results: Vec<_> = sources
+        .par_iter()
+        .map(|img_src| {
+            let result = optimize_cached(
+                img_src,
+                &source_root,
+                &args,
+                &shared,
+                cache.as_ref(),
+                &settings,
+            );
+            progress.inc(1);
+            result
+        })
+        .collect();
+    progress.finish_and_clear();
+    let mut summary = Summary::default();
+    let mut output_count = 0;
+    for (img_src, result) in sources.iter().zip(results) {
+        let result = result.map(|(optimized, key)| {
+            if let (Some(cache), Some(key)) = (cache.as_mut(), key) {
+                cache.insert(img_src, key, optimized.outputs.clone());
+            }
+            output_count += optimized.outputs.len();
+            print_colors(img_src, &optimized.outputs, &args);
+            summary.add(img_src, &optimized.outputs);
+            manifest.add(img_src, optimized.outputs);
+            if let (Some(contact_sheet), Some(thumbnail)) =
+                (contact_sheet.as_mut(), optimized.thumbnail)
+            {
+                contact_sheet.add(thumbnail);
+            }
+        });
+        report.record(img_src, result);
+    }
+
+    if let (Some(cache), Some(cache_path)) = (&cache, &args.cache) {
+        cache.write(cache_path)?;
+    }
+
+    if let Some(manifest_path) = &args.manifest {
+        manifest.write(manifest_path)?;
+    }
+
+    if let (Some(contact_sheet), Some(sheet_path)) = (&contact_sheet, &args.contact_sheet) {
+        contact_sheet.write(sheet_path)?;
+    }
+
+    if let Some(html_path) = &args.html {
+        let snippets = html::snippets(&manifest);
+        match html_path {
+            Some(path) => fs::write(path, snippets)
+                .with_context(|| format!("failed to write {}", path.display()))?,
+            None => print!("{snippets}"),
+        }
+    }
+
+    for (img_src, err) in report.failures() {
+        error!("Failed to optimize {img_src}: {err:#}");
+    }
+    if let Some(report_path) = &args.error_report {
+        report.write(report_path)?;
+    }
+    let show_summary = sources.len() > 1 || output_count > 1 || args.report_metrics;
+    if show_summary && !args.quiet && !args.dry_run && !summary.is_empty() {
+        eprintln!("{summary}");
+    }
+    if !args.quiet {
+        eprintln!("{report}");
+    }
+
+    if args.watch {
+        watch::watch(&batch::watch_roots(&inputs), &filter, |img_src| {
+            match optimize_file(img_src, &source_root, &args, &shared) {
+                Ok(optimized) => {
+                    print_colors(img_src, &optimized.outputs, &args);
+                    manifest.add(img_src, optimized.outputs);
+                    info!("Optimized {img_src}");
+                }
+                Err(err) => error!("Failed to optimize {img_src}: {err:#}"),
+            }
+            if let Some(manifest_path) = &args.manifest {
+                if let Err(err) = manifest.write(manifest_path) {
+                    error!("{err:#}");
+                }
+            }
+        })?;
+    }
+
+    process::exit(report.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageFormat};
+
+    use super::*;
+
+    #[test]
+    fn piped_sources_are_written_as_a_single_image() {
+        let mut png = vec![];
+        DynamicImage::new_rgb8(64, 48)
+            .write_to(&mut io::Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let DecodedSource { img, .. } =
+            decode_source(&png, None, &DecodeLimits::default()).unwrap();
+        let mut optimizer = Optimizer::new(img, "-");
+        optimizer.set_encoder(Encoder::Png);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        let mut piped = vec![];
+        write_optimized(&optimizer, &mut piped).unwrap();
+        let decoded = image::load_from_memory(&piped).unwrap();
+        assert_eq!(decoded.dimensions(), (32, 24));
+
+        optimizer.set_targets(vec![(16, 12), (32, 24)]).unwrap();
+        let err = write_optimized(&optimizer, &mut vec![]).unwrap_err();
+        assert!(err.to_string().contains("multiple target widths"), "{err}");
+    }
+
+    #[test]
+    fn webp_options_are_range_checked() {
+        let parse = |flag: &str, value: &str| {
+            Args::try_parse_from(["img-optimizer-and-resizer", flag, value, "a.jpg"])
+        };
+        assert_eq!(parse("--webp-method", "6").unwrap().webp_method, Some(6));
+        assert!(parse("--webp-method", "7").is_err());
+        assert!(parse("--webp-alpha-quality", "101").is_err());
+        assert!(parse("--near-lossless", "101").is_err());
+        let lossless = Args::try_parse_from([
+            "img-optimizer-and-resizer",
+            "--near-lossless",
+            "60",
+            "--lossless",
+            "a.png",
+        ]);
+        assert!(lossless.is_err());
+    }
+
+    #[test]
+    fn encoder_options_change_the_cache_key() {
+        let digest = |extra: &[&str]| {
+            let mut argv = vec!["img-optimizer-and-resizer", "-w", "320"];
+            argv.extend_from_slice(extra);
+            argv.push("a.jpg");
+            settings_digest(&Args::parse_from(argv)).unwrap()
+        };
+        let base = digest(&[]);
+        assert_eq!(digest(&["-v", "--jobs", "2"]), base);
+        assert_ne!(digest(&["--webp-method", "6"]), base);
+        assert_ne!(digest(&["--chroma-subsampling", "444"]), base);
+        assert_ne!(
+            digest(&["--webp-method", "6"]),
+            digest(&["--webp-method", "5"])
+        );
+    }
+
+    #[test]
+    fn sidecars_only_override_their_own_image() {
+        let dir = std::env::temp_dir().join(format!("img-optimizer-sidecars-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a.png", "b.png"] {
+            DynamicImage::new_rgb8(64, 48).save(dir.join(name)).unwrap();
+        }
+        fs::write(dir.join("b.png.toml"), "quality = 40\nwidths = [16]\n").unwrap();
+        let out_dir = dir.join("out");
+        let args = Args::parse_from([
+            "img-optimizer-and-resizer",
+            "-w",
+            "32",
+            "-q",
+            "80",
+            "-e",
+            "mozjpeg",
+            "-o",
+            out_dir.to_str().unwrap(),
+            "unused",
+        ]);
+        let shared = Shared::new(&args).unwrap();
+        let outputs = |name: &str| {
+            let src = dir.join(name);
+            optimize_file(src.to_str().unwrap(), &dir, &args, &shared)
+                .unwrap()
+                .outputs
+        };
+        let (a, b) = (outputs("a.png"), outputs("b.png"));
+        assert_eq!((a[0].width, a[0].quality), (32, Some(80.0)));
+        assert_eq!((b[0].width, b[0].quality), (16, Some(40.0)));
+    }
+
+    #[test]
+    fn transparent_pngs_keep_their_alpha_as_webp() {
+        let dir = std::env::temp_dir().join(format!("img-optimizer-alpha-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("logo.png");
+        image::RgbaImage::from_fn(64, 32, |x, _| {
+            image::Rgba([0, 0, 255, if x < 32 { 0 } else { 255 }])
+        })
+        .save(&src)
+        .unwrap();
+        let args = Args::parse_from([
+            "img-optimizer-and-resizer",
+            "-w",
+            "16",
+            "-e",
+            "webp",
+            "-o",
+            dir.join("out").to_str().unwrap(),
+            "unused",
+        ]);
+        let shared = Shared::new(&args).unwrap();
+        let optimized = optimize_file(src.to_str().unwrap(), &dir, &args, &shared).unwrap();
+        let output = &optimized.outputs[0];
+        assert_eq!(output.format, "webp");
+        let decoded = image::open(&output.path).unwrap();
+        assert!(decoded.color().has_alpha());
+        let decoded = decoded.to_rgba8();
+        assert_eq!(decoded.get_pixel(2, 4)[3], 0);
+        assert_eq!(decoded.get_pixel(13, 4)[3], 255);
+    }
+
+    #[test]
+    fn qualities_must_be_finite_and_in_range() {
+        let spec = parse_quality("80, webp=70").unwrap();
+        assert_eq!(spec.base, Some(80.0));
+        assert_eq!(spec.per_encoder, vec![(Encoder::WebP, 70.0)]);
+        for invalid in ["NaN", "inf", "-inf", "101", "-1", "webp=nan", "high"] {
+            assert!(parse_quality(invalid).is_err(), "{invalid}");
+        }
+        let parsed = Args::try_parse_from([
+            "img-optimizer-and-resizer",
+            "--qualities",
+            "60,NaN",
+            "a.jpg",
+        ]);
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn dominant_colors_are_recorded_in_the_outputs() {
+        let dir = std::env::temp_dir().join(format!("img-optimizer-colors-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("red.png");
+        image::RgbImage::from_pixel(16, 16, image::Rgb([255, 0, 0]))
+            .save(&src)
+            .unwrap();
+        let args = Args::parse_from([
+            "img-optimizer-and-resizer",
+            "-w",
+            "8",
+            "-e",
+            "png",
+            "--dominant-color",
+            "-o",
+            dir.join("out").to_str().unwrap(),
+            "unused",
+        ]);
+        let shared = Shared::new(&args).unwrap();
+        let optimized = optimize_file(src.to_str().unwrap(), &dir, &args, &shared).unwrap();
+        let output = &optimized.outputs[0];
+        assert_eq!(output.dominant_color.as_deref(), Some("#ff0000"));
+        assert_eq!(output.palette, None);
+    }
 }
@@ -0,0 +1,105 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputRecord {
+    pub path: String,
+    pub width: usize,
+    pub height: usize,
+    pub format: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<f32>,
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dominant_color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub psnr: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dssim: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Manifest(BTreeMap<String, Vec<OutputRecord>>);
+
+impl Manifest {
+    pub fn add(&mut self, img_src: &str, outputs: Vec<OutputRecord>) {
+        // Watch mode re-optimizes sources, so replace rather than append
+        self.0.insert(img_src.to_string(), outputs);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &[OutputRecord])> {
+        self.0
+            .iter()
+            .map(|(img_src, outputs)| (img_src.as_str(), outputs.as_slice()))
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json).with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::{Encoder, Optimizer};
+    use crate::testing;
+
+    #[test]
+    fn manifests_map_each_source_to_its_outputs() {
+        let dir = testing::temp_dir("manifest");
+        let mut manifest = Manifest::default();
+        for name in ["hero.png", "logo.png"] {
+            let src = dir.join(name);
+            let mut optimizer = Optimizer::new(testing::gradient(64, 48), src.to_str().unwrap());
+            optimizer.set_encoder(Encoder::Png);
+            optimizer.set_targets(vec![(16, 12), (32, 24)]).unwrap();
+            optimizer.optimize().unwrap();
+            manifest.add(src.to_str().unwrap(), optimizer.outputs());
+        }
+        let path = dir.join("manifest.json");
+        manifest.write(&path).unwrap();
+
+        let json: BTreeMap<String, Vec<OutputRecord>> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json.len(), 2);
+        let hero = &json[dir.join("hero.png").to_str().unwrap()];
+        let widths: Vec<_> = hero.iter().map(|output| output.width).collect();
+        assert_eq!(widths, [16, 32]);
+        for output in hero {
+            assert_eq!(fs::metadata(&output.path).unwrap().len(), output.bytes);
+            assert_eq!(output.format, "png");
+        }
+    }
+
+    #[test]
+    fn re_added_sources_replace_their_outputs() {
+        let mut manifest = Manifest::default();
+        let output = |width| OutputRecord {
+            path: format!("optimized/hero_{width}.png"),
+            width,
+            height: width / 2,
+            format: "png".to_string(),
+            quality: None,
+            bytes: 100,
+            placeholder: None,
+            dominant_color: None,
+            palette: None,
+            psnr: None,
+            dssim: None,
+        };
+        manifest.add("hero.png", vec![output(320), output(640)]);
+        manifest.add("hero.png", vec![output(640)]);
+        let entries: Vec<_> = manifest.entries().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].1.len(), 1);
+        assert_eq!(entries[0].1[0].width, 640);
+    }
+}
@@ -1,23 +1,439 @@
 use std::{
-    fs::File,
-    io::Write,
+    borrow::Cow,
+    cell::OnceCell,
+    fs,
+    io::Cursor,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::utils::{self, ensure_parent_directory_exists};
-use anyhow::{anyhow, Ok};
+use crate::error::{OptimizeError, Result};
+use crate::manifest::OutputRecord;
+use crate::sink::{FileSink, OutputSink};
+use crate::timings::Timings;
+use crate::utils::{self, ChromaSubsampling, MetadataSegment, ResizeFilter};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use clap::ValueEnum;
-use image::{DynamicImage, GenericImageView};
+use image::{imageops, DynamicImage, GenericImageView, ImageFormat};
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, ValueEnum, Clone)]
+#[derive(Debug, ValueEnum, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Encoder {
     WebP,
     MozJpeg,
+    Avif,
+    JpegXl,
+    Png,
 }
 
+#[derive(Debug, ValueEnum, Clone, Serialize)]
+pub enum Placeholder {
+    Blurhash,
+    Thumbhash,
+    #[value(alias = "base64")]
+    Lqip,
+}
+
+#[derive(Debug, ValueEnum, Clone, Default, Serialize)]
+pub enum MetadataPolicy {
+    #[default]
+    Strip,
+    Copyright,
+    All,
+    #[value(skip)]
+    Only(Vec<MetadataKind>),
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Serialize)]
+pub enum Fit {
+    Cover,
+    Contain,
+    #[default]
+    Fill,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, Default, Serialize)]
+#[value(rename_all = "lower")]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+    Smart,
+}
+
+impl Gravity {
+    pub fn anchor(&self) -> Option<(f32, f32)> {
+        match self {
+            Gravity::Center => Some((0.5, 0.5)),
+            Gravity::North => Some((0.5, 0.0)),
+            Gravity::NorthEast => Some((1.0, 0.0)),
+            Gravity::East => Some((1.0, 0.5)),
+            Gravity::SouthEast => Some((1.0, 1.0)),
+            Gravity::South => Some((0.5, 1.0)),
+            Gravity::SouthWest => Some((0.0, 1.0)),
+            Gravity::West => Some((0.0, 0.5)),
+            Gravity::NorthWest => Some((0.0, 0.0)),
+            Gravity::Smart => None,
+        }
+    }
+}
+
+#[derive(Debug, ValueEnum, Clone, Default, Serialize)]
+pub enum UpscalePolicy {
+    #[default]
+    Allow,
+    Skip,
+    Error,
+}
+
+#[derive(Debug, ValueEnum, Clone, PartialEq, Serialize)]
+pub enum MetadataKind {
+    Copyright,
+    Icc,
+    Exif,
+    Xmp,
+}
+
+impl MetadataKind {
+    fn matches(&self, segment: &MetadataSegment) -> bool {
+        match self {
+            MetadataKind::Copyright => {
+                matches!(segment.marker, utils::APP13_MARKER | utils::COM_MARKER)
+            }
+            MetadataKind::Icc => {
+                segment.marker == utils::APP2_MARKER && segment.data.starts_with(b"ICC_PROFILE\0")
+            }
+            MetadataKind::Exif => {
+                segment.marker == utils::APP1_MARKER && segment.data.starts_with(b"Exif\0\0")
+            }
+            MetadataKind::Xmp => {
+                segment.marker == utils::APP1_MARKER
+                    && segment.data.starts_with(b"http://ns.adobe.com/xap/1.0/\0")
+            }
+        }
+    }
+}
+
+impl MetadataPolicy {
+    fn keeps(&self, segment: &MetadataSegment) -> bool {
+        match self {
+            MetadataPolicy::Only(kinds) => kinds.iter().any(|kind| kind.matches(segment)),
+            MetadataPolicy::Strip => false,
+            MetadataPolicy::Copyright => {
+                matches!(segment.marker, utils::APP13_MARKER | utils::COM_MARKER)
+            }
+            MetadataPolicy::All => matches!(
+                segment.marker,
+                utils::APP1_MARKER | utils::APP2_MARKER | utils::APP13_MARKER | utils::COM_MARKER
+            ),
+        }
+    }
+}
+
+impl Placeholder {
+    fn extension(&self) -> &'static str {
+        match self {
+            Placeholder::Blurhash => "blurhash",
+            Placeholder::Thumbhash => "thumbhash",
+            Placeholder::Lqip => "lqip",
+        }
+    }
+}
+
+impl Encoder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoder::WebP => "webp",
+            Encoder::MozJpeg => "mozjpeg",
+            Encoder::Avif => "avif",
+            Encoder::JpegXl => "jpegxl",
+            Encoder::Png => "png",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoder::WebP => "webp",
+            Encoder::MozJpeg => "jpg",
+            Encoder::Avif => "avif",
+            Encoder::JpegXl => "jxl",
+            Encoder::Png => "png",
+        }
+    }
+
+    // The encoder behind an output's extension, in any case and with .jpeg as well as .jpg
+    pub fn from_extension(ext: &str) -> Option<Encoder> {
+        let ext = ext.to_ascii_lowercase();
+        let ext = if ext == "jpeg" { "jpg" } else { &ext };
+        Encoder::value_variants()
+            .iter()
+            .find(|encoder| encoder.extension() == ext)
+            .cloned()
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Encoder::WebP => "image/webp",
+            Encoder::MozJpeg => "image/jpeg",
+            Encoder::Avif => "image/avif",
+            Encoder::JpegXl => "image/jxl",
+            Encoder::Png => "image/png",
+        }
+    }
+
+    pub fn supports_alpha(&self) -> bool {
+        !matches!(self, Encoder::MozJpeg)
+    }
+
+    // The image crate has no AVIF or JPEG XL decoder, so those outputs can't be read back
+    pub fn is_decodable(&self) -> bool {
+        !matches!(self, Encoder::Avif | Encoder::JpegXl)
+    }
+
+    // AVIF looks as good as the others at a much lower number
+    pub fn default_quality(&self) -> f32 {
+        match self {
+            Encoder::Avif => 55.0,
+            _ => 75.0,
+        }
+    }
+
+    // Accepts the CLI names plus the short names used in file extensions
+    pub fn parse_name(name: &str) -> Option<Encoder> {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "jpeg" | "jpg" => return Some(Encoder::MozJpeg),
+            "jxl" => return Some(Encoder::JpegXl),
+            _ => {}
+        }
+        Encoder::value_variants()
+            .iter()
+            .find(|encoder| encoder.name() == name)
+            .cloned()
+            .or_else(|| Encoder::from_str(&name, true).ok())
+    }
+}
+
+const SEQUENTIAL_TARGET_PIXELS: u64 = 40_000_000;
+
+// Outputs written by the image crate keep whatever the source has
+fn encodes_alpha(compressor: Option<&Compressor>) -> bool {
+    compressor.is_none_or(|compressor| compressor.encoder.supports_alpha())
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or_default()
+}
+
+fn is_jpeg_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"))
+}
+
+fn output_record(
+    path: &Path,
+    (width, height): (usize, usize),
+    compressor: Option<&Compressor>,
+) -> OutputRecord {
+    OutputRecord {
+        path: path.to_string_lossy().into_owned(),
+        width,
+        height,
+        format: path
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        quality: compressor.and_then(Compressor::fixed_quality),
+        bytes: 0,
+        placeholder: None,
+        dominant_color: None,
+        palette: None,
+        psnr: None,
+        dssim: None,
+    }
+}
+
+// Errors from the image crate's own encoders are reported against the file being written
+fn image_write_error(path: &Path, err: image::ImageError) -> OptimizeError {
+    match err {
+        image::ImageError::IoError(err) => OptimizeError::write(path, err),
+        source => OptimizeError::ImageEncode {
+            path: path.to_path_buf(),
+            source,
+        },
+    }
+}
+
+pub fn responsive_dimensions(src: (u32, u32), widths: &[usize]) -> Vec<(usize, usize)> {
+    let (src_w, src_h) = (src.0 as usize, src.1 as usize);
+    widths
+        .iter()
+        .map(|&width| {
+            // Scale with the full ratio rather than an integer factor, which
+            // would be zero for targets wider than the source
+            let height = (src_h * width + src_w / 2) / src_w.max(1);
+            (width, height.max(1))
+        })
+        .collect()
+}
+
+pub fn height_dimensions(src: (u32, u32), heights: &[usize]) -> Vec<(usize, usize)> {
+    let (src_w, src_h) = (src.0 as usize, src.1 as usize);
+    heights
+        .iter()
+        .map(|&height| {
+            let width = (src_w * height + src_h / 2) / src_h.max(1);
+            (width.max(1), height)
+        })
+        .collect()
+}
+
+// The longest edge gets the requested length, whichever way the image is oriented
+pub fn max_dimension_targets(src: (u32, u32), lengths: &[usize]) -> Vec<(usize, usize)> {
+    if src.0 >= src.1 {
+        responsive_dimensions(src, lengths)
+    } else {
+        height_dimensions(src, lengths)
+    }
+}
+
+pub fn scaled_dimensions(src: (u32, u32), percentages: &[f32]) -> Vec<(usize, usize)> {
+    let scale = |length: u32, percentage: f32| {
+        ((length as f32 * percentage / 100.0).round() as usize).max(1)
+    };
+    percentages
+        .iter()
+        .map(|&percentage| (scale(src.0, percentage), scale(src.1, percentage)))
+        .collect()
+}
+
+pub fn limit_height(
+    src: (u32, u32),
+    targets: Vec<(usize, usize)>,
+    max_height: usize,
+) -> Vec<(usize, usize)> {
+    let (src_w, src_h) = (src.0 as usize, src.1 as usize);
+    targets
+        .into_iter()
+        .map(|(width, height)| {
+            if height <= max_height {
+                return (width, height);
+            }
+            // Too tall for the bounding box, so let the height drive the width instead
+            let width = (src_w * max_height + src_h / 2) / src_h.max(1);
+            (width.max(1), max_height)
+        })
+        .collect()
+}
+
+pub fn exact_dimensions(
+    src: (u32, u32),
+    sizes: &[(usize, usize)],
+    fit: Fit,
+) -> Vec<(usize, usize)> {
+    sizes
+        .iter()
+        .map(|&(width, height)| {
+            if fit != Fit::Contain {
+                return (width, height);
+            }
+            // Shrink the box to the source aspect ratio, the other modes fill it exactly
+            let scale = (width as f64 / src.0 as f64).min(height as f64 / src.1 as f64);
+            let fit = |length: u32| ((length as f64 * scale).round() as usize).max(1);
+            (fit(src.0), fit(src.1))
+        })
+        .collect()
+}
+
+pub fn apply_upscale_policy(
+    src: (u32, u32),
+    targets: Vec<(usize, usize)>,
+    policy: &UpscalePolicy,
+) -> Result<Vec<(usize, usize)>> {
+    let (src_w, src_h) = (src.0 as usize, src.1 as usize);
+    let mut kept = vec![];
+    for (width, height) in targets {
+        if width <= src_w && height <= src_h {
+            kept.push((width, height));
+            continue;
+        }
+        match policy {
+            UpscalePolicy::Allow => kept.push((width, height)),
+            UpscalePolicy::Skip => {
+                warn!("Skipping {width}x{height}, it would upscale the {src_w}x{src_h} source")
+            }
+            UpscalePolicy::Error => {
+                return Err(OptimizeError::InvalidTarget(format!(
+                    "Target {width}x{height} would upscale the {src_w}x{src_h} source"
+                )))
+            }
+        }
+    }
+    Ok(kept)
+}
+
+pub fn density_widths(base_width: usize, densities: &[u32], src_width: u32) -> Vec<usize> {
+    densities
+        .iter()
+        .map(|&density| base_width * density as usize)
+        .filter(|&width| {
+            let fits = width <= src_width as usize;
+            if !fits {
+                warn!("Skipping {width}w, it would upscale the {src_width}w source");
+            }
+            fits
+        })
+        .collect()
+}
+
+fn missing_compressor() -> OptimizeError {
+    OptimizeError::InvalidOption(
+        "Must provide a quality value/compressor to compress an image".to_string(),
+    )
+}
+
+fn missing_file_name() -> OptimizeError {
+    OptimizeError::InvalidPath("Error getting file name".to_string())
+}
+
+fn validate_target((width, height): (usize, usize)) -> Result<()> {
+    if width == 0 || height == 0 {
+        return Err(OptimizeError::InvalidTarget(format!(
+            "Target dimensions must be greater than 0, got {width}x{height}"
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
 pub struct Compressor {
     quality: f32,
     encoder: Encoder,
+    max_bytes: Option<usize>,
+    min_ssim: Option<f64>,
+    webp_options: utils::WebPOptions,
+    mozjpeg_options: utils::MozJpegOptions,
+    avif_speed: u8,
+    avif_bit_depth: utils::AvifBitDepth,
+    lossless: bool,
+    png_options: utils::PngOptions,
+    metadata_policy: MetadataPolicy,
 }
 
 impl Compressor {
@@ -25,9 +441,32 @@ impl Compressor {
         Compressor {
             quality,
             encoder: Encoder::MozJpeg,
+            max_bytes: None,
+            min_ssim: None,
+            webp_options: utils::WebPOptions::default(),
+            mozjpeg_options: utils::MozJpegOptions::default(),
+            avif_speed: 4,
+            avif_bit_depth: utils::AvifBitDepth::default(),
+            lossless: false,
+            png_options: utils::PngOptions::default(),
+            metadata_policy: MetadataPolicy::default(),
         }
     }
 
+    fn is_lossy(&self) -> bool {
+        !self.lossless && !matches!(self.encoder, Encoder::Png)
+    }
+
+    // A byte budget or SSIM target picks the quality per output, while encoding
+    fn is_searched(&self) -> bool {
+        self.max_bytes.is_some() || self.min_ssim.is_some()
+    }
+
+    // Searched and lossless encodes have no single quality worth reporting
+    fn fixed_quality(&self) -> Option<f32> {
+        (!self.is_searched() && self.is_lossy()).then_some(self.quality)
+    }
+
     pub fn set_quality(&mut self, quality: f32) {
         self.quality = quality;
     }
@@ -35,196 +474,2266 @@ impl Compressor {
     pub fn set_encoder(&mut self, encoder: Encoder) {
         self.encoder = encoder;
     }
+
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = Some(max_bytes);
+    }
+
+    pub fn set_min_ssim(&mut self, min_ssim: f64) {
+        self.min_ssim = Some(min_ssim);
+    }
+
+    pub fn set_webp_method(&mut self, method: u8) {
+        self.webp_options.method = Some(method);
+    }
+
+    pub fn set_webp_alpha_quality(&mut self, alpha_quality: u8) {
+        self.webp_options.alpha_quality = Some(alpha_quality);
+    }
+
+    pub fn set_lossless(&mut self, lossless: bool) {
+        self.lossless = lossless;
+    }
+
+    pub fn set_png_zopfli(&mut self, zopfli: bool) {
+        self.png_options.zopfli = zopfli;
+    }
+
+    pub fn set_png_max_colors(&mut self, max_colors: u32) {
+        self.png_options.max_colors = Some(max_colors);
+    }
+
+    pub fn set_png_dithering(&mut self, dithering: f32) {
+        self.png_options.dithering = Some(dithering);
+    }
+
+    pub fn set_webp_near_lossless(&mut self, near_lossless: u8) {
+        self.webp_options.near_lossless = Some(near_lossless);
+    }
+
+    pub fn set_webp_sharp_yuv(&mut self, sharp_yuv: bool) {
+        self.webp_options.sharp_yuv = sharp_yuv;
+    }
+
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.mozjpeg_options.progressive = progressive;
+    }
+
+    pub fn set_chroma_subsampling(&mut self, subsampling: ChromaSubsampling) {
+        self.mozjpeg_options.subsampling = Some(subsampling);
+    }
+
+    pub fn set_trellis_scans(&mut self, trellis_scans: bool) {
+        self.mozjpeg_options.trellis_scans = trellis_scans;
+    }
+
+    pub fn set_avif_speed(&mut self, speed: u8) {
+        self.avif_speed = speed;
+    }
+
+    pub fn set_avif_bit_depth(&mut self, bit_depth: utils::AvifBitDepth) {
+        self.avif_bit_depth = bit_depth;
+    }
+
+    pub fn set_metadata_policy(&mut self, metadata_policy: MetadataPolicy) {
+        self.metadata_policy = metadata_policy;
+    }
+
+    pub fn encode(
+        &self,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        metadata: &[MetadataSegment],
+    ) -> Result<Vec<u8>> {
+        let metadata: Vec<MetadataSegment> = metadata
+            .iter()
+            .filter(|segment| self.metadata_policy.keeps(segment))
+            .cloned()
+            .collect();
+        match (self.max_bytes, self.min_ssim) {
+            (Some(max_bytes), _) => {
+                self.encode_within_budget(img, width, height, &metadata, max_bytes)
+            }
+            (None, Some(min_ssim)) => self.encode_for_ssim(img, width, height, &metadata, min_ssim),
+            (None, None) => self.encode_at_quality(img, width, height, &metadata, self.quality),
+        }
+    }
+
+    fn encode_at_quality(
+        &self,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        metadata: &[MetadataSegment],
+        quality: f32,
+    ) -> Result<Vec<u8>> {
+        match self.encoder {
+            Encoder::WebP => utils::compress_webp(
+                img,
+                width as u32,
+                height as u32,
+                quality,
+                self.lossless,
+                &self.webp_options,
+                metadata,
+            ),
+            Encoder::MozJpeg => utils::compress_mozjpeg(
+                img,
+                width,
+                height,
+                quality,
+                &self.mozjpeg_options,
+                metadata,
+            ),
+            Encoder::Avif => utils::compress_avif(
+                img,
+                width,
+                height,
+                quality,
+                self.avif_speed,
+                self.avif_bit_depth,
+            ),
+            Encoder::JpegXl => utils::compress_jpegxl(img, width, height, quality, self.lossless),
+            Encoder::Png => utils::compress_png(img, width, height, &self.png_options),
+        }
+    }
+
+    fn encode_within_budget(
+        &self,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        metadata: &[MetadataSegment],
+        max_bytes: usize,
+    ) -> Result<Vec<u8>> {
+        // Binary search for the highest quality whose output still fits the budget
+        let (mut low, mut high) = (0u32, 100u32);
+        let mut best = None;
+        while low <= high {
+            let quality = (low + high) / 2;
+            let encoded = self.encode_at_quality(img, width, height, metadata, quality as f32)?;
+            if encoded.len() <= max_bytes {
+                debug!(
+                    "Quality {quality} fits {width}x{height} in {} bytes",
+                    encoded.len()
+                );
+                best = Some(encoded);
+                low = quality + 1;
+            } else if quality == 0 {
+                break;
+            } else {
+                high = quality - 1;
+            }
+        }
+        best.ok_or(OptimizeError::OverBudget(max_bytes))
+    }
+
+    fn encode_for_ssim(
+        &self,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        metadata: &[MetadataSegment],
+        min_ssim: f64,
+    ) -> Result<Vec<u8>> {
+        if !self.encoder.is_decodable() {
+            return Err(OptimizeError::InvalidOption(format!(
+                "SSIM targets can't be used with {}, its outputs can't be decoded to compare",
+                self.encoder.name()
+            )));
+        }
+        // Binary search for the lowest quality whose output still meets the SSIM threshold
+        let (mut low, mut high) = (0u32, 100u32);
+        let mut best = None;
+        while low <= high {
+            let quality = (low + high) / 2;
+            let encoded = self.encode_at_quality(img, width, height, metadata, quality as f32)?;
+            let ssim = utils::ssim(img, &encoded, width, height)?;
+            if ssim >= min_ssim {
+                debug!("Quality {quality} reaches SSIM {ssim:.4} at {width}x{height}");
+                best = Some(encoded);
+                if quality == 0 {
+                    break;
+                }
+                high = quality - 1;
+            } else {
+                low = quality + 1;
+            }
+        }
+        best.ok_or(OptimizeError::SsimUnreachable(min_ssim))
+    }
+}
+
+// What one in-memory output was encoded as
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariantSpec {
+    pub width: usize,
+    pub height: usize,
+    pub encoder: Encoder,
+    pub quality: f32,
 }
 
 pub struct Optimizer {
     img: DynamicImage,
+    source_dimensions: (u32, u32),
     base_path: String,
     target_sizes: Vec<(usize, usize)>,
     compressor: Option<Compressor>,
+    overwrite: bool,
+    name_template: Option<String>,
+    sharpen: f32,
+    density_base_width: Option<usize>,
+    source_format: Option<ImageFormat>,
+    keep_original_format: bool,
+    verify: bool,
+    quality_in_name: bool,
+    placeholder: Option<Placeholder>,
+    source_metadata: Vec<MetadataSegment>,
+    timings: Mutex<Timings>,
+    outputs: Mutex<Vec<OutputRecord>>,
+    cancel_token: Option<Arc<AtomicBool>>,
+    deadline: Option<Instant>,
+    qualities: Vec<f32>,
+    encoders: Vec<Encoder>,
+    encoder_qualities: Vec<(Encoder, f32)>,
+    fallback_quality: bool,
+    out_dir: Option<PathBuf>,
+    source_root: Option<PathBuf>,
+    background: [u8; 3],
+    jxl_transcode_source: Option<Vec<u8>>,
+    fit: Fit,
+    gravity: Gravity,
+    focal_point: Option<(f32, f32)>,
+    filter: ResizeFilter,
+    animation: Option<utils::Animation>,
+    sink: Arc<dyn OutputSink>,
+    watermark: Option<Arc<utils::Watermark>>,
+    color_adjustments: utils::ColorAdjustments,
+    svg_source: Option<Vec<u8>>,
+    report_metrics: bool,
 }
 
 impl Optimizer {
     pub fn new(img: DynamicImage, img_path: &str) -> Optimizer {
+        let img = utils::reduce_to_8_bit(img);
         Optimizer {
+            source_dimensions: img.dimensions(),
             img,
             base_path: img_path.to_string(),
             target_sizes: vec![],
             compressor: None,
+            overwrite: false,
+            name_template: None,
+            sharpen: 0.0,
+            density_base_width: None,
+            source_format: None,
+            keep_original_format: false,
+            verify: false,
+            quality_in_name: true,
+            placeholder: None,
+            source_metadata: vec![],
+            timings: Mutex::new(Timings::default()),
+            outputs: Mutex::new(vec![]),
+            cancel_token: None,
+            deadline: None,
+            qualities: vec![],
+            encoders: vec![],
+            encoder_qualities: vec![],
+            fallback_quality: false,
+            out_dir: None,
+            source_root: None,
+            background: [255, 255, 255],
+            jxl_transcode_source: None,
+            fit: Fit::default(),
+            gravity: Gravity::default(),
+            focal_point: None,
+            filter: ResizeFilter::default(),
+            animation: None,
+            sink: Arc::new(FileSink),
+            watermark: None,
+            color_adjustments: utils::ColorAdjustments::default(),
+            svg_source: None,
+            report_metrics: false,
         }
     }
 
-    pub fn set_encoder(&mut self, encoder: Encoder) {
-        match &mut self.compressor {
-            None => {
-                let mut compressor = Compressor::new(75.0);
-                compressor.set_encoder(encoder);
-                self.compressor = Some(compressor);
-            }
-            Some(compressor) => compressor.set_encoder(encoder),
+    pub fn builder(img: DynamicImage, img_path: &str) -> OptimizerBuilder {
+        OptimizerBuilder::new(img, img_path)
+    }
+
+    pub fn set_compressor(&mut self, compressor: Compressor) {
+        self.compressor = Some(compressor);
+        self.fallback_quality = false;
+    }
+
+    // The convenience setters below fall back to a mozjpeg compressor at the encoder's default quality
+    fn compressor_mut(&mut self) -> &mut Compressor {
+        if self.compressor.is_none() {
+            self.fallback_quality = true;
         }
+        self.compressor
+            .get_or_insert_with(|| Compressor::new(Encoder::MozJpeg.default_quality()))
+    }
+
+    pub fn set_encoder(&mut self, encoder: Encoder) {
+        self.compressor_mut().set_encoder(encoder);
     }
 
     pub fn set_quality(&mut self, quality: f32) {
-        match &mut self.compressor {
-            None => self.compressor = Some(Compressor::new(quality)),
-            Some(compressor) => compressor.set_quality(quality),
-        }
+        self.compressor_mut().set_quality(quality);
+        self.fallback_quality = false;
     }
 
-    pub fn set_targets(&mut self, target_sizes: Vec<(usize, usize)>) {
-        self.target_sizes = target_sizes;
+    pub fn set_qualities(&mut self, qualities: Vec<f32>) {
+        self.compressor_mut();
+        self.qualities = qualities;
     }
 
-    pub fn add_target(&mut self, target: (usize, usize)) {
-        self.target_sizes.push(target);
+    pub fn set_encoder_quality(&mut self, encoder: Encoder, quality: f32) {
+        self.compressor_mut();
+        self.encoder_qualities
+            .retain(|(existing, _)| *existing != encoder);
+        self.encoder_qualities.push((encoder, quality));
     }
 
-    fn get_img_dimensions(&self) -> (usize, usize) {
-        let (w, h) = self.img.dimensions();
-        (w.try_into().unwrap(), h.try_into().unwrap())
+    fn quality_for(&self, encoder: &Encoder, compressor: &Compressor) -> f32 {
+        if let Some((_, quality)) = self.encoder_qualities.iter().find(|(e, _)| e == encoder) {
+            *quality
+        } else if self.fallback_quality {
+            encoder.default_quality()
+        } else {
+            compressor.quality
+        }
     }
 
-    pub fn compress(&self) -> anyhow::Result<Vec<u8>> {
-        match &self.compressor {
-            None => Err(anyhow!(
-                "Must provide a quality value/compressor to compress an image"
-            )),
-            Some(compressor) => {
-                let (width, height) = self.get_img_dimensions();
-                let img_as_vec = &self.img.as_bytes().to_vec();
-                match &compressor.encoder {
-                    Encoder::WebP => utils::compress_webp(
-                        img_as_vec,
-                        width as u32,
-                        height as u32,
-                        compressor.quality,
-                    ),
-                    Encoder::MozJpeg => {
-                        utils::compress_mozjpeg(img_as_vec, width, height, compressor.quality)
+    pub fn set_encoders(&mut self, encoders: Vec<Encoder>) {
+        self.compressor_mut();
+        self.encoders = encoders;
+    }
+
+    // One variant per encoder and quality, all sharing the rest of the settings. Lossless and
+    // searched encodes don't use the quality, so those get one variant each
+    fn compressor_variants(&self) -> Vec<Option<Compressor>> {
+        let Some(compressor) = &self.compressor else {
+            return vec![None];
+        };
+        let encoders = match self.encoders.as_slice() {
+            [] => vec![compressor.encoder.clone()],
+            encoders => encoders.to_vec(),
+        };
+        encoders
+            .iter()
+            .flat_map(|encoder| {
+                let mut base = compressor.clone();
+                base.set_encoder(encoder.clone());
+                base.set_quality(self.quality_for(encoder, compressor));
+                let qualities = match self.qualities.as_slice() {
+                    qualities if !qualities.is_empty() && base.fixed_quality().is_some() => {
+                        qualities.to_vec()
                     }
-                }
-            }
-        }
+                    _ => vec![base.quality],
+                };
+                qualities.into_iter().map(move |quality| {
+                    let mut variant = base.clone();
+                    variant.set_quality(quality);
+                    Some(variant)
+                })
+            })
+            .collect()
     }
 
-    fn generate_save_path(&self, w: usize) -> anyhow::Result<PathBuf> {
-        let path = Path::new(&self.base_path);
-        let mut result = path
-            .parent()
-            .ok_or(anyhow!("Provided image must have a parent directory"))?
-            .to_owned();
-        result.push("optimized");
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.compressor_mut().set_max_bytes(max_bytes);
+    }
 
-        let stem = path.file_stem().ok_or(anyhow!("Error getting file name"))?;
+    pub fn set_min_ssim(&mut self, min_ssim: f64) {
+        self.compressor_mut().set_min_ssim(min_ssim);
+    }
 
-        let mut file_name = stem.to_os_string();
+    pub fn set_report_metrics(&mut self, report_metrics: bool) {
+        self.report_metrics = report_metrics;
+    }
 
-        file_name.push(format!("_{w}"));
+    pub fn set_webp_method(&mut self, method: u8) {
+        self.compressor_mut().set_webp_method(method);
+    }
 
-        if let Some(compressor) = &self.compressor {
-            file_name.push(format!("_{}.", compressor.quality));
-            match compressor.encoder {
-                Encoder::MozJpeg => {
-                    let ext = path
-                        .extension()
-                        .ok_or(anyhow!("Expected an extension present on image path"))?;
-                    file_name.push(ext);
-                }
-                Encoder::WebP => file_name.push("webp"),
-            }
-        } else {
-            file_name.push(".");
-            let ext = path
-                .extension()
-                .ok_or(anyhow!("Expected an extension present on image path"))?;
-            file_name.push(ext);
-        }
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.compressor_mut().set_progressive(progressive);
+    }
 
-        result.push(file_name);
-        Ok(result)
+    pub fn set_chroma_subsampling(&mut self, subsampling: ChromaSubsampling) {
+        self.compressor_mut().set_chroma_subsampling(subsampling);
     }
 
-    fn compress_self(&self) -> anyhow::Result<()> {
-        let compressor = match &self.compressor {
-            None => Err(anyhow!(
-                "Must provide a quality value/compressor to compress an image"
-            )),
-            Some(compressor) => Ok(compressor),
-        }?;
-        let img = self.img.as_bytes().to_vec();
-        let (src_w, src_h) = self.get_img_dimensions();
+    pub fn set_trellis_scans(&mut self, trellis_scans: bool) {
+        self.compressor_mut().set_trellis_scans(trellis_scans);
+    }
 
-        let write_path = self.generate_save_path(src_w)?;
+    pub fn set_webp_alpha_quality(&mut self, alpha_quality: u8) {
+        self.compressor_mut().set_webp_alpha_quality(alpha_quality);
+    }
 
-        let optimized = match compressor.encoder {
-            Encoder::WebP => {
-                utils::compress_webp(&img, src_w as u32, src_h as u32, compressor.quality)
-            }
-            Encoder::MozJpeg => utils::compress_mozjpeg(&img, src_w, src_h, compressor.quality),
-        }?;
+    pub fn set_lossless(&mut self, lossless: bool) {
+        self.compressor_mut().set_lossless(lossless);
+    }
 
-        ensure_parent_directory_exists(&write_path)?;
-        let mut file = File::create(write_path)?;
-        file.write_all(&optimized)?;
-        Ok(())
+    pub fn set_png_zopfli(&mut self, zopfli: bool) {
+        self.compressor_mut().set_png_zopfli(zopfli);
     }
 
-    fn resize_and_maybe_compress(&self) -> anyhow::Result<()> {
-        if self.target_sizes.is_empty() {
-            return Err(anyhow!("Must provide at least one resize target size"));
-        }
-        let img = self.img.as_bytes().to_vec();
-        // First, resize the image
-        let (src_w, src_h) = self.get_img_dimensions();
-        for (target_w, target_h) in &self.target_sizes {
-            let resize_config = utils::ResizeConfig {
-                src_height: src_h,
-                src_width: src_w,
-                dest_height: *target_h,
-                dest_width: *target_w,
-            };
+    pub fn set_png_max_colors(&mut self, max_colors: u32) {
+        self.compressor_mut().set_png_max_colors(max_colors);
+    }
 
-            let write_path = self.generate_save_path(*target_w)?;
-
-            let resized_img = utils::resize(&img, resize_config)?;
-
-            if let Some(compressor) = &self.compressor {
-                let optimized = match compressor.encoder {
-                    Encoder::WebP => utils::compress_webp(
-                        &resized_img,
-                        *target_w as u32,
-                        *target_h as u32,
-                        compressor.quality,
-                    ),
-                    Encoder::MozJpeg => utils::compress_mozjpeg(
-                        &resized_img,
-                        *target_w,
-                        *target_h,
-                        compressor.quality,
-                    ),
-                }?;
-                ensure_parent_directory_exists(&write_path)?;
-                let mut file = File::create(write_path)?;
-                file.write_all(&optimized)?;
-            } else {
-                ensure_parent_directory_exists(&write_path)?;
-                image::save_buffer(
-                    write_path,
-                    &resized_img,
-                    *target_w as u32,
-                    *target_h as u32,
-                    image::ColorType::Rgb8,
-                )?;
-            }
-        }
-        Ok(())
+    pub fn set_png_dithering(&mut self, dithering: f32) {
+        self.compressor_mut().set_png_dithering(dithering);
     }
 
-    pub fn optimize(&self) -> anyhow::Result<()> {
-        match self.target_sizes.len() {
-            0 => self.compress_self(),
-            _ => self.resize_and_maybe_compress(),
-        }
+    pub fn set_webp_near_lossless(&mut self, near_lossless: u8) {
+        self.compressor_mut().set_webp_near_lossless(near_lossless);
+    }
+
+    pub fn set_webp_sharp_yuv(&mut self, sharp_yuv: bool) {
+        self.compressor_mut().set_webp_sharp_yuv(sharp_yuv);
+    }
+
+    pub fn set_avif_speed(&mut self, speed: u8) {
+        self.compressor_mut().set_avif_speed(speed);
+    }
+
+    pub fn set_avif_bit_depth(&mut self, bit_depth: utils::AvifBitDepth) {
+        self.compressor_mut().set_avif_bit_depth(bit_depth);
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    pub fn set_placeholder(&mut self, placeholder: Placeholder) {
+        self.placeholder = Some(placeholder);
+    }
+
+    pub fn set_overwrite(&mut self, overwrite: bool) {
+        self.overwrite = overwrite;
+    }
+
+    pub fn set_out_dir(&mut self, out_dir: PathBuf) {
+        self.out_dir = Some(out_dir);
+    }
+
+    pub fn set_grayscale(&mut self, grayscale: bool) {
+        self.color_adjustments.saturation = if grayscale { 0.0 } else { 1.0 };
+    }
+
+    pub fn set_brightness(&mut self, brightness: f32) {
+        self.color_adjustments.brightness = brightness;
+    }
+
+    pub fn set_contrast(&mut self, contrast: f32) {
+        self.color_adjustments.contrast = contrast;
+    }
+
+    pub fn set_saturation(&mut self, saturation: f32) {
+        self.color_adjustments.saturation = saturation;
+    }
+
+    pub fn set_watermark(&mut self, watermark: Arc<utils::Watermark>) {
+        self.watermark = Some(watermark);
+    }
+
+    pub fn set_sink(&mut self, sink: Arc<dyn OutputSink>) {
+        self.sink = sink;
+    }
+
+    pub fn set_source_root(&mut self, source_root: PathBuf) {
+        self.source_root = Some(source_root);
+    }
+
+    pub fn set_background(&mut self, background: [u8; 3]) {
+        self.background = background;
+    }
+
+    pub fn set_fit(&mut self, fit: Fit) {
+        self.fit = fit;
+    }
+
+    pub fn set_gravity(&mut self, gravity: Gravity) {
+        self.gravity = gravity;
+    }
+
+    pub fn set_filter(&mut self, filter: ResizeFilter) {
+        self.filter = filter;
+    }
+
+    pub fn set_animation(&mut self, animation: utils::Animation) {
+        self.animation = Some(animation);
+    }
+
+    pub fn set_focal_point(&mut self, focal_point: (f32, f32)) {
+        self.focal_point = Some(focal_point);
+    }
+
+    // Every output is rendered from the SVG at its own size, img only stands in for layout
+    pub fn set_svg_source(&mut self, svg: Vec<u8>) {
+        self.svg_source = Some(svg);
+    }
+
+    pub fn set_jxl_transcode_source(&mut self, jpeg: Vec<u8>) {
+        self.jxl_transcode_source = Some(jpeg);
+    }
+
+    // Resizes keep the alpha when any variant can encode it, the others get it flattened
+    fn keeps_alpha(&self) -> bool {
+        self.img.color().has_alpha()
+            && self
+                .compressor_variants()
+                .iter()
+                .any(|compressor| encodes_alpha(compressor.as_ref()))
+    }
+
+    // The flattened copy is made once per image, however many variants need it
+    fn variant_img<'a>(
+        &self,
+        img: &'a DynamicImage,
+        flattened: &'a OnceCell<DynamicImage>,
+        compressor: Option<&Compressor>,
+    ) -> &'a DynamicImage {
+        if img.color().has_alpha() && !encodes_alpha(compressor) {
+            flattened.get_or_init(|| utils::flatten(img, self.background))
+        } else {
+            img
+        }
+    }
+
+    // Encoders without alpha would otherwise expose whatever color sits under transparent pixels
+    fn opaque_img(&self) -> Cow<'_, DynamicImage> {
+        if self.img.color().has_alpha() {
+            Cow::Owned(utils::flatten(&self.img, self.background))
+        } else {
+            Cow::Borrowed(&self.img)
+        }
+    }
+
+    // Transparent sources keep their alpha through the resize and are flattened at the output's
+    // size, which matches flattening first without another copy of the whole source
+    fn resize_opaque(
+        &self,
+        source: &DynamicImage,
+        width: usize,
+        height: usize,
+    ) -> Result<DynamicImage> {
+        if source.color().has_alpha() {
+            let resized = utils::resize_image_rgba(source, width, height, self.filter)?;
+            Ok(utils::flatten(&resized, self.background))
+        } else {
+            utils::resize_image(source, width, height, self.filter)
+        }
+    }
+
+    pub fn palette(&self, count: usize) -> Vec<[u8; 3]> {
+        utils::palette(&self.img, count)
+    }
+
+    // Runs after resizing and sharpening, the watermark goes on last so it keeps its own colors
+    fn post_process(&self, img: &mut DynamicImage) {
+        utils::adjust_colors(img, &self.color_adjustments);
+        if let Some(watermark) = &self.watermark {
+            let scale = img.width() as f64 / self.source_dimensions.0 as f64;
+            utils::apply_watermark(img, watermark, scale);
+        }
+    }
+
+    // The whole source as 8-bit RGB, or RGBA when a variant keeps the alpha
+    pub(crate) fn source_pixels(&self) -> DynamicImage {
+        let keeps_alpha = self.keeps_alpha();
+        let mut img = if keeps_alpha {
+            Cow::Borrowed(&self.img)
+        } else {
+            self.opaque_img()
+        };
+        if self.watermark.is_some() || self.color_adjustments != Default::default() {
+            self.post_process(img.to_mut());
+        }
+        if keeps_alpha {
+            DynamicImage::ImageRgba8(img.to_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(img.to_rgb8())
+        }
+    }
+
+    pub fn set_cancel_token(&mut self, cancel_token: Arc<AtomicBool>) {
+        self.cancel_token = Some(cancel_token);
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if let Some(cancel_token) = &self.cancel_token {
+            if cancel_token.load(Ordering::Relaxed) {
+                return Err(OptimizeError::Cancelled(self.base_path.clone()));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(OptimizeError::DeadlineExceeded(self.base_path.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_name_template(&mut self, template: &str) -> Result<()> {
+        utils::validate_name_template(template)?;
+        self.name_template = Some(template.to_string());
+        Ok(())
+    }
+
+    pub fn set_quality_in_name(&mut self, quality_in_name: bool) {
+        self.quality_in_name = quality_in_name;
+    }
+
+    pub fn set_sharpen(&mut self, amount: f32) -> Result<()> {
+        if !(0.0..=1.0).contains(&amount) {
+            return Err(OptimizeError::InvalidOption(
+                "Sharpen amount must be between 0.0 and 1.0".to_string(),
+            ));
+        }
+        self.sharpen = amount;
+        Ok(())
+    }
+
+    pub fn set_density_base_width(&mut self, base_width: usize) {
+        self.density_base_width = Some(base_width);
+    }
+
+    pub fn set_source_format(&mut self, format: ImageFormat) {
+        self.source_format = Some(format);
+    }
+
+    // For a source decoded at a reduced scale, records and watermarks follow its own size
+    pub fn set_source_dimensions(&mut self, dimensions: (u32, u32)) {
+        self.source_dimensions = dimensions;
+    }
+
+    pub fn set_source_metadata(&mut self, metadata: Vec<MetadataSegment>) {
+        self.source_metadata = metadata;
+    }
+
+    pub fn convert_to_srgb(&mut self) -> Result<()> {
+        let Some(icc_profile) = utils::icc_profile(&self.source_metadata) else {
+            debug!("{} has no ICC profile, assuming sRGB", self.base_path);
+            return Ok(());
+        };
+        let img = std::mem::take(&mut self.img);
+        self.img = utils::convert_to_srgb(img, &icc_profile)?;
+        // The pixels are sRGB now, so the original profile must not be embedded again
+        self.source_metadata
+            .retain(|segment| segment.marker != utils::APP2_MARKER);
+        Ok(())
+    }
+
+    pub fn set_metadata_policy(&mut self, metadata_policy: MetadataPolicy) {
+        self.compressor_mut().set_metadata_policy(metadata_policy);
+    }
+
+    pub fn set_keep_original_format(&mut self, keep_original_format: bool) {
+        self.keep_original_format = keep_original_format;
+    }
+
+    pub fn record_decode_time(&mut self, elapsed: Duration) {
+        self.timings.get_mut().unwrap().decode = elapsed;
+    }
+
+    pub fn timings(&self) -> Timings {
+        self.timings.lock().unwrap().clone()
+    }
+
+    pub fn outputs(&self) -> Vec<OutputRecord> {
+        // Targets finish in any order when encoded in parallel
+        let mut outputs = self.outputs.lock().unwrap().clone();
+        outputs.sort_by(|a, b| (a.width, &a.path).cmp(&(b.width, &b.path)));
+        outputs
+    }
+
+    fn record_output(
+        &self,
+        path: &Path,
+        dimensions: (usize, usize),
+        compressor: Option<&Compressor>,
+        bytes: u64,
+    ) {
+        let mut record = output_record(path, dimensions, compressor);
+        record.bytes = bytes;
+        self.outputs.lock().unwrap().push(record);
+    }
+
+    // Metrics compare against the resized pixels the output was encoded from. Formats the image
+    // crate can't decode, such as AVIF and JPEG XL, are left unmeasured.
+    fn record_encoded(
+        &self,
+        path: &Path,
+        dimensions: (usize, usize),
+        compressor: Option<&Compressor>,
+        reference: &[u8],
+        encoded: &[u8],
+    ) {
+        let mut record = output_record(path, dimensions, compressor);
+        record.bytes = encoded.len() as u64;
+        if self.report_metrics {
+            let (width, height) = dimensions;
+            match utils::quality_metrics(reference, encoded, width, height) {
+                Ok(metrics) => {
+                    debug!(
+                        "{} has a PSNR of {:.2} dB and a DSSIM of {:.6}",
+                        path.display(),
+                        metrics.psnr,
+                        metrics.dssim
+                    );
+                    record.psnr = Some(metrics.psnr);
+                    record.dssim = Some(metrics.dssim);
+                }
+                Err(err) => warn!("Unable to measure {}: {err}", path.display()),
+            }
+        }
+        self.outputs.lock().unwrap().push(record);
+    }
+
+    fn write_output(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        self.sink.write(path, bytes)?;
+        info!("Wrote {} ({} bytes)", path.display(), bytes.len());
+        Ok(())
+    }
+
+    // Everything optimize() would write, without encoding or touching the disk
+    pub fn planned_outputs(&self) -> Result<Vec<OutputRecord>> {
+        let targets = if self.target_sizes.is_empty() {
+            if self.compressor.is_none() {
+                return Err(missing_compressor());
+            }
+            vec![self.get_img_dimensions()]
+        } else {
+            self.target_sizes.clone()
+        };
+        let mut planned = vec![];
+        for (width, height) in targets {
+            for compressor in self.compressor_variants() {
+                let path = self.generate_save_path(compressor.as_ref(), width, height)?;
+                planned.push(output_record(&path, (width, height), compressor.as_ref()));
+            }
+        }
+        if self.jxl_transcode_source.is_some() {
+            let stem = Path::new(&self.base_path)
+                .file_stem()
+                .ok_or_else(missing_file_name)?;
+            let mut path = self.output_dir()?;
+            path.push(stem);
+            path.set_extension("jxl");
+            planned.push(output_record(&path, self.get_img_dimensions(), None));
+        }
+        Ok(planned)
+    }
+
+    pub fn set_targets(&mut self, mut target_sizes: Vec<(usize, usize)>) -> Result<()> {
+        for target in &target_sizes {
+            validate_target(*target)?;
+        }
+        let requested = target_sizes.len();
+        target_sizes.sort_unstable();
+        target_sizes.dedup();
+        if target_sizes.len() < requested {
+            debug!(
+                "Dropped {} duplicate target(s) for {}",
+                requested - target_sizes.len(),
+                self.base_path
+            );
+        }
+        self.target_sizes = target_sizes;
+        Ok(())
+    }
+
+    pub fn add_target(&mut self, target: (usize, usize)) -> Result<()> {
+        validate_target(target)?;
+        if let Err(index) = self.target_sizes.binary_search(&target) {
+            self.target_sizes.insert(index, target);
+        } else {
+            debug!("Dropped duplicate target {}x{}", target.0, target.1);
+        }
+        Ok(())
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.source_dimensions
+    }
+
+    fn get_img_dimensions(&self) -> (usize, usize) {
+        let (w, h) = self.source_dimensions;
+        (w.try_into().unwrap(), h.try_into().unwrap())
+    }
+
+    fn encode_with(
+        &self,
+        compressor: &Compressor,
+        img: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<u8>> {
+        if self.verify && !compressor.encoder.is_decodable() {
+            return Err(OptimizeError::InvalidOption(format!(
+                "Verification can't be used with {}, its outputs can't be decoded",
+                compressor.encoder.name()
+            )));
+        }
+        let start = Instant::now();
+        let encoded = match (&self.animation, &compressor.encoder) {
+            (Some(animation), Encoder::WebP) => {
+                self.encode_animation(animation, compressor, width, height)?
+            }
+            _ => compressor.encode(img, width, height, &self.source_metadata)?,
+        };
+        let elapsed = start.elapsed();
+        self.timings.lock().unwrap().encodes.push((width, elapsed));
+        if self.verify {
+            utils::verify_encoded(&encoded, width, height)?;
+        }
+        Ok(encoded)
+    }
+
+    // Size budgets and SSIM targets are not searched for animations, the quality is used as is
+    fn encode_animation(
+        &self,
+        animation: &utils::Animation,
+        compressor: &Compressor,
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<u8>> {
+        // Crop every frame the same way so the subject doesn't jump around
+        let crop = (self.fit == Fit::Cover).then(|| self.crop_window(&self.img, (width, height)));
+        let mut frames = vec![];
+        for (frame, duration) in &animation.frames {
+            self.check_cancelled()?;
+            let mut frame = DynamicImage::ImageRgba8(frame.clone());
+            if let Some((x, y, crop_w, crop_h)) = crop {
+                frame = frame.crop_imm(x, y, crop_w, crop_h);
+            }
+            let mut resized = utils::resize_image_rgba(&frame, width, height, self.filter)?;
+            if self.sharpen > 0.0 {
+                resized = utils::sharpen(&resized, self.sharpen);
+            }
+            self.post_process(&mut resized);
+            frames.push((resized.into_bytes(), *duration));
+        }
+        utils::compress_animated_webp(
+            &frames,
+            width as u32,
+            height as u32,
+            compressor.quality,
+            compressor.lossless,
+            &compressor.webp_options,
+            animation.loop_count,
+        )
+    }
+
+    fn single_compressor(&self) -> Result<Compressor> {
+        match self.compressor_variants().as_slice() {
+            [Some(compressor)] => Ok(compressor.clone()),
+            [None] => Err(missing_compressor()),
+            _ => Err(OptimizeError::InvalidOption(
+                "Cannot produce a single output from multiple qualities or encoders".to_string(),
+            )),
+        }
+    }
+
+    pub fn compress(&self) -> Result<Vec<u8>> {
+        let compressor = self.single_compressor()?;
+        let source = self.source_pixels();
+        let (width, height) = source.dimensions();
+        self.encode_with(
+            &compressor,
+            source.as_bytes(),
+            width as usize,
+            height as usize,
+        )
+    }
+
+    pub fn optimize_to_bytes(&self) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        let optimized = match self.target_sizes.as_slice() {
+            [] => self.compress(),
+            [(target_w, target_h)] => {
+                let compressor = self.single_compressor()?;
+                self.check_cancelled()?;
+                let resized = self.resize_to(*target_w, *target_h)?;
+                self.check_cancelled()?;
+                self.encode_with(&compressor, resized.as_bytes(), *target_w, *target_h)
+            }
+            _ => Err(OptimizeError::InvalidOption(
+                "Cannot produce a single output from multiple target widths".to_string(),
+            )),
+        }?;
+        self.timings.lock().unwrap().processing = start.elapsed();
+        Ok(optimized)
+    }
+
+    // Every output optimize() would encode, kept in memory instead of going through the sink.
+    // Placeholders and JPEG XL transcodes are left out.
+    pub fn optimize_to_variants(&self) -> Result<Vec<(VariantSpec, Vec<u8>)>> {
+        if self.compressor.is_none() {
+            return Err(missing_compressor());
+        }
+        let start = Instant::now();
+        let variants = if self.target_sizes.is_empty() {
+            self.encode_variants(&self.source_pixels())?
+        } else {
+            let (width, height) = self.img.dimensions();
+            let encoded = if width as u64 * height as u64 > SEQUENTIAL_TARGET_PIXELS {
+                self.target_sizes
+                    .iter()
+                    .map(|&(target_w, target_h)| self.encode_target_variants(target_w, target_h))
+                    .collect::<Result<Vec<_>>>()?
+            } else {
+                self.target_sizes
+                    .par_iter()
+                    .map(|&(target_w, target_h)| self.encode_target_variants(target_w, target_h))
+                    .collect::<Result<Vec<_>>>()?
+            };
+            encoded.into_iter().flatten().collect()
+        };
+        self.timings.lock().unwrap().processing = start.elapsed();
+        Ok(variants)
+    }
+
+    fn encode_target_variants(
+        &self,
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<(VariantSpec, Vec<u8>)>> {
+        self.check_cancelled()?;
+        let resized = self.resize_to(width, height)?;
+        self.encode_variants(&resized)
+    }
+
+    fn encode_variants(&self, img: &DynamicImage) -> Result<Vec<(VariantSpec, Vec<u8>)>> {
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let flattened = OnceCell::new();
+        let mut variants = vec![];
+        for compressor in self.compressor_variants().iter().flatten() {
+            self.check_cancelled()?;
+            let pixels = self
+                .variant_img(img, &flattened, Some(compressor))
+                .as_bytes();
+            let encoded = self.encode_with(compressor, pixels, width, height)?;
+            let spec = VariantSpec {
+                width,
+                height,
+                encoder: compressor.encoder.clone(),
+                quality: compressor.quality,
+            };
+            variants.push((spec, encoded));
+        }
+        Ok(variants)
+    }
+
+    fn default_name_template(&self, compressor: Option<&Compressor>) -> String {
+        let mut template = String::from("{stem}");
+        match self.density_base_width {
+            Some(_) => template.push_str("{density}"),
+            None => template.push_str("_{width}"),
+        }
+        // Lossless output is the same at every quality and searched output isn't at the base
+        // one, so the token would only mislead
+        let fixed = compressor.is_some_and(|compressor| compressor.fixed_quality().is_some());
+        if fixed && self.quality_in_name {
+            template.push_str("_{quality}");
+        }
+        template.push_str(".{ext}");
+        template
+    }
+
+    fn quality_in_file_name(&self, compressor: Option<&Compressor>) -> bool {
+        match &self.name_template {
+            Some(template) => template.contains("{quality}"),
+            None => self.default_name_template(compressor).contains("{quality}"),
+        }
+    }
+
+    fn output_dir(&self) -> Result<PathBuf> {
+        let parent = Path::new(&self.base_path).parent();
+        if let Some(out_dir) = &self.out_dir {
+            let relative = self
+                .source_root
+                .as_ref()
+                .and_then(|root| parent?.strip_prefix(root).ok())
+                .unwrap_or(Path::new(""));
+            return Ok(out_dir.join(relative));
+        }
+        let parent = parent.ok_or(OptimizeError::InvalidPath(
+            "Provided image must have a parent directory".to_string(),
+        ))?;
+        // A bare file name has an empty parent, which means the current directory
+        let mut result = if parent.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            parent.to_owned()
+        };
+        result.push("optimized");
+        Ok(result)
+    }
+
+    fn generate_save_path(
+        &self,
+        compressor: Option<&Compressor>,
+        w: usize,
+        h: usize,
+    ) -> Result<PathBuf> {
+        let path = Path::new(&self.base_path);
+        let mut result = self.output_dir()?;
+
+        let stem = path.file_stem().ok_or_else(missing_file_name)?;
+
+        let ext = match compressor.map(|c| &c.encoder) {
+            // A JPEG source keeps its own spelling, while a PNG one must not keep its .png
+            Some(Encoder::MozJpeg) if is_jpeg_path(path) => {
+                path.extension().unwrap_or_default().to_string_lossy()
+            }
+            Some(encoder) => encoder.extension().into(),
+            None => path
+                .extension()
+                .ok_or(OptimizeError::InvalidPath(
+                    "Expected an extension present on image path".to_string(),
+                ))?
+                .to_string_lossy(),
+        };
+
+        let density = match self.density_base_width {
+            Some(base) if w > base && w.is_multiple_of(base) => format!("@{}x", w / base),
+            _ => String::new(),
+        };
+
+        let template = match &self.name_template {
+            Some(template) => template.clone(),
+            None => self.default_name_template(compressor),
+        };
+
+        let file_name = utils::render_name_template(
+            &template,
+            &utils::NameFields {
+                stem: &stem.to_string_lossy(),
+                width: w,
+                height: h,
+                quality: compressor.and_then(Compressor::fixed_quality),
+                encoder: compressor.map(|c| c.encoder.name()),
+                density: &density,
+                ext: &ext,
+            },
+        );
+
+        result.push(file_name);
+        Ok(result)
+    }
+
+    // A name without the quality can't tell a changed --quality apart, so those are rewritten
+    fn should_skip(&self, compressor: Option<&Compressor>, write_path: &Path) -> bool {
+        let quality_unnamed =
+            compressor.is_some_and(Compressor::is_lossy) && !self.quality_in_file_name(compressor);
+        let skip = !self.overwrite
+            && !quality_unnamed
+            && self
+                .sink
+                .is_up_to_date(Path::new(&self.base_path), write_path);
+        if skip {
+            info!("Skipping {}, already up to date", write_path.display());
+        }
+        skip
+    }
+
+    fn compress_self(&self) -> Result<()> {
+        if self.compressor.is_none() {
+            return Err(missing_compressor());
+        }
+        let source = self.source_pixels();
+        let (src_w, src_h) = (source.width() as usize, source.height() as usize);
+        let flattened = OnceCell::new();
+
+        for compressor in self.compressor_variants().iter().flatten() {
+            self.check_cancelled()?;
+            let write_path = self.generate_save_path(Some(compressor), src_w, src_h)?;
+            if self.should_skip(Some(compressor), &write_path) {
+                let bytes = file_size(&write_path);
+                self.record_output(&write_path, (src_w, src_h), Some(compressor), bytes);
+                continue;
+            }
+
+            let pixels = self
+                .variant_img(&source, &flattened, Some(compressor))
+                .as_bytes();
+            let optimized = self.encode_with(compressor, pixels, src_w, src_h)?;
+            self.write_output(&write_path, &optimized)?;
+            self.record_encoded(
+                &write_path,
+                (src_w, src_h),
+                Some(compressor),
+                pixels,
+                &optimized,
+            );
+        }
+        Ok(())
+    }
+
+    // An explicit focal point wins over gravity
+    fn crop_window(&self, source: &DynamicImage, target: (usize, usize)) -> (u32, u32, u32, u32) {
+        let src = source.dimensions();
+        let crop = utils::cover_size(src, target);
+        let (x, y) = match (self.focal_point, self.gravity.anchor()) {
+            (Some(focal_point), _) => utils::focal_offset(src, crop, focal_point),
+            (None, Some(anchor)) => utils::anchored_offset(src, crop, anchor),
+            (None, None) => utils::salient_offset(source, crop),
+        };
+        (x, y, crop.0, crop.1)
+    }
+
+    // Crops come out of a render at the crop's scale, so vector outputs are never resampled
+    fn render_svg_to(&self, svg: &[u8], width: usize, height: usize) -> Result<DynamicImage> {
+        let (src_w, src_h) = self.img.dimensions();
+        let (x, y, crop_w, crop_h) = if self.fit == Fit::Cover {
+            self.crop_window(&self.img, (width, height))
+        } else {
+            (0, 0, src_w, src_h)
+        };
+        let scale_x = width as f64 / crop_w as f64;
+        let scale_y = height as f64 / crop_h as f64;
+        let full_w = ((src_w as f64 * scale_x).round() as u32).max(width as u32);
+        let full_h = ((src_h as f64 * scale_y).round() as u32).max(height as u32);
+        let rendered = utils::render_svg(svg, full_w, full_h)?;
+        let x = ((x as f64 * scale_x).round() as u32).min(full_w - width as u32);
+        let y = ((y as f64 * scale_y).round() as u32).min(full_h - height as u32);
+        let cropped = imageops::crop_imm(&rendered, x, y, width as u32, height as u32).to_image();
+        let cropped = DynamicImage::ImageRgba8(cropped);
+        if self.keeps_alpha() {
+            Ok(cropped)
+        } else {
+            Ok(utils::flatten(&cropped, self.background))
+        }
+    }
+
+    fn resize_to(&self, width: usize, height: usize) -> Result<DynamicImage> {
+        let start = Instant::now();
+        if let Some(svg) = &self.svg_source {
+            let mut rendered = self.render_svg_to(svg, width, height)?;
+            self.post_process(&mut rendered);
+            let elapsed = start.elapsed();
+            self.timings.lock().unwrap().resizes.push((width, elapsed));
+            return Ok(rendered);
+        }
+        let source = if self.fit == Fit::Cover {
+            let (x, y, crop_w, crop_h) = self.crop_window(&self.img, (width, height));
+            Cow::Owned(self.img.crop_imm(x, y, crop_w, crop_h))
+        } else {
+            Cow::Borrowed(&self.img)
+        };
+        let mut resized = if self.keeps_alpha() {
+            utils::resize_image_rgba(&source, width, height, self.filter)?
+        } else {
+            self.resize_opaque(&source, width, height)?
+        };
+        if self.sharpen > 0.0 {
+            resized = utils::sharpen(&resized, self.sharpen);
+        }
+        self.post_process(&mut resized);
+        let elapsed = start.elapsed();
+        self.timings.lock().unwrap().resizes.push((width, elapsed));
+        Ok(resized)
+    }
+
+    fn resize_and_maybe_compress(&self) -> Result<()> {
+        if self.target_sizes.is_empty() {
+            return Err(OptimizeError::InvalidTarget(
+                "Must provide at least one resize target size".to_string(),
+            ));
+        }
+        // Every target resizes from the whole decoded source. Each one also holds its crop window
+        // when covering, the box shrink's output (a quarter of the source at most) and its own
+        // buffers, so huge sources take them one at a time to keep that to a single target.
+        // JPEGs bound to small outputs are decoded at a reduced scale in the first place.
+        let (width, height) = self.img.dimensions();
+        if width as u64 * height as u64 > SEQUENTIAL_TARGET_PIXELS {
+            debug!("{} is large, resizing one target at a time", self.base_path);
+            return self
+                .target_sizes
+                .iter()
+                .try_for_each(|&(target_w, target_h)| self.write_target(target_w, target_h));
+        }
+        self.target_sizes
+            .par_iter()
+            .try_for_each(|&(target_w, target_h)| self.write_target(target_w, target_h))
+    }
+
+    fn write_target(&self, target_w: usize, target_h: usize) -> Result<()> {
+        let passthrough = self.compressor.is_none() && self.keep_original_format;
+        let mut pending = vec![];
+        for compressor in self.compressor_variants() {
+            let write_path = self.generate_save_path(compressor.as_ref(), target_w, target_h)?;
+            if self.should_skip(compressor.as_ref(), &write_path) {
+                let bytes = file_size(&write_path);
+                self.record_output(
+                    &write_path,
+                    (target_w, target_h),
+                    compressor.as_ref(),
+                    bytes,
+                );
+            } else {
+                pending.push((compressor, write_path));
+            }
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        // First, resize the image
+        self.check_cancelled()?;
+        debug!("Resizing {} to {target_w}x{target_h}", self.base_path);
+        let resized = self.resize_to(target_w, target_h)?;
+        let flattened = OnceCell::new();
+
+        for (compressor, write_path) in pending {
+            self.check_cancelled()?;
+            let img = self.variant_img(&resized, &flattened, compressor.as_ref());
+            let optimized = if let Some(compressor) = &compressor {
+                self.encode_with(compressor, img.as_bytes(), target_w, target_h)?
+            } else {
+                // Without a compressor the image crate encodes in the source or path's format
+                let format = if passthrough {
+                    self.source_format.ok_or(OptimizeError::InvalidOption(
+                        "Unable to detect the source image format".to_string(),
+                    ))?
+                } else {
+                    ImageFormat::from_path(&write_path)
+                        .map_err(|err| image_write_error(&write_path, err))?
+                };
+                let mut encoded = Cursor::new(vec![]);
+                img.write_to(&mut encoded, format)
+                    .map_err(|err| image_write_error(&write_path, err))?;
+                encoded.into_inner()
+            };
+            self.write_output(&write_path, &optimized)?;
+            self.record_encoded(
+                &write_path,
+                (target_w, target_h),
+                compressor.as_ref(),
+                img.as_bytes(),
+                &optimized,
+            );
+        }
+        Ok(())
+    }
+
+    pub fn generate_placeholder(&self, placeholder: &Placeholder) -> Result<String> {
+        match placeholder {
+            Placeholder::Blurhash => {
+                let (w, h) = responsive_dimensions(self.img.dimensions(), &[32])[0];
+                let thumbnail = utils::resize_image_rgba(&self.img, w, h, self.filter)?;
+                utils::encode_blurhash(&thumbnail)
+            }
+            Placeholder::Thumbhash => {
+                let (w, h) = max_dimension_targets(self.img.dimensions(), &[100])[0];
+                let thumbnail = utils::resize_image_rgba(&self.img, w, h, self.filter)?;
+                Ok(utils::encode_thumbhash(&thumbnail))
+            }
+            Placeholder::Lqip => {
+                let (w, h) = responsive_dimensions(self.img.dimensions(), &[20])[0];
+                let thumbnail = self.resize_opaque(&self.img, w, h)?.blur(1.0);
+                let jpeg = utils::compress_mozjpeg(
+                    thumbnail.as_bytes(),
+                    w,
+                    h,
+                    40.0,
+                    &utils::MozJpegOptions::default(),
+                    &[],
+                )?;
+                Ok(format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg)))
+            }
+        }
+    }
+
+    fn write_placeholder(&self, placeholder: &Placeholder) -> Result<()> {
+        let stem = Path::new(&self.base_path)
+            .file_stem()
+            .ok_or_else(missing_file_name)?;
+        let mut write_path = self.output_dir()?;
+        write_path.push(stem);
+        write_path.set_extension(placeholder.extension());
+
+        let generated = self.generate_placeholder(placeholder)?;
+        self.write_output(&write_path, generated.as_bytes())?;
+        // The manifest carries the value itself so pages can inline it without another fetch
+        let mut record = output_record(&write_path, self.get_img_dimensions(), None);
+        record.bytes = generated.len() as u64;
+        record.placeholder = Some(generated);
+        self.outputs.lock().unwrap().push(record);
+        Ok(())
+    }
+
+    fn write_jxl_transcode(&self, jpeg: &[u8]) -> Result<()> {
+        let stem = Path::new(&self.base_path)
+            .file_stem()
+            .ok_or_else(missing_file_name)?;
+        let mut write_path = self.output_dir()?;
+        write_path.push(stem);
+        write_path.set_extension("jxl");
+
+        let dimensions = self.get_img_dimensions();
+        if self.should_skip(None, &write_path) {
+            self.record_output(&write_path, dimensions, None, file_size(&write_path));
+            return Ok(());
+        }
+        let transcoded = utils::transcode_jpeg_to_jxl(jpeg)?;
+        self.write_output(&write_path, &transcoded)?;
+        self.record_output(&write_path, dimensions, None, transcoded.len() as u64);
+        Ok(())
+    }
+
+    pub fn optimize(&self) -> Result<()> {
+        let start = Instant::now();
+        let keeps_animation = self
+            .compressor_variants()
+            .iter()
+            .flatten()
+            .any(|compressor| matches!(compressor.encoder, Encoder::WebP));
+        if self.animation.is_some() && !keeps_animation {
+            warn!(
+                "Only WebP output keeps the animation of {}, using the first frame",
+                self.base_path
+            );
+        }
+        match self.target_sizes.len() {
+            0 => self.compress_self(),
+            _ => self.resize_and_maybe_compress(),
+        }?;
+        if let Some(jpeg) = &self.jxl_transcode_source {
+            self.write_jxl_transcode(jpeg)?;
+        }
+        if let Some(placeholder) = &self.placeholder {
+            self.write_placeholder(placeholder)?;
+        }
+        self.timings.lock().unwrap().processing = start.elapsed();
+        Ok(())
+    }
+}
+
+/// Chained alternative to the `set_*` methods on [`Optimizer`].
+///
+/// Values that need validating are checked once, in [`OptimizerBuilder::build`].
+pub struct OptimizerBuilder {
+    optimizer: Optimizer,
+    widths: Vec<usize>,
+    targets: Vec<(usize, usize)>,
+    name_template: Option<String>,
+    sharpen: Option<f32>,
+}
+
+impl OptimizerBuilder {
+    pub fn new(img: DynamicImage, img_path: &str) -> OptimizerBuilder {
+        OptimizerBuilder {
+            optimizer: Optimizer::new(img, img_path),
+            widths: vec![],
+            targets: vec![],
+            name_template: None,
+            sharpen: None,
+        }
+    }
+
+    /// Resize to these widths, keeping the source aspect ratio.
+    pub fn widths(mut self, widths: &[usize]) -> Self {
+        self.widths.extend_from_slice(widths);
+        self
+    }
+
+    /// Resize to exact `(width, height)` pairs.
+    pub fn target(mut self, width: usize, height: usize) -> Self {
+        self.targets.push((width, height));
+        self
+    }
+
+    pub fn compressor(mut self, compressor: Compressor) -> Self {
+        self.optimizer.set_compressor(compressor);
+        self
+    }
+
+    pub fn encoder(mut self, encoder: Encoder) -> Self {
+        self.optimizer.set_encoder(encoder);
+        self
+    }
+
+    pub fn quality(mut self, quality: f32) -> Self {
+        self.optimizer.set_quality(quality);
+        self
+    }
+
+    pub fn qualities(mut self, qualities: Vec<f32>) -> Self {
+        self.optimizer.set_qualities(qualities);
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.optimizer.set_max_bytes(max_bytes);
+        self
+    }
+
+    pub fn min_ssim(mut self, min_ssim: f64) -> Self {
+        self.optimizer.set_min_ssim(min_ssim);
+        self
+    }
+
+    pub fn lossless(mut self, lossless: bool) -> Self {
+        self.optimizer.set_lossless(lossless);
+        self
+    }
+
+    pub fn source_format(mut self, format: ImageFormat) -> Self {
+        self.optimizer.set_source_format(format);
+        self
+    }
+
+    pub fn keep_original_format(mut self, keep_original_format: bool) -> Self {
+        self.optimizer
+            .set_keep_original_format(keep_original_format);
+        self
+    }
+
+    pub fn metadata_policy(mut self, metadata_policy: MetadataPolicy) -> Self {
+        self.optimizer.set_metadata_policy(metadata_policy);
+        self
+    }
+
+    pub fn placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.optimizer.set_placeholder(placeholder);
+        self
+    }
+
+    pub fn background(mut self, background: [u8; 3]) -> Self {
+        self.optimizer.set_background(background);
+        self
+    }
+
+    pub fn name_template(mut self, template: &str) -> Self {
+        self.name_template = Some(template.to_string());
+        self
+    }
+
+    pub fn filter(mut self, filter: ResizeFilter) -> Self {
+        self.optimizer.set_filter(filter);
+        self
+    }
+
+    pub fn grayscale(mut self, grayscale: bool) -> Self {
+        self.optimizer.set_grayscale(grayscale);
+        self
+    }
+
+    pub fn brightness(mut self, brightness: f32) -> Self {
+        self.optimizer.set_brightness(brightness);
+        self
+    }
+
+    pub fn contrast(mut self, contrast: f32) -> Self {
+        self.optimizer.set_contrast(contrast);
+        self
+    }
+
+    pub fn saturation(mut self, saturation: f32) -> Self {
+        self.optimizer.set_saturation(saturation);
+        self
+    }
+
+    pub fn sharpen(mut self, amount: f32) -> Self {
+        self.sharpen = Some(amount);
+        self
+    }
+
+    /// Write outputs under `out_dir` instead of an `optimized` folder next to the source.
+    pub fn out_dir(mut self, out_dir: impl Into<PathBuf>) -> Self {
+        self.optimizer.set_out_dir(out_dir.into());
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.optimizer.set_overwrite(overwrite);
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.optimizer.set_verify(verify);
+        self
+    }
+
+    pub fn cancel_token(mut self, cancel_token: Arc<AtomicBool>) -> Self {
+        self.optimizer.set_cancel_token(cancel_token);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.optimizer.set_deadline(deadline);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Optimizer> {
+        let mut targets = self.targets;
+        if !self.widths.is_empty() {
+            let dimensions = self.optimizer.img.dimensions();
+            targets.extend(responsive_dimensions(dimensions, &self.widths));
+        }
+        if !targets.is_empty() {
+            self.optimizer.set_targets(targets)?;
+        }
+        if let Some(template) = &self.name_template {
+            self.optimizer.set_name_template(template)?;
+        }
+        if let Some(amount) = self.sharpen {
+            self.optimizer.set_sharpen(amount)?;
+        }
+        Ok(self.optimizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn overwrite_and_stale_outputs_are_rewritten() {
+        let dir = testing::temp_dir("overwrite");
+        let src = dir.join("photo.png");
+        testing::gradient(64, 48).save(&src).unwrap();
+        let optimize = |overwrite: bool| {
+            let mut optimizer = Optimizer::new(image::open(&src).unwrap(), src.to_str().unwrap());
+            optimizer.set_quality(75.0);
+            optimizer.set_overwrite(overwrite);
+            optimizer.set_targets(vec![(32, 24)]).unwrap();
+            optimizer.optimize().unwrap();
+        };
+        let output = dir.join("optimized/photo_32_75.jpg");
+        fs::create_dir_all(output.parent().unwrap()).unwrap();
+
+        fs::write(&output, b"older than the source").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&output)
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        optimize(false);
+        assert!(image::open(&output).is_ok());
+
+        fs::write(&output, b"up to date").unwrap();
+        optimize(true);
+        assert!(image::open(&output).is_ok());
+    }
+
+    #[test]
+    fn responsive_dimensions_keep_the_aspect_ratio() {
+        assert_eq!(
+            responsive_dimensions((1920, 1080), &[640, 1280]),
+            vec![(640, 360), (1280, 720)]
+        );
+        // Wider than the source, where an integer scale factor would be zero
+        assert_eq!(responsive_dimensions((100, 50), &[300]), vec![(300, 150)]);
+        assert_eq!(responsive_dimensions((1000, 1), &[10]), vec![(10, 1)]);
+    }
+
+    #[test]
+    fn density_variants_are_tagged_and_never_upscale() {
+        let widths = density_widths(320, &[1, 2, 3], 800);
+        assert_eq!(widths, vec![320, 640]);
+
+        let mut optimizer = Optimizer::new(testing::gradient(800, 600), "img/hero.jpg");
+        optimizer.set_quality(80.0);
+        optimizer.set_density_base_width(320);
+        let targets = responsive_dimensions(optimizer.dimensions(), &widths);
+        optimizer.set_targets(targets).unwrap();
+        let paths: Vec<_> = optimizer
+            .planned_outputs()
+            .unwrap()
+            .into_iter()
+            .map(|output| output.path)
+            .collect();
+        assert_eq!(
+            paths,
+            ["img/optimized/hero_80.jpg", "img/optimized/hero@2x_80.jpg"]
+        );
+    }
+
+    #[test]
+    fn max_height_narrows_targets_that_are_too_tall() {
+        // A portrait source, where widths alone would give heights past the cap
+        let targets = responsive_dimensions((600, 900), &[200, 400, 600]);
+        assert_eq!(targets, vec![(200, 300), (400, 600), (600, 900)]);
+        assert_eq!(
+            limit_height((600, 900), targets, 450),
+            vec![(200, 300), (300, 450), (300, 450)]
+        );
+    }
+
+    #[test]
+    fn scaled_dimensions_round_each_side() {
+        assert_eq!(
+            scaled_dimensions((1000, 750), &[50.0, 75.0]),
+            vec![(500, 375), (750, 563)]
+        );
+        // 33.33x22.11 for an odd source
+        assert_eq!(scaled_dimensions((101, 67), &[33.0]), vec![(33, 22)]);
+        assert_eq!(scaled_dimensions((10, 10), &[1.0]), vec![(1, 1)]);
+    }
+
+    fn planned_path(img_path: &str, configure: impl Fn(&mut Optimizer)) -> String {
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), img_path);
+        optimizer.set_quality(75.0);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        configure(&mut optimizer);
+        optimizer.planned_outputs().unwrap()[0].path.clone()
+    }
+
+    #[test]
+    fn outputs_go_next_to_relative_sources() {
+        let planned = |img_path| planned_path(img_path, |_| {});
+        assert_eq!(planned("photo.jpg"), "./optimized/photo_32_75.jpg");
+        assert_eq!(planned("./photo.jpg"), "./optimized/photo_32_75.jpg");
+        assert_eq!(
+            planned("shoots/2024/photo.jpg"),
+            "shoots/2024/optimized/photo_32_75.jpg"
+        );
+    }
+
+    #[test]
+    fn repeated_widths_are_planned_once_in_order() {
+        let mut optimizer = Optimizer::new(testing::gradient(1000, 500), "photo.jpg");
+        optimizer.set_quality(75.0);
+        let targets = responsive_dimensions((1000, 500), &[640, 320, 640, 960, 320]);
+        optimizer.set_targets(targets).unwrap();
+        optimizer.add_target((640, 320)).unwrap();
+        optimizer.add_target((480, 240)).unwrap();
+        let widths: Vec<_> = optimizer
+            .planned_outputs()
+            .unwrap()
+            .iter()
+            .map(|output| output.width)
+            .collect();
+        assert_eq!(widths, [320, 480, 640, 960]);
+    }
+
+    #[test]
+    fn each_quality_gets_its_own_output_per_width() {
+        let dir = testing::temp_dir("qualities");
+        let src = dir.join("photo.png");
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), src.to_str().unwrap());
+        optimizer.set_qualities(vec![60.0, 90.0]);
+        optimizer.set_targets(vec![(16, 12), (32, 24)]).unwrap();
+        optimizer.optimize().unwrap();
+
+        let outputs = optimizer.outputs();
+        let names: Vec<_> = outputs
+            .iter()
+            .map(|output| Path::new(&output.path).file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "photo_16_60.jpg",
+                "photo_16_90.jpg",
+                "photo_32_60.jpg",
+                "photo_32_90.jpg"
+            ]
+        );
+        for output in &outputs {
+            assert_eq!(fs::metadata(&output.path).unwrap().len(), output.bytes);
+        }
+        assert!(outputs[0].bytes < outputs[1].bytes);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn qualities_only_multiply_encodes_that_use_them() {
+        let names = |name, configure: fn(&mut Optimizer)| {
+            let dir = testing::temp_dir(name);
+            let src = dir.join("photo.jpg");
+            let mut optimizer = Optimizer::new(testing::gradient(64, 48), src.to_str().unwrap());
+            optimizer.set_qualities(vec![60.0, 90.0]);
+            configure(&mut optimizer);
+            optimizer.set_targets(vec![(16, 12)]).unwrap();
+            optimizer.optimize().unwrap();
+            optimizer
+                .outputs()
+                .iter()
+                .map(|output| Path::new(&output.path).file_name().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names("qualities-mixed", |optimizer| {
+                optimizer.set_encoders(vec![Encoder::WebP, Encoder::Png])
+            }),
+            ["photo_16.png", "photo_16_60.webp", "photo_16_90.webp"]
+        );
+        assert_eq!(
+            names("qualities-lossless", |optimizer| {
+                optimizer.set_encoder(Encoder::WebP);
+                optimizer.set_lossless(true);
+            }),
+            ["photo_16.webp"]
+        );
+        assert_eq!(
+            names("qualities-searched", |optimizer| optimizer
+                .set_max_bytes(4096)),
+            ["photo_16.jpg"]
+        );
+    }
+
+    #[test]
+    fn preserved_structure_mirrors_the_source_tree() {
+        let planned = |img_path, source_root: Option<&str>| {
+            planned_path(img_path, |optimizer| {
+                optimizer.set_out_dir(PathBuf::from("dist"));
+                if let Some(root) = source_root {
+                    optimizer.set_source_root(PathBuf::from(root));
+                }
+            })
+        };
+        assert_eq!(planned("src/a/b/c.jpg", None), "dist/c_32_75.jpg");
+        assert_eq!(
+            planned("src/a/b/c.jpg", Some("src")),
+            "dist/a/b/c_32_75.jpg"
+        );
+        assert_eq!(planned("src/top.jpg", Some("src")), "dist/top_32_75.jpg");
+    }
+
+    #[test]
+    fn zero_sized_targets_are_rejected() {
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "photo.jpg");
+        let err = optimizer.set_targets(vec![(32, 24), (0, 24)]).unwrap_err();
+        assert!(matches!(err, OptimizeError::InvalidTarget(_)), "{err}");
+        assert_eq!(
+            err.to_string(),
+            "Target dimensions must be greater than 0, got 0x24"
+        );
+        assert!(optimizer.add_target((32, 0)).is_err());
+    }
+
+    #[test]
+    fn resize_image_returns_the_target_size() {
+        let img = testing::gradient(64, 48);
+        let resized = utils::resize_image(&img, 20, 15, ResizeFilter::default()).unwrap();
+        assert_eq!(resized.dimensions(), (20, 15));
+    }
+
+    // Every quality that encodes to exactly what a search settled on, lowest first
+    fn searched_qualities(compressor: &Compressor, img: &[u8], encoded: &[u8]) -> Vec<f32> {
+        (0..=100)
+            .map(|quality| quality as f32)
+            .filter(|&quality| {
+                compressor
+                    .encode_at_quality(img, 64, 48, &[], quality)
+                    .unwrap()
+                    == encoded
+            })
+            .collect()
+    }
+
+    #[test]
+    fn byte_budget_picks_the_highest_quality_that_fits() {
+        let img = testing::gradient(64, 48).into_bytes();
+        let mut compressor = Compressor::new(75.0);
+        compressor.set_encoder(Encoder::MozJpeg);
+        let size_at = |quality| {
+            compressor
+                .encode_at_quality(&img, 64, 48, &[], quality)
+                .unwrap()
+        };
+
+        let mut chosen = vec![];
+        for budget in [size_at(40.0).len(), size_at(90.0).len()] {
+            let mut budgeted = compressor.clone();
+            budgeted.set_max_bytes(budget);
+            let encoded = budgeted.encode(&img, 64, 48, &[]).unwrap();
+            assert!(encoded.len() <= budget, "{} > {budget}", encoded.len());
+            let quality = *searched_qualities(&compressor, &img, &encoded)
+                .last()
+                .unwrap();
+            if quality < 100.0 {
+                assert!(size_at(quality + 1.0).len() > budget);
+            }
+            chosen.push(quality);
+        }
+        assert!(chosen[0] < chosen[1], "{chosen:?}");
+    }
+
+    #[test]
+    fn kept_formats_match_the_source() {
+        let dir = testing::temp_dir("keep-original-format");
+        // The last is a JPEG behind a .png name, which only the decoded format gets right
+        for (name, format) in [
+            ("photo.png", ImageFormat::Png),
+            ("photo.jpg", ImageFormat::Jpeg),
+            ("scan.png", ImageFormat::Jpeg),
+        ] {
+            let src = dir.join(name);
+            let optimizer = Optimizer::builder(testing::gradient(64, 48), src.to_str().unwrap())
+                .source_format(format)
+                .keep_original_format(true)
+                .target(32, 24)
+                .build()
+                .unwrap();
+            optimizer.optimize().unwrap();
+            let output = fs::read(&optimizer.outputs()[0].path).unwrap();
+            assert_eq!(image::guess_format(&output).unwrap(), format);
+            let decoded = image::load_from_memory(&output).unwrap();
+            assert_eq!(decoded.dimensions(), (32, 24));
+        }
+    }
+
+    #[test]
+    fn placeholders_are_tiny_previews() {
+        let optimizer = Optimizer::new(testing::gradient(64, 48), "photo.jpg");
+        // 4x3 components: a size flag, the max AC, four characters of DC and two per AC
+        let blurhash = optimizer
+            .generate_placeholder(&Placeholder::Blurhash)
+            .unwrap();
+        assert_eq!(blurhash.len(), 28, "{blurhash}");
+        assert!(blurhash.starts_with('L'), "{blurhash}");
+
+        let lqip = optimizer.generate_placeholder(&Placeholder::Lqip).unwrap();
+        let jpeg = lqip.strip_prefix("data:image/jpeg;base64,").unwrap();
+        let jpeg = STANDARD.decode(jpeg).unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(decoded.dimensions(), (20, 15));
+    }
+
+    #[test]
+    fn sixteen_bit_pngs_come_out_at_the_right_size_and_colour() {
+        let img = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(
+            40,
+            30,
+            image::Rgb([65535, 32896, 0]),
+        ));
+        let png = testing::encode(&img, ImageFormat::Png);
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb16);
+
+        let mut optimizer = Optimizer::new(decoded, "photo.png");
+        optimizer.set_encoder(Encoder::Png);
+        optimizer.set_targets(vec![(20, 15)]).unwrap();
+        let encoded = optimizer.optimize_to_bytes().unwrap();
+        let output = image::load_from_memory(&encoded).unwrap().into_rgb8();
+        assert_eq!(output.dimensions(), (20, 15));
+        assert!(output
+            .pixels()
+            .all(|pixel| pixel[0] == 255 && pixel[1].abs_diff(128) <= 1 && pixel[2] == 0));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn metadata_policies_choose_the_surviving_markers() {
+        let img = testing::gradient(16, 16).into_bytes();
+        let metadata = [
+            MetadataSegment {
+                marker: utils::APP1_MARKER,
+                data: b"Exif\0\0MM\0*".to_vec(),
+            },
+            MetadataSegment {
+                marker: utils::COM_MARKER,
+                data: b"(c) Example Photos".to_vec(),
+            },
+        ];
+        let markers = |policy: MetadataPolicy| {
+            let mut compressor = Compressor::new(75.0);
+            compressor.set_metadata_policy(policy);
+            let jpeg = compressor.encode(&img, 16, 16, &metadata).unwrap();
+            utils::read_jpeg_metadata(&jpeg)
+                .iter()
+                .map(|segment| segment.marker)
+                .collect::<Vec<_>>()
+        };
+        assert!(markers(MetadataPolicy::Strip).is_empty());
+        assert_eq!(markers(MetadataPolicy::Copyright), [utils::COM_MARKER]);
+        assert_eq!(
+            markers(MetadataPolicy::All),
+            [utils::APP1_MARKER, utils::COM_MARKER]
+        );
+    }
+
+    #[test]
+    fn timings_cover_each_stage_of_a_real_encode() {
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "photo.png");
+        optimizer.record_decode_time(Duration::from_millis(5));
+        optimizer.set_quality(75.0);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        optimizer.optimize_to_bytes().unwrap();
+
+        let timings = optimizer.timings();
+        assert_eq!(timings.decode, Duration::from_millis(5));
+        assert_eq!(timings.resizes.len(), 1);
+        assert_eq!(timings.encodes.len(), 1);
+        assert_eq!((timings.resizes[0].0, timings.encodes[0].0), (32, 32));
+        assert!(timings.processing > Duration::ZERO);
+        assert!(timings.total() > timings.decode);
+    }
+
+    // Keeps every info record, tests pick out their own by path as they share the logger
+    struct RecordingLogger(Mutex<Vec<String>>);
+
+    impl log::Log for RecordingLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Info
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: RecordingLogger = RecordingLogger(Mutex::new(vec![]));
+
+    #[test]
+    fn each_written_file_is_logged() {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Info);
+        }
+        let dir = testing::temp_dir("logging");
+        let src = dir.join("photo.png");
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), src.to_str().unwrap());
+        optimizer.set_quality(75.0);
+        optimizer.set_targets(vec![(16, 12), (32, 24)]).unwrap();
+        optimizer.optimize().unwrap();
+
+        let records = LOGGER.0.lock().unwrap();
+        let outputs = optimizer.outputs();
+        assert_eq!(outputs.len(), 2);
+        for output in outputs {
+            let written = format!("Wrote {} ({} bytes)", output.path, output.bytes);
+            assert_eq!(
+                records.iter().filter(|record| **record == written).count(),
+                1,
+                "{written}"
+            );
+        }
+    }
+
+    // Raises the cancel flag as soon as the first output lands
+    struct CancellingSink {
+        cancel_token: Arc<AtomicBool>,
+        written: Mutex<Vec<PathBuf>>,
+    }
+
+    impl OutputSink for CancellingSink {
+        fn write(&self, path: &Path, _bytes: &[u8]) -> Result<()> {
+            self.written.lock().unwrap().push(path.to_path_buf());
+            self.cancel_token.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn exists(&self, _output: &Path) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn cancelling_stops_before_the_next_width() {
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        let sink = Arc::new(CancellingSink {
+            cancel_token: cancel_token.clone(),
+            written: Mutex::new(vec![]),
+        });
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "dir/photo.png");
+        optimizer.set_quality(75.0);
+        optimizer.set_targets(vec![(16, 12), (32, 24)]).unwrap();
+        optimizer.set_cancel_token(cancel_token);
+        optimizer.set_sink(sink.clone());
+        // A single thread works through the widths in order
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let err = pool.install(|| optimizer.optimize()).unwrap_err();
+        assert!(matches!(err, OptimizeError::Cancelled(_)), "{err}");
+        assert_eq!(
+            *sink.written.lock().unwrap(),
+            [PathBuf::from("dir/optimized/photo_16_75.jpg")]
+        );
+    }
+
+    #[test]
+    fn past_deadlines_stop_before_any_encode() {
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "photo.png");
+        optimizer.set_quality(75.0);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        optimizer.set_deadline(Instant::now());
+        let err = optimizer.optimize_to_bytes().unwrap_err();
+        assert!(matches!(err, OptimizeError::DeadlineExceeded(_)), "{err}");
+    }
+
+    #[test]
+    fn ssim_target_picks_the_lowest_quality_that_meets_it() {
+        let img = testing::gradient(64, 48).into_bytes();
+        let mut compressor = Compressor::new(75.0);
+        compressor.set_encoder(Encoder::MozJpeg);
+        let ssim_at = |quality| {
+            let encoded = compressor
+                .encode_at_quality(&img, 64, 48, &[], quality)
+                .unwrap();
+            utils::ssim(&img, &encoded, 64, 48).unwrap()
+        };
+
+        let mut chosen = vec![];
+        for target in [ssim_at(30.0), ssim_at(85.0)] {
+            let mut searched = compressor.clone();
+            searched.set_min_ssim(target);
+            let encoded = searched.encode(&img, 64, 48, &[]).unwrap();
+            assert!(utils::ssim(&img, &encoded, 64, 48).unwrap() >= target);
+            let quality = searched_qualities(&compressor, &img, &encoded)[0];
+            if quality > 0.0 {
+                assert!(ssim_at(quality - 1.0) < target);
+            }
+            chosen.push(quality);
+        }
+        assert!(chosen[0] < chosen[1], "{chosen:?}");
+    }
+
+    #[test]
+    fn configured_compressors_are_used_as_given() {
+        let mut compressor = Compressor::new(55.0);
+        compressor.set_encoder(Encoder::MozJpeg);
+        compressor.set_progressive(true);
+        compressor.set_chroma_subsampling(ChromaSubsampling::Yuv444);
+        let optimizer = Optimizer::builder(testing::gradient(64, 48), "dir/photo.png")
+            .compressor(compressor.clone())
+            .target(32, 24)
+            .build()
+            .unwrap();
+
+        let planned = &optimizer.planned_outputs().unwrap()[0];
+        assert_eq!(planned.path, "dir/optimized/photo_32_55.jpg");
+        assert_eq!(planned.quality, Some(55.0));
+        let resized =
+            utils::resize_image(&testing::gradient(64, 48), 32, 24, ResizeFilter::default())
+                .unwrap();
+        assert_eq!(
+            optimizer.optimize_to_bytes().unwrap(),
+            compressor.encode(resized.as_bytes(), 32, 24, &[]).unwrap()
+        );
+
+        // The convenience setters adjust the compressor rather than replacing it
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "dir/photo.png");
+        optimizer.set_compressor(compressor);
+        optimizer.set_encoder(Encoder::Png);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        let planned = &optimizer.planned_outputs().unwrap()[0];
+        assert_eq!(planned.path, "dir/optimized/photo_32.png");
+    }
+
+    fn optimize_twice(name: &str, configure: impl Fn(&mut Optimizer, u32)) -> (Vec<u8>, Vec<u8>) {
+        let dir = testing::temp_dir(name);
+        let src = dir.join("photo.png");
+        testing::gradient(64, 48).save(&src).unwrap();
+        let mut outputs = vec![];
+        for run in 0..2 {
+            let mut optimizer = Optimizer::new(image::open(&src).unwrap(), src.to_str().unwrap());
+            configure(&mut optimizer, run);
+            optimizer.set_targets(vec![(32, 24)]).unwrap();
+            optimizer.optimize().unwrap();
+            let written = optimizer.outputs();
+            assert_eq!(written.len(), 1);
+            outputs.push(fs::read(&written[0].path).unwrap());
+        }
+        (outputs.remove(0), outputs.remove(0))
+    }
+
+    #[test]
+    fn ssim_target_rejects_undecodable_encoders() {
+        let img = testing::gradient(16, 16).into_bytes();
+        for encoder in [Encoder::Avif, Encoder::JpegXl] {
+            let mut compressor = Compressor::new(75.0);
+            compressor.set_encoder(encoder);
+            compressor.set_min_ssim(0.9);
+            let err = compressor.encode(&img, 16, 16, &[]).unwrap_err();
+            assert!(matches!(err, OptimizeError::InvalidOption(_)), "{err}");
+        }
+    }
+
+    #[test]
+    fn unreachable_quality_searches_have_their_own_errors() {
+        let img = testing::gradient(16, 16).into_bytes();
+        let mut compressor = Compressor::new(75.0);
+        compressor.set_encoder(Encoder::MozJpeg);
+        compressor.set_max_bytes(10);
+        let err = compressor.encode(&img, 16, 16, &[]).unwrap_err();
+        assert!(matches!(err, OptimizeError::OverBudget(10)), "{err}");
+
+        let mut compressor = Compressor::new(75.0);
+        compressor.set_encoder(Encoder::MozJpeg);
+        compressor.set_min_ssim(1.0);
+        let err = compressor.encode(&img, 16, 16, &[]).unwrap_err();
+        assert!(matches!(err, OptimizeError::SsimUnreachable(_)), "{err}");
+    }
+
+    #[test]
+    fn image_crate_encode_errors_name_the_output() {
+        let path = Path::new("out/photo.xyz");
+        let err = image_write_error(path, ImageFormat::from_path(path).unwrap_err());
+        assert!(matches!(err, OptimizeError::ImageEncode { .. }), "{err}");
+        assert_eq!(err.to_string(), "Error encoding out/photo.xyz");
+
+        let io_error = image::ImageError::IoError(io::Error::other("disk full"));
+        let err = image_write_error(path, io_error);
+        assert!(matches!(err, OptimizeError::Io { .. }), "{err}");
+    }
+
+    #[test]
+    fn searched_encodes_leave_the_quality_out_of_names() {
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "dir/photo.jpg");
+        optimizer.set_encoder(Encoder::MozJpeg);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        let name = |optimizer: &Optimizer| optimizer.planned_outputs().unwrap()[0].path.clone();
+        assert_eq!(name(&optimizer), "dir/optimized/photo_32_75.jpg");
+
+        optimizer.set_max_bytes(2000);
+        assert_eq!(name(&optimizer), "dir/optimized/photo_32.jpg");
+        optimizer
+            .set_name_template("{stem}-{width}-q{quality}.{ext}")
+            .unwrap();
+        assert_eq!(name(&optimizer), "dir/optimized/photo-32-q.jpg");
+    }
+
+    #[test]
+    fn verification_decodes_outputs() {
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "photo.png");
+        optimizer.set_verify(true);
+        optimizer.set_encoder(Encoder::Png);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        let encoded = optimizer.optimize_to_bytes().unwrap();
+        assert_eq!(
+            image::load_from_memory(&encoded).unwrap().dimensions(),
+            (32, 24)
+        );
+
+        optimizer.set_encoder(Encoder::Avif);
+        let err = optimizer.optimize_to_bytes().unwrap_err();
+        assert!(matches!(err, OptimizeError::InvalidOption(_)), "{err}");
+    }
+
+    #[test]
+    fn sixteen_bit_sources_are_reduced_like_decoded_ones() {
+        let img = DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            image::Rgb([x as u16 * 8000, y as u16 * 8000, 25828])
+        }));
+        let optimizer = Optimizer::new(img.clone(), "photo.png");
+        assert_eq!(optimizer.img, utils::reduce_to_8_bit(img));
+    }
+
+    #[test]
+    fn transparent_sources_are_flattened_after_the_resize() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 32, |x, _| {
+            if x < 32 {
+                image::Rgba([255, 0, 0, 0])
+            } else {
+                image::Rgba([0, 0, 255, 255])
+            }
+        }));
+        let mut optimizer = Optimizer::new(img, "logo.png");
+        optimizer.set_encoder(Encoder::MozJpeg);
+        optimizer.set_background([0, 255, 0]);
+        let resized = optimizer.resize_to(16, 8).unwrap();
+        let resized = resized.as_rgb8().unwrap();
+        assert_eq!(resized.get_pixel(2, 4), &image::Rgb([0, 255, 0]));
+        assert_eq!(resized.get_pixel(13, 4), &image::Rgb([0, 0, 255]));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn only_variants_without_alpha_are_flattened() {
+        let dir = testing::temp_dir("per-variant-alpha");
+        let src = dir.join("logo.png");
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 32, |x, _| {
+            if x < 32 {
+                image::Rgba([255, 0, 0, 0])
+            } else {
+                image::Rgba([0, 0, 255, 255])
+            }
+        }));
+        let mut optimizer = Optimizer::new(img, src.to_str().unwrap());
+        optimizer.set_encoders(vec![Encoder::WebP, Encoder::MozJpeg]);
+        optimizer.set_background([0, 255, 0]);
+        optimizer.set_targets(vec![(16, 8)]).unwrap();
+        optimizer.optimize().unwrap();
+
+        let decoded = |extension: &str| {
+            let output = optimizer
+                .outputs()
+                .into_iter()
+                .find(|output| output.path.ends_with(extension))
+                .unwrap();
+            image::load_from_memory(&fs::read(&output.path).unwrap()).unwrap()
+        };
+        let jpeg = decoded(".jpg").into_rgb8();
+        let green = jpeg.get_pixel(2, 4);
+        assert!(
+            green[0] < 32 && green[1] > 224 && green[2] < 32,
+            "{green:?}"
+        );
+        let webp = decoded(".webp");
+        assert!(webp.color().has_alpha());
+        assert_eq!(webp.to_rgba8().get_pixel(2, 4)[3], 0);
+    }
+
+    #[test]
+    fn reduced_decodes_report_the_source_size() {
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), "photo.jpg");
+        optimizer.set_source_dimensions((512, 384));
+        assert_eq!(optimizer.dimensions(), (512, 384));
+        assert_eq!(optimizer.get_img_dimensions(), (512, 384));
+        assert_eq!(optimizer.resize_to(32, 24).unwrap().dimensions(), (32, 24));
+    }
+
+    #[test]
+    fn changed_quality_rewrites_outputs_named_without_it() {
+        let (first, second) = optimize_twice("quality-not-in-name", |optimizer, run| {
+            optimizer.set_quality(if run == 0 { 20.0 } else { 95.0 });
+            optimizer.set_quality_in_name(false);
+        });
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn changed_quality_rewrites_outputs_with_template_without_it() {
+        let (first, second) = optimize_twice("template-without-quality", |optimizer, run| {
+            optimizer.set_quality(if run == 0 { 20.0 } else { 95.0 });
+            optimizer
+                .set_name_template("{stem}-{width}w.{ext}")
+                .unwrap();
+        });
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn up_to_date_outputs_named_with_quality_are_skipped() {
+        let dir = testing::temp_dir("quality-in-name");
+        let src = dir.join("photo.png");
+        testing::gradient(64, 48).save(&src).unwrap();
+        let optimize = || {
+            let mut optimizer = Optimizer::new(image::open(&src).unwrap(), src.to_str().unwrap());
+            optimizer.set_quality(80.0);
+            optimizer.set_targets(vec![(32, 24)]).unwrap();
+            optimizer.optimize().unwrap();
+        };
+        optimize();
+        let output = dir.join("optimized/photo_32_80.jpg");
+        fs::write(&output, b"left alone").unwrap();
+        optimize();
+        assert_eq!(fs::read(&output).unwrap(), b"left alone");
     }
 }
@@ -0,0 +1,217 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    thread,
+};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::optimizer::Encoder;
+
+#[derive(Debug)]
+pub struct ImageRequest {
+    pub path: PathBuf,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub encoder: Encoder,
+    pub quality: Option<f32>,
+}
+
+// `+` is only a space in the query, callers swap it before decoding so %2B stays a plus
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(pos + 1..pos + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                pos += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                pos += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn parse_length(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(length) if length > 0 => Ok(length),
+        _ => Err(format!("{name} must be a positive integer, got {value}")),
+    }
+}
+
+impl ImageRequest {
+    // Only /img/<path below root>?w=&h=&format=&q= is served
+    pub fn parse(url: &str, root: &Path) -> Result<ImageRequest, String> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let path = path
+            .strip_prefix("/img/")
+            .and_then(percent_decode)
+            .ok_or(format!("Unknown path {path}"))?;
+        let relative = Path::new(&path);
+        // Never let a request climb out of the served directory
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("Invalid path {path}"));
+        }
+
+        let mut request = ImageRequest {
+            path: root.join(relative),
+            width: None,
+            height: None,
+            encoder: relative
+                .extension()
+                .and_then(|ext| Encoder::parse_name(&ext.to_string_lossy()))
+                .unwrap_or(Encoder::WebP),
+            quality: None,
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(&value.replace('+', " "))
+                .ok_or(format!("Invalid value for {key}"))?;
+            match key {
+                "w" => request.width = Some(parse_length("w", &value)?),
+                "h" => request.height = Some(parse_length("h", &value)?),
+                "format" => {
+                    request.encoder =
+                        Encoder::parse_name(&value).ok_or(format!("Unknown format {value}"))?
+                }
+                "q" => match value.parse() {
+                    Ok(quality) if (0.0..=100.0).contains(&quality) => {
+                        request.quality = Some(quality)
+                    }
+                    _ => return Err(format!("q must be between 0 and 100, got {value}")),
+                },
+                _ => return Err(format!("Unknown parameter {key}")),
+            }
+        }
+        Ok(request)
+    }
+}
+
+fn respond_error(request: Request, status: u16, message: &str) {
+    let response = Response::from_string(message).with_status_code(status);
+    if let Err(err) = request.respond(response) {
+        warn!("Failed to send response: {err}");
+    }
+}
+
+fn handle(
+    request: Request,
+    root: &Path,
+    cache_control: &Header,
+    optimize: &(impl Fn(&ImageRequest) -> anyhow::Result<Vec<u8>> + Sync),
+) {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        return respond_error(request, 405, "Only GET is supported");
+    }
+    let image_request = match ImageRequest::parse(request.url(), root) {
+        Ok(image_request) => image_request,
+        Err(message) => return respond_error(request, 400, &message),
+    };
+    if !image_request.path.is_file() {
+        return respond_error(request, 404, "Not found");
+    }
+    let optimized = match optimize(&image_request) {
+        Ok(optimized) => optimized,
+        Err(err) => {
+            warn!("Failed to optimize {}: {err:#}", request.url());
+            return respond_error(request, 500, "Failed to optimize image");
+        }
+    };
+    info!("Served {} ({} bytes)", request.url(), optimized.len());
+    let content_type = Header::from_bytes("Content-Type", image_request.encoder.mime_type())
+        .expect("static headers are valid");
+    let response = Response::from_data(optimized)
+        .with_header(content_type)
+        .with_header(cache_control.clone());
+    if let Err(err) = request.respond(response) {
+        warn!("Failed to send response: {err}");
+    }
+}
+
+pub fn serve(
+    addr: &str,
+    root: &Path,
+    workers: usize,
+    cache_control: &str,
+    optimize: impl Fn(&ImageRequest) -> anyhow::Result<Vec<u8>> + Sync,
+) -> anyhow::Result<()> {
+    let cache_control = Header::from_bytes("Cache-Control", cache_control)
+        .map_err(|_| anyhow!("Invalid cache control {cache_control}"))?;
+    let server = Server::http(addr).map_err(|err| anyhow!("Unable to listen on {addr}: {err}"))?;
+    info!("Serving {} on http://{addr}/img/", root.display());
+    let (server, cache_control, optimize) = (&server, &cache_control, &optimize);
+    // A fixed set of workers outside the rayon pool, so at most that many sources are decoded at
+    // once and the rest wait in the listener. Blocking on the pool itself could starve it when
+    // --jobs is 1.
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            scope.spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request, root, cache_control, optimize);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> Result<ImageRequest, String> {
+        ImageRequest::parse(url, Path::new("/srv"))
+    }
+
+    #[test]
+    fn paths_keep_their_plus_signs() {
+        let request = parse("/img/summer+beach%20day.jpg").unwrap();
+        assert_eq!(request.path, Path::new("/srv/summer+beach day.jpg"));
+        assert!(matches!(request.encoder, Encoder::MozJpeg));
+    }
+
+    #[test]
+    fn query_values_decode_plus_as_space() {
+        assert_eq!(percent_decode("a%2Bb").as_deref(), Some("a+b"));
+        let err = parse("/img/a.png?format=web+p").unwrap_err();
+        assert_eq!(err, "Unknown format web p");
+        let request = parse("/img/a.png?w=%33%32&format=avif&q=60").unwrap();
+        assert_eq!(request.width, Some(32));
+        assert!(matches!(request.encoder, Encoder::Avif));
+        assert_eq!(request.quality, Some(60.0));
+    }
+
+    #[test]
+    fn malformed_escapes_are_rejected() {
+        let request = parse("/img/caf%C3%A9/%E2%9C%93.png").unwrap();
+        assert_eq!(request.path, Path::new("/srv/café/✓.png"));
+        for url in ["/img/a%zz.jpg", "/img/a%4", "/img/%FF.jpg"] {
+            assert!(parse(url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn paths_outside_the_root_are_rejected() {
+        assert!(parse("/img/../etc/passwd").is_err());
+        assert!(parse("/img/%2E%2E/etc/passwd").is_err());
+        assert!(parse("/img//etc/passwd").is_err());
+        assert!(parse("/other/a.jpg").is_err());
+    }
+
+    #[test]
+    fn invalid_lengths_are_rejected() {
+        assert!(parse("/img/a.jpg?w=0").is_err());
+        assert!(parse("/img/a.jpg?h=-4").is_err());
+        assert!(parse("/img/a.jpg?q=101").is_err());
+    }
+}
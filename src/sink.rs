@@ -0,0 +1,165 @@
+use std::{fs::File, io::Write, path::Path};
+
+#[cfg(feature = "native")]
+use std::io;
+
+#[cfg(feature = "native")]
+use s3::{bucket::Bucket, creds::Credentials, region::Region};
+
+use crate::error::{OptimizeError, Result};
+use crate::utils::{self, ensure_parent_directory_exists};
+
+// Output names don't change with their contents, so a year is as far as it's safe to go
+pub const DEFAULT_CACHE_CONTROL: &str = "public, max-age=31536000";
+
+pub trait OutputSink: Send + Sync {
+    fn write(&self, path: &Path, bytes: &[u8]) -> Result<()>;
+
+    // Whether an earlier run's output is still there, for skipping sources the cache knows
+    fn exists(&self, output: &Path) -> bool;
+
+    // Outputs that can't be checked are always written again
+    fn is_up_to_date(&self, _src: &Path, _output: &Path) -> bool {
+        false
+    }
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "jxl" => "image/jxl",
+        "gif" => "image/gif",
+        "blurhash" | "lqip" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+pub struct FileSink;
+
+impl OutputSink for FileSink {
+    fn write(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        let write_error = |err| OptimizeError::write(path, err);
+        ensure_parent_directory_exists(path).map_err(write_error)?;
+        let mut file = File::create(path).map_err(write_error)?;
+        file.write_all(bytes).map_err(write_error)
+    }
+
+    fn exists(&self, output: &Path) -> bool {
+        output.is_file()
+    }
+
+    fn is_up_to_date(&self, src: &Path, output: &Path) -> bool {
+        utils::is_output_up_to_date(src, output)
+    }
+}
+
+// Writes outputs under s3://bucket/ as objects keyed by the rest of the path. Credentials and
+// the region come from the usual AWS environment variables and profiles.
+#[cfg(feature = "native")]
+pub struct S3Sink {
+    bucket: Bucket,
+    prefix: String,
+}
+
+pub fn is_s3_url(path: &Path) -> bool {
+    path.to_string_lossy().starts_with("s3://")
+}
+
+#[cfg(feature = "native")]
+impl S3Sink {
+    pub fn new(url: &str, cache_control: &str) -> Result<S3Sink> {
+        let invalid =
+            || OptimizeError::InvalidPath(format!("Expected s3://bucket/prefix, got {url}"));
+        let bucket_name = url
+            .strip_prefix("s3://")
+            .and_then(|rest| rest.split('/').next())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(invalid)?;
+        let setup_error = |err: &dyn std::fmt::Display| {
+            OptimizeError::InvalidOption(format!("Unable to set up S3 bucket {bucket_name}: {err}"))
+        };
+        let region = Region::from_default_env().map_err(|err| setup_error(&err))?;
+        let credentials = Credentials::default().map_err(|err| setup_error(&err))?;
+        let mut bucket =
+            Bucket::new(bucket_name, region, credentials).map_err(|err| setup_error(&err))?;
+        bucket.add_header("Cache-Control", cache_control);
+        Ok(S3Sink {
+            bucket,
+            prefix: format!("s3://{bucket_name}/"),
+        })
+    }
+
+    fn key<'a>(&self, path: &'a str) -> Result<&'a str> {
+        path.strip_prefix(&self.prefix)
+            .ok_or(OptimizeError::InvalidPath(format!(
+                "{path} is outside {}",
+                self.prefix
+            )))
+    }
+}
+
+#[cfg(feature = "native")]
+impl OutputSink for S3Sink {
+    fn write(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        let path_str = path.to_string_lossy();
+        let key = self.key(&path_str)?;
+        let response = self
+            .bucket
+            .put_object_with_content_type(key, bytes, content_type(path))
+            .map_err(|err| OptimizeError::write(path, io::Error::other(err)))?;
+        if response.status_code() >= 300 {
+            let status = format!("S3 responded with status {}", response.status_code());
+            return Err(OptimizeError::write(path, io::Error::other(status)));
+        }
+        Ok(())
+    }
+
+    // A failed lookup counts as missing, so the source is optimized and uploaded again
+    fn exists(&self, output: &Path) -> bool {
+        let path_str = output.to_string_lossy();
+        let Ok(key) = self.key(&path_str) else {
+            return false;
+        };
+        matches!(self.bucket.head_object(key), Ok((_, status)) if status < 300)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::optimizer::Optimizer;
+    use crate::testing;
+
+    #[test]
+    fn failed_writes_name_the_output() {
+        let dir = testing::temp_dir("blocked-output");
+        // A file where the output folder should go
+        fs::write(dir.join("optimized"), b"").unwrap();
+        let output = dir.join("optimized/photo_32_75.jpg");
+
+        let err = FileSink.write(&output, b"jpeg").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("failed to write {}", output.display())
+        );
+
+        let src = dir.join("photo.png");
+        let mut optimizer = Optimizer::new(testing::gradient(64, 48), src.to_str().unwrap());
+        optimizer.set_quality(75.0);
+        optimizer.set_targets(vec![(32, 24)]).unwrap();
+        let err = optimizer.optimize().unwrap_err();
+        assert!(
+            err.to_string().contains(&output.display().to_string()),
+            "{err}"
+        );
+    }
+}
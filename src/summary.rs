@@ -0,0 +1,106 @@
+use std::{fmt::Display, fs};
+
+use crate::manifest::OutputRecord;
+
+struct Row {
+    img_src: String,
+    before: u64,
+    after: u64,
+    // The worst PSNR and DSSIM across all of the source's outputs
+    metrics: Option<(f64, f64)>,
+}
+
+#[derive(Default)]
+pub struct Summary(Vec<Row>);
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+fn saved_percent(before: u64, after: u64) -> f64 {
+    if before == 0 {
+        0.0
+    } else {
+        100.0 * (1.0 - after as f64 / before as f64)
+    }
+}
+
+impl Summary {
+    pub fn add(&mut self, img_src: &str, outputs: &[OutputRecord]) {
+        // Smaller widths would flatter the savings, so compare against the best encode of
+        // the largest output
+        let images = outputs.iter().filter(|output| output.placeholder.is_none());
+        let largest = images.clone().map(|output| output.width).max();
+        let Some(after) = images
+            .filter(|output| Some(output.width) == largest)
+            .map(|output| output.bytes)
+            .min()
+        else {
+            return;
+        };
+        let before = fs::metadata(img_src).map(|meta| meta.len()).unwrap_or(0);
+        let metrics = outputs
+            .iter()
+            .filter_map(|output| output.psnr.zip(output.dssim))
+            .reduce(|(psnr, dssim), (other_psnr, other_dssim)| {
+                (psnr.min(other_psnr), dssim.max(other_dssim))
+            });
+        self.0.push(Row {
+            img_src: img_src.to_string(),
+            before,
+            after,
+            metrics,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name_width = self
+            .0
+            .iter()
+            .map(|row| row.img_src.len())
+            .max()
+            .unwrap_or(0)
+            .max("Total".len());
+        for row in &self.0 {
+            write!(
+                f,
+                "{:<name_width$}  {:>10} -> {:>10}  {:>6.1}%",
+                row.img_src,
+                format_bytes(row.before),
+                format_bytes(row.after),
+                saved_percent(row.before, row.after)
+            )?;
+            if let Some((psnr, dssim)) = row.metrics {
+                write!(f, "  PSNR {psnr:>5.1} dB  DSSIM {dssim:.6}")?;
+            }
+            writeln!(f)?;
+        }
+        let before = self.0.iter().map(|row| row.before).sum();
+        let after = self.0.iter().map(|row| row.after).sum();
+        write!(
+            f,
+            "{:<name_width$}  {:>10} -> {:>10}  {:>6.1}% saved across {} files",
+            "Total",
+            format_bytes(before),
+            format_bytes(after),
+            saved_percent(before, after),
+            self.0.len()
+        )
+    }
+}
@@ -0,0 +1,42 @@
+use std::{fs, io::Cursor, path::PathBuf};
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+// A fresh directory per test, so parallel tests never see each other's outputs
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("img-optimizer-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Smooth ramps with a hard edge, enough detail for lossy encoders to differ by quality
+pub fn gradient(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        let edge = if x > width / 2 { 200 } else { 0 };
+        Rgb([
+            (x * 255 / width.max(1)) as u8,
+            (y * 255 / height.max(1)) as u8,
+            edge,
+        ])
+    }))
+}
+
+pub fn encode(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut encoded = Cursor::new(vec![]);
+    img.write_to(&mut encoded, format).unwrap();
+    encoded.into_inner()
+}
+
+// A JPEG whose EXIF says the camera was rotated, with a single little-endian IFD entry
+pub fn jpeg_with_orientation(img: &DynamicImage, orientation: u16) -> Vec<u8> {
+    let jpeg = encode(img, ImageFormat::Jpeg);
+    let mut exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+    exif.extend_from_slice(&orientation.to_le_bytes());
+    exif.extend_from_slice(&[0; 6]);
+    let mut with_exif = vec![0xFF, 0xD8, 0xFF, 0xE1];
+    with_exif.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    with_exif.extend_from_slice(&exif);
+    with_exif.extend_from_slice(&jpeg[2..]);
+    with_exif
+}
@@ -0,0 +1,28 @@
+use std::{fmt::Display, time::Duration};
+
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    pub decode: Duration,
+    pub resizes: Vec<(usize, Duration)>,
+    pub encodes: Vec<(usize, Duration)>,
+    pub processing: Duration,
+}
+
+impl Timings {
+    pub fn total(&self) -> Duration {
+        self.decode + self.processing
+    }
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  decode: {:?}", self.decode)?;
+        for (width, elapsed) in &self.resizes {
+            writeln!(f, "  resize {width}w: {elapsed:?}")?;
+        }
+        for (width, elapsed) in &self.encodes {
+            writeln!(f, "  encode {width}w: {elapsed:?}")?;
+        }
+        writeln!(f, "  total: {:?}", self.total())
+    }
+}
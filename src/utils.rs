@@ -1,10 +1,55 @@
-use anyhow::anyhow;
-use std::{fs, io, path::Path};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use image::{
+    codecs::gif::GifDecoder,
+    error::{DecodingError, ImageFormatHint},
+    imageops, AnimationDecoder, DynamicImage, GenericImageView, ImageBuffer, ImageError,
+    ImageFormat, Pixel, RgbImage, Rgba, RgbaImage,
+};
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, Cursor},
+    num::NonZeroU8,
+    path::Path,
+};
 
-use resize::px::RGB;
-use resize::Pixel::RGB8;
-use resize::Type::Lanczos3;
+use crate::error::{OptimizeError, Result};
+use crate::optimizer::Encoder;
+use resize::px::{RGB, RGBA};
+use resize::Pixel::{RGB8, RGBA8P};
+use resize::Type;
 use rgb::{ComponentBytes, FromSlice};
+use serde::Serialize;
+
+// Saliency is judged on a thumbnail, full resolution adds cost but not accuracy
+const SALIENCY_SIZE: u32 = 256;
+const PALETTE_SAMPLE_SIZE: u32 = 64;
+// Gap between a watermark and the edges, as a fraction of the output's shorter side
+const WATERMARK_MARGIN: f32 = 0.02;
+
+#[derive(Debug, ValueEnum, Clone, Copy, Default, Serialize)]
+pub enum ResizeFilter {
+    #[default]
+    Lanczos3,
+    #[value(name = "catmullrom")]
+    CatmullRom,
+    Mitchell,
+    Triangle,
+    Nearest,
+}
+
+impl ResizeFilter {
+    fn kind(&self) -> Type {
+        match self {
+            ResizeFilter::Lanczos3 => Type::Lanczos3,
+            ResizeFilter::CatmullRom => Type::Catrom,
+            ResizeFilter::Mitchell => Type::Mitchell,
+            ResizeFilter::Triangle => Type::Triangle,
+            ResizeFilter::Nearest => Type::Point,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ResizeConfig {
@@ -12,9 +57,19 @@ pub struct ResizeConfig {
     pub src_width: usize,
     pub dest_height: usize,
     pub dest_width: usize,
+    pub filter: ResizeFilter,
 }
 
-pub fn resize(img: &Vec<u8>, config: ResizeConfig) -> anyhow::Result<Vec<u8>> {
+pub fn resize(img: &[u8], config: ResizeConfig) -> Result<Vec<u8>> {
+    let expected_len = config.src_width * config.src_height * 3;
+    if img.len() != expected_len {
+        return Err(OptimizeError::Resize(format!(
+            "Expected {expected_len} bytes for a {}x{} RGB image, got {}",
+            config.src_width,
+            config.src_height,
+            img.len()
+        )));
+    }
     let mut dst = vec![RGB::new(0, 0, 0); config.dest_width * config.dest_height];
     let mut resizer = resize::new(
         config.src_width,
@@ -22,43 +77,1674 @@ pub fn resize(img: &Vec<u8>, config: ResizeConfig) -> anyhow::Result<Vec<u8>> {
         config.dest_width,
         config.dest_height,
         RGB8,
-        Lanczos3,
+        config.filter.kind(),
     )
-    .map_err(|_| anyhow!("Error creating resizer"))?;
+    .map_err(|_| OptimizeError::Resize("Error creating resizer".to_string()))?;
 
     resizer
         .resize(img.as_rgb(), &mut dst)
-        .map_err(|_| anyhow!("Error resizing image"))?;
+        .map_err(|_| OptimizeError::Resize("Error resizing image".to_string()))?;
 
     let resized_image_as_u8 = dst.as_bytes();
 
     Ok(resized_image_as_u8.to_vec())
 }
 
+pub fn convert_to_srgb(img: DynamicImage, icc_profile: &[u8]) -> Result<DynamicImage> {
+    let source_profile = qcms::Profile::new_from_slice(icc_profile, false).ok_or(
+        OptimizeError::ColorProfile("Unable to parse the embedded ICC profile".to_string()),
+    )?;
+    let srgb = qcms::Profile::new_sRGB();
+    let data_type = if img.color().has_alpha() {
+        qcms::DataType::RGBA8
+    } else {
+        qcms::DataType::RGB8
+    };
+    let transform = qcms::Transform::new(&source_profile, &srgb, data_type, Default::default())
+        .ok_or(OptimizeError::ColorProfile(
+            "Unable to build an sRGB transform for the ICC profile".to_string(),
+        ))?;
+    if data_type == qcms::DataType::RGBA8 {
+        let mut rgba = img.to_rgba8();
+        transform.apply(&mut rgba);
+        Ok(DynamicImage::ImageRgba8(rgba))
+    } else {
+        let mut rgb = img.to_rgb8();
+        transform.apply(&mut rgb);
+        Ok(DynamicImage::ImageRgb8(rgb))
+    }
+}
+
+pub fn parse_hex_color(hex: &str) -> Result<[u8; 3]> {
+    let invalid =
+        || OptimizeError::InvalidOption(format!("Expected a #rgb or #rrggbb color, got {hex}"));
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if !digits.is_ascii() {
+        return Err(invalid());
+    }
+    let digits = match digits.len() {
+        3 => digits.chars().flat_map(|c| [c, c]).collect(),
+        6 => digits.to_string(),
+        _ => return Err(invalid()),
+    };
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| invalid());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+pub fn format_hex_color([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+pub fn parse_byte_size(size: &str) -> Result<usize> {
+    let invalid = || {
+        OptimizeError::InvalidOption(format!(
+            "Expected a size such as 150000, 150k or 1.5m, got {size}"
+        ))
+    };
+    let lower = size.trim().to_ascii_lowercase();
+    let lower = lower.strip_suffix('b').unwrap_or(&lower);
+    let (number, multiplier) = if let Some(number) = lower.strip_suffix('k') {
+        (number, 1024.0)
+    } else if let Some(number) = lower.strip_suffix('m') {
+        (number, 1024.0 * 1024.0)
+    } else {
+        (lower, 1.0)
+    };
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(invalid());
+    }
+    Ok((number * multiplier) as usize)
+}
+
+// "50%" and "50" are percentages, as are "1" and "1.5". Only values below 1 such as "0.5" are
+// fractions of the source
+pub fn parse_scale(scale: &str) -> Result<f32> {
+    let invalid = || {
+        OptimizeError::InvalidOption(format!(
+            "Expected a scale such as 50%, 50 or 0.5, got {scale}"
+        ))
+    };
+    let (number, percent) = match scale.trim().strip_suffix('%') {
+        Some(number) => (number, true),
+        None => (scale.trim(), false),
+    };
+    let number: f32 = number.trim().parse().map_err(|_| invalid())?;
+    if !number.is_finite() {
+        return Err(invalid());
+    }
+    if percent || number <= 0.0 || number >= 1.0 {
+        Ok(number)
+    } else {
+        Ok(number * 100.0)
+    }
+}
+
+pub fn parse_dimensions(size: &str) -> Result<(usize, usize)> {
+    let invalid =
+        || OptimizeError::InvalidOption(format!("Expected dimensions such as 400x300, got {size}"));
+    let (width, height) = size.split_once(['x', 'X']).ok_or_else(invalid)?;
+    let width: usize = width.trim().parse().map_err(|_| invalid())?;
+    let height: usize = height.trim().parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+    Ok((width, height))
+}
+
+pub fn parse_focal_point(point: &str) -> Result<(f32, f32)> {
+    let invalid = || {
+        OptimizeError::InvalidOption(format!(
+            "Expected a focal point such as 0.3,0.6, got {point}"
+        ))
+    };
+    let (x, y) = point.split_once(',').ok_or_else(invalid)?;
+    let x: f32 = x.trim().parse().map_err(|_| invalid())?;
+    let y: f32 = y.trim().parse().map_err(|_| invalid())?;
+    if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+        return Err(invalid());
+    }
+    Ok((x, y))
+}
+
+// The largest window with the target's aspect ratio that fits in the source
+pub fn cover_size(src: (u32, u32), target: (usize, usize)) -> (u32, u32) {
+    let (src_w, src_h) = (src.0 as f64, src.1 as f64);
+    let target_ratio = target.0 as f64 / target.1 as f64;
+    let (crop_w, crop_h) = if src_w / src_h > target_ratio {
+        ((src_h * target_ratio).round().clamp(1.0, src_w), src_h)
+    } else {
+        (src_w, (src_w / target_ratio).round().clamp(1.0, src_h))
+    };
+    (crop_w as u32, crop_h as u32)
+}
+
+// `anchor` is the fraction of the spare width and height to the left of and above the window
+pub fn anchored_offset(src: (u32, u32), crop: (u32, u32), anchor: (f32, f32)) -> (u32, u32) {
+    let offset = |src: u32, crop: u32, anchor: f32| ((src - crop) as f32 * anchor).round() as u32;
+    (
+        offset(src.0, crop.0, anchor.0),
+        offset(src.1, crop.1, anchor.1),
+    )
+}
+
+// Centers the window on the focal point, sliding it back inside the source near the edges
+pub fn focal_offset(src: (u32, u32), crop: (u32, u32), focal: (f32, f32)) -> (u32, u32) {
+    let offset = |src: u32, crop: u32, focal: f32| {
+        let start = src as f32 * focal - crop as f32 / 2.0;
+        start.round().clamp(0.0, (src - crop) as f32) as u32
+    };
+    (
+        offset(src.0, crop.0, focal.0),
+        offset(src.1, crop.1, focal.1),
+    )
+}
+
+// Slides the window along whichever axis has room and keeps the position with the most edge detail
+pub fn salient_offset(img: &DynamicImage, crop: (u32, u32)) -> (u32, u32) {
+    let (src_w, src_h) = img.dimensions();
+    let small = img.thumbnail(SALIENCY_SIZE, SALIENCY_SIZE).to_luma8();
+    let (small_w, small_h) = small.dimensions();
+    let luma = |x: u32, y: u32| small.get_pixel(x, y).0[0] as i32;
+
+    let horizontal = src_w - crop.0 > src_h - crop.1;
+    let (len, scale, window) = if horizontal {
+        let scale = small_w as f32 / src_w as f32;
+        (small_w, scale, crop.0)
+    } else {
+        let scale = small_h as f32 / src_h as f32;
+        (small_h, scale, crop.1)
+    };
+    let mut profile = vec![0i64; len as usize];
+    for y in 1..small_h.saturating_sub(1) {
+        for x in 1..small_w.saturating_sub(1) {
+            let energy =
+                (luma(x + 1, y) - luma(x - 1, y)).abs() + (luma(x, y + 1) - luma(x, y - 1)).abs();
+            let index = if horizontal { x } else { y };
+            profile[index as usize] += energy as i64;
+        }
+    }
+
+    let window = ((window as f32 * scale).round() as usize).clamp(1, len as usize);
+    let mut sum: i64 = profile[..window].iter().sum();
+    let (mut best, mut best_start) = (sum, 0);
+    for start in 1..=profile.len() - window {
+        sum += profile[start + window - 1] - profile[start - 1];
+        if sum > best {
+            (best, best_start) = (sum, start);
+        }
+    }
+
+    let start = (best_start as f32 / scale).round() as u32;
+    if horizontal {
+        (start.min(src_w - crop.0), 0)
+    } else {
+        (0, start.min(src_h - crop.1))
+    }
+}
+
+// The most common colors, most common first. Pixels are grouped into 4096 buckets of similar
+// colors and each bucket reports its average, transparent pixels don't count.
+pub fn palette(img: &DynamicImage, count: usize) -> Vec<[u8; 3]> {
+    let small = img
+        .thumbnail(PALETTE_SAMPLE_SIZE, PALETTE_SAMPLE_SIZE)
+        .to_rgba8();
+    let mut buckets = vec![[0u64; 4]; 4096];
+    for pixel in small.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+        let index = (r as usize >> 4) << 8 | (g as usize >> 4) << 4 | b as usize >> 4;
+        let bucket = &mut buckets[index];
+        bucket[0] += 1;
+        bucket[1] += r as u64;
+        bucket[2] += g as u64;
+        bucket[3] += b as u64;
+    }
+    buckets.retain(|bucket| bucket[0] > 0);
+    buckets.sort_by_key(|bucket| std::cmp::Reverse(bucket[0]));
+    buckets
+        .iter()
+        .take(count)
+        .map(|&[n, r, g, b]| [(r / n) as u8, (g / n) as u8, (b / n) as u8])
+        .collect()
+}
+
+// Multipliers, so 1.0 leaves a channel alone and a saturation of 0.0 is grayscale
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorAdjustments {
+    pub brightness: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+}
+
+impl Default for ColorAdjustments {
+    fn default() -> ColorAdjustments {
+        ColorAdjustments {
+            brightness: 1.0,
+            contrast: 1.0,
+            saturation: 1.0,
+        }
+    }
+}
+
+fn adjust_pixels(pixels: &mut [u8], channels: usize, adjustments: &ColorAdjustments) {
+    for pixel in pixels.chunks_exact_mut(channels) {
+        let luma = 0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32;
+        for channel in &mut pixel[..3] {
+            let saturated = luma + (*channel as f32 - luma) * adjustments.saturation;
+            let contrasted = (saturated - 128.0) * adjustments.contrast + 128.0;
+            *channel = (contrasted * adjustments.brightness)
+                .round()
+                .clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+// Keeps the image's RGB or RGBA layout, which everything after resizing relies on
+pub fn adjust_colors(img: &mut DynamicImage, adjustments: &ColorAdjustments) {
+    if *adjustments == ColorAdjustments::default() {
+        return;
+    }
+    match img {
+        DynamicImage::ImageRgb8(rgb) => adjust_pixels(rgb, 3, adjustments),
+        DynamicImage::ImageRgba8(rgba) => adjust_pixels(rgba, 4, adjustments),
+        other if other.color().has_alpha() => {
+            *other = DynamicImage::ImageRgba8(other.to_rgba8());
+            adjust_colors(other, adjustments);
+        }
+        other => {
+            *other = DynamicImage::ImageRgb8(other.to_rgb8());
+            adjust_colors(other, adjustments);
+        }
+    }
+}
+
+pub struct Watermark {
+    pub image: RgbaImage,
+    pub anchor: (f32, f32),
+    pub opacity: f32,
+}
+
+// `scale` is the output's size relative to the source, so the watermark keeps the proportions
+// it would have on the full size image
+pub fn apply_watermark(img: &mut DynamicImage, watermark: &Watermark, scale: f64) {
+    let (width, height) = img.dimensions();
+    let margin = (width.min(height) as f32 * WATERMARK_MARGIN).round() as u32;
+    let area = (width - 2 * margin, height - 2 * margin);
+    let (mark_w, mark_h) = watermark.image.dimensions();
+    let fit = (area.0 as f64 / mark_w as f64).min(area.1 as f64 / mark_h as f64);
+    let scale = scale.min(fit);
+    let size = |length: u32| ((length as f64 * scale).round() as u32).max(1);
+    let mut mark = imageops::resize(
+        &watermark.image,
+        size(mark_w),
+        size(mark_h),
+        imageops::FilterType::Triangle,
+    );
+    for pixel in mark.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * watermark.opacity).round() as u8;
+    }
+
+    let (x, y) = anchored_offset(area, mark.dimensions(), watermark.anchor);
+    let (x, y) = ((x + margin) as i64, (y + margin) as i64);
+    if let DynamicImage::ImageRgba8(rgba) = img {
+        imageops::overlay(rgba, &mark, x, y);
+    } else {
+        let mut rgba = img.to_rgba8();
+        imageops::overlay(&mut rgba, &mark, x, y);
+        *img = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8());
+    }
+}
+
+// Blends straight into the RGB output, so an RGBA source is read in place rather than copied
+pub fn flatten(img: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let rgba = rgba8_pixels(img);
+    let mut rgb = RgbImage::new(img.width(), img.height());
+    for (out, pixel) in rgb.pixels_mut().zip(rgba.chunks_exact(4)) {
+        let alpha = pixel[3] as u32;
+        for channel in 0..3 {
+            let blended =
+                pixel[channel] as u32 * alpha + background[channel] as u32 * (255 - alpha);
+            out[channel] = ((blended + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
+
+pub fn has_alpha(img: &[u8], width: usize, height: usize) -> bool {
+    // Buffers are tightly packed, so the length tells RGB and RGBA apart
+    img.len() == width * height * 4
+}
+
+// A 4x4 Bayer matrix spreads the rounding error of dropping bits, so smooth gradients don't band
+const BAYER_4X4: [[f32; 4]; 4] = [
+    [0.0, 8.0, 2.0, 10.0],
+    [12.0, 4.0, 14.0, 6.0],
+    [3.0, 11.0, 1.0, 9.0],
+    [15.0, 7.0, 13.0, 5.0],
+];
+
+fn dither(value: f32, x: u32, y: u32) -> u8 {
+    let threshold = (BAYER_4X4[y as usize % 4][x as usize % 4] + 0.5) / 16.0 - 0.5;
+    (value * 255.0 + threshold).round().clamp(0.0, 255.0) as u8
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// HDR sources are linear light, Reinhard on the luminance rolls highlights off instead of clipping
+fn tonemap(rgb: [f32; 3]) -> [f32; 3] {
+    let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+    if luma <= 0.0 {
+        return [0.0; 3];
+    }
+    let scale = 1.0 / (1.0 + luma);
+    rgb.map(|channel| linear_to_srgb((channel * scale).clamp(0.0, 1.0)))
+}
+
+// 16-bit and floating point sources are brought down to 8 bits once, right after decoding
+pub fn reduce_to_8_bit(img: DynamicImage) -> DynamicImage {
+    let hdr = matches!(
+        img,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    );
+    let high_bit_depth = matches!(
+        img,
+        DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)
+    );
+    if !hdr && !high_bit_depth {
+        return img;
+    }
+    let has_alpha = img.color().has_alpha();
+    let src = img.into_rgba32f();
+    let mut rgba = RgbaImage::new(src.width(), src.height());
+    for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+        let [r, g, b, a] = src.get_pixel(x, y).0;
+        let rgb = if hdr { tonemap([r, g, b]) } else { [r, g, b] };
+        let [r, g, b] = rgb.map(|channel| dither(channel, x, y));
+        *pixel = Rgba([r, g, b, (a.clamp(0.0, 1.0) * 255.0).round() as u8]);
+    }
+    if has_alpha {
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).into_rgb8())
+    }
+}
+
+pub fn to_rgba8_bytes(img: &DynamicImage) -> (Vec<u8>, u32, u32) {
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    (rgba.into_raw(), width, height)
+}
+
+pub fn to_rgb8_bytes(img: &DynamicImage) -> (Vec<u8>, u32, u32) {
+    // Everything downstream assumes tightly packed 3-byte RGB pixels
+    let rgb = img.to_rgb8();
+    let (width, height) = rgb.dimensions();
+    (rgb.into_raw(), width, height)
+}
+
+// Borrows the pixels when the image already has the layout the resizer wants, a copy of a
+// 100 megapixel source is 300MB per target otherwise
+fn rgb8_pixels(img: &DynamicImage) -> Cow<'_, [u8]> {
+    match img {
+        DynamicImage::ImageRgb8(rgb) => Cow::Borrowed(rgb.as_raw()),
+        other => Cow::Owned(other.to_rgb8().into_raw()),
+    }
+}
+
+fn rgba8_pixels(img: &DynamicImage) -> Cow<'_, [u8]> {
+    match img {
+        DynamicImage::ImageRgba8(rgba) => Cow::Borrowed(rgba.as_raw()),
+        other => Cow::Owned(other.to_rgba8().into_raw()),
+    }
+}
+
+// Averages factor x factor blocks, taking the source a row at a time. Colors are weighted by alpha
+// so transparent pixels don't bleed into their neighbours.
+struct BoxSums {
+    sums: Vec<u32>,
+    channels: usize,
+    factor: usize,
+}
+
+impl BoxSums {
+    fn new(out_w: usize, channels: usize, factor: usize) -> BoxSums {
+        BoxSums {
+            sums: vec![0; out_w * channels],
+            channels,
+            factor,
+        }
+    }
+
+    // One source row, of exactly factor pixels per output pixel
+    fn add_row(&mut self, row: &[u8]) {
+        let channels = self.channels;
+        for (x, pixel) in row.chunks_exact(channels).enumerate() {
+            let sum = &mut self.sums[x / self.factor * channels..][..channels];
+            let weight = if channels == 4 { pixel[3] as u32 } else { 1 };
+            for c in 0..3 {
+                sum[c] += pixel[c] as u32 * weight;
+            }
+            if channels == 4 {
+                sum[3] += weight;
+            }
+        }
+    }
+
+    // Writes the averages of the last factor rows and starts the next output row
+    fn finish_row(&mut self, out_row: &mut [u8]) {
+        let channels = self.channels;
+        let area = (self.factor * self.factor) as u32;
+        for (pixel, sum) in out_row
+            .chunks_exact_mut(channels)
+            .zip(self.sums.chunks_exact(channels))
+        {
+            let weight = if channels == 4 { sum[3] } else { area };
+            for c in 0..3 {
+                pixel[c] = (sum[c] + weight / 2).checked_div(weight).unwrap_or(0) as u8;
+            }
+            if channels == 4 {
+                pixel[3] = ((sum[3] + area / 2) / area) as u8;
+            }
+        }
+        self.sums.fill(0);
+    }
+}
+
+fn box_shrink(
+    pixels: &[u8],
+    (width, height): (usize, usize),
+    channels: usize,
+    factor: usize,
+) -> (Vec<u8>, usize, usize) {
+    let (out_w, out_h) = (width / factor, height / factor);
+    let mut out = vec![0; out_w * out_h * channels];
+    let mut sums = BoxSums::new(out_w, channels, factor);
+    for (out_y, out_row) in out.chunks_exact_mut(out_w * channels).enumerate() {
+        for y in out_y * factor..(out_y + 1) * factor {
+            sums.add_row(&pixels[y * width * channels..][..out_w * factor * channels]);
+        }
+        sums.finish_row(out_row);
+    }
+    (out, out_w, out_h)
+}
+
+// Decodes a PNG a row at a time and box shrinks it by factor on the way, so only a few source rows
+// are ever held. Edge pixels short of a whole block are dropped, as box_shrink does. None for
+// interlaced PNGs, whose rows arrive in passes.
+pub fn decode_png_shrunk(png: &[u8], factor: usize) -> Result<Option<DynamicImage>> {
+    let decode_error = |err: png::DecodingError| {
+        OptimizeError::Decode(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(ImageFormat::Png),
+            err,
+        )))
+    };
+    let mut decoder = png::Decoder::new(png);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(decode_error)?;
+    let info = reader.info();
+    let (width, height) = (info.width as usize, info.height as usize);
+    let (out_w, out_h) = (width / factor, height / factor);
+    if info.interlaced || out_w == 0 || out_h == 0 {
+        return Ok(None);
+    }
+    let samples = reader.output_color_type().0.samples();
+    // Gray widens to RGB, and anything with alpha or tRNS comes out as RGBA
+    let channels = if samples % 2 == 0 { 4 } else { 3 };
+    let mut row = vec![0; out_w * factor * channels];
+    let mut out = vec![0; out_w * out_h * channels];
+    let mut sums = BoxSums::new(out_w, channels, factor);
+    for out_row in out.chunks_exact_mut(out_w * channels) {
+        for _ in 0..factor {
+            let source = reader.next_row().map_err(decode_error)?.ok_or_else(|| {
+                decode_error(png::DecodingError::IoError(
+                    io::ErrorKind::UnexpectedEof.into(),
+                ))
+            })?;
+            for (pixel, source) in row
+                .chunks_exact_mut(channels)
+                .zip(source.data().chunks_exact(samples))
+            {
+                match source {
+                    [gray] => pixel.copy_from_slice(&[*gray; 3]),
+                    [gray, alpha] => pixel.copy_from_slice(&[*gray, *gray, *gray, *alpha]),
+                    color => pixel.copy_from_slice(color),
+                }
+            }
+            sums.add_row(&row);
+        }
+        sums.finish_row(out_row);
+    }
+    let (out_w, out_h) = (out_w as u32, out_h as u32);
+    Ok(Some(if channels == 4 {
+        DynamicImage::ImageRgba8(RgbaImage::from_raw(out_w, out_h, out).unwrap())
+    } else {
+        DynamicImage::ImageRgb8(RgbImage::from_raw(out_w, out_h, out).unwrap())
+    }))
+}
+
+// The filtered resize costs time and scratch memory in proportion to the source, so sources many
+// times larger than the target are box shrunk first, to no less than twice the target
+fn shrink_for_target<'a>(
+    pixels: Cow<'a, [u8]>,
+    (width, height): (usize, usize),
+    (target_w, target_h): (usize, usize),
+    channels: usize,
+) -> (Cow<'a, [u8]>, usize, usize) {
+    let factor = (width / (target_w * 2))
+        .min(height / (target_h * 2))
+        .min(16);
+    if factor < 2 {
+        return (pixels, width, height);
+    }
+    let (shrunk, width, height) = box_shrink(&pixels, (width, height), channels, factor);
+    (Cow::Owned(shrunk), width, height)
+}
+
+pub fn resize_rgba(img: &[u8], config: ResizeConfig) -> Result<Vec<u8>> {
+    let expected_len = config.src_width * config.src_height * 4;
+    if img.len() != expected_len {
+        return Err(OptimizeError::Resize(format!(
+            "Expected {expected_len} bytes for a {}x{} RGBA image, got {}",
+            config.src_width,
+            config.src_height,
+            img.len()
+        )));
+    }
+    let mut dst = vec![RGBA::new(0, 0, 0, 0); config.dest_width * config.dest_height];
+    // Resize with premultiplied alpha so transparent pixels don't bleed colour
+    let mut resizer = resize::new(
+        config.src_width,
+        config.src_height,
+        config.dest_width,
+        config.dest_height,
+        RGBA8P,
+        config.filter.kind(),
+    )
+    .map_err(|_| OptimizeError::Resize("Error creating resizer".to_string()))?;
+
+    resizer
+        .resize(img.as_rgba(), &mut dst)
+        .map_err(|_| OptimizeError::Resize("Error resizing image".to_string()))?;
+
+    Ok(dst.as_bytes().to_vec())
+}
+
+pub fn resize_image_rgba(
+    img: &DynamicImage,
+    width: usize,
+    height: usize,
+    filter: ResizeFilter,
+) -> Result<DynamicImage> {
+    let (src_width, src_height) = img.dimensions();
+    let (rgba, src_width, src_height) = shrink_for_target(
+        rgba8_pixels(img),
+        (src_width as usize, src_height as usize),
+        (width, height),
+        4,
+    );
+    let resize_config = ResizeConfig {
+        src_height,
+        src_width,
+        dest_height: height,
+        dest_width: width,
+        filter,
+    };
+    let resized = resize_rgba(&rgba, resize_config)?;
+    let buffer = RgbaImage::from_raw(width as u32, height as u32, resized).ok_or(
+        OptimizeError::Resize("Resized buffer does not match target dimensions".to_string()),
+    )?;
+    Ok(DynamicImage::ImageRgba8(buffer))
+}
+
+pub fn resize_image(
+    img: &DynamicImage,
+    width: usize,
+    height: usize,
+    filter: ResizeFilter,
+) -> Result<DynamicImage> {
+    let (src_width, src_height) = img.dimensions();
+    let (rgb, src_width, src_height) = shrink_for_target(
+        rgb8_pixels(img),
+        (src_width as usize, src_height as usize),
+        (width, height),
+        3,
+    );
+    let resize_config = ResizeConfig {
+        src_height,
+        src_width,
+        dest_height: height,
+        dest_width: width,
+        filter,
+    };
+    let resized = resize(&rgb, resize_config)?;
+    let buffer = RgbImage::from_raw(width as u32, height as u32, resized).ok_or(
+        OptimizeError::Resize("Resized buffer does not match target dimensions".to_string()),
+    )?;
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+fn unsharp_mask<P>(original: &ImageBuffer<P, Vec<u8>>, amount: f32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    // Push each channel away from its blurred neighbourhood
+    let blurred = image::imageops::blur(original, 1.0);
+    let mut sharpened = original.clone();
+    for (channel, &blur) in sharpened.iter_mut().zip(blurred.iter()) {
+        let orig = *channel as f32;
+        *channel = (orig + amount * (orig - blur as f32))
+            .round()
+            .clamp(0.0, 255.0) as u8;
+    }
+    sharpened
+}
+
+pub fn sharpen(img: &DynamicImage, amount: f32) -> DynamicImage {
+    if amount <= 0.0 {
+        return img.clone();
+    }
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(unsharp_mask(&img.to_rgba8(), amount))
+    } else {
+        DynamicImage::ImageRgb8(unsharp_mask(&img.to_rgb8(), amount))
+    }
+}
+
+pub fn encode_blurhash(img: &DynamicImage) -> Result<String> {
+    let (width, height) = img.dimensions();
+    blurhash::encode(4, 3, width, height, img.to_rgba8().as_raw())
+        .map_err(|err| OptimizeError::Placeholder(format!("Error computing BlurHash: {err:?}")))
+}
+
+// ThumbHash caps its input at 100x100, callers shrink the image first
+pub fn encode_thumbhash(img: &DynamicImage) -> String {
+    let (width, height) = img.dimensions();
+    let hash =
+        thumbhash::rgba_to_thumb_hash(width as usize, height as usize, img.to_rgba8().as_raw());
+    STANDARD.encode(hash)
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataSegment {
+    pub marker: u8,
+    pub data: Vec<u8>,
+}
+
+pub const APP1_MARKER: u8 = 0xE1;
+pub const APP2_MARKER: u8 = 0xE2;
+pub const APP13_MARKER: u8 = 0xED;
+pub const COM_MARKER: u8 = 0xFE;
+
+fn cmyk_to_rgb(cmyk: &[u8]) -> Vec<u8> {
+    cmyk.chunks_exact(4)
+        .flat_map(|pixel| {
+            let k = 255 - pixel[3] as u16;
+            [0, 1, 2].map(|i| ((255 - pixel[i] as u16) * k / 255) as u8)
+        })
+        .collect()
+}
+
+// CMYK and YCCK JPEGs from print workflows come out of the decoder as CMYK. They are converted
+// through their embedded profile when there is one, which is then dropped along with the ink.
+pub fn decode_cmyk_jpeg(
+    jpeg: &[u8],
+    metadata: &mut Vec<MetadataSegment>,
+) -> Result<Option<DynamicImage>> {
+    let decode_error = |err: jpeg_decoder::Error| {
+        OptimizeError::Decode(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(ImageFormat::Jpeg),
+            err,
+        )))
+    };
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(jpeg));
+    decoder.read_info().map_err(decode_error)?;
+    let Some(info) = decoder.info() else {
+        return Ok(None);
+    };
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Ok(None);
+    }
+    let cmyk = decoder.decode().map_err(decode_error)?;
+
+    let transform = icc_profile(metadata)
+        .and_then(|icc| qcms::Profile::new_from_slice(&icc, false))
+        .and_then(|profile| {
+            let srgb = qcms::Profile::new_sRGB();
+            qcms::Transform::new_to(
+                &profile,
+                &srgb,
+                qcms::DataType::CMYK,
+                qcms::DataType::RGB8,
+                Default::default(),
+            )
+        });
+    let rgb = match transform {
+        Some(transform) => {
+            let mut rgb = vec![0; cmyk.len() / 4 * 3];
+            transform.convert(&cmyk, &mut rgb);
+            rgb
+        }
+        None => cmyk_to_rgb(&cmyk),
+    };
+    metadata.retain(|segment| segment.marker != APP2_MARKER);
+
+    let img = RgbImage::from_raw(info.width as u32, info.height as u32, rgb).ok_or(
+        OptimizeError::Unreachable("CMYK pixel data doesn't match its dimensions".to_string()),
+    )?;
+    Ok(Some(DynamicImage::ImageRgb8(img)))
+}
+
+// Checked against header dimensions, before anything is allocated for the pixels
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_pixels: Option<u64>,
+    pub max_memory: Option<u64>,
+}
+
+impl DecodeLimits {
+    pub fn check(&self, (width, height): (u32, u32)) -> Result<()> {
+        let pixels = width as u64 * height as u64;
+        let too_large = |limit| OptimizeError::TooLarge {
+            width,
+            height,
+            limit,
+        };
+        if let Some(max_pixels) = self.max_pixels.filter(|&max| pixels > max) {
+            return Err(too_large(format!("{max_pixels} pixels")));
+        }
+        // Decoded as 8-bit RGBA at worst, by the time anything is resized
+        if let Some(max_memory) = self.max_memory.filter(|&max| pixels * 4 > max) {
+            return Err(too_large(format!("{max_memory} bytes")));
+        }
+        Ok(())
+    }
+
+    pub fn image_limits(&self) -> image::io::Limits {
+        let mut limits = image::io::Limits::default();
+        if let Some(max_memory) = self.max_memory {
+            limits.max_alloc = Some(max_memory);
+        }
+        limits
+    }
+}
+
+// HEIF files are ISO boxes, an ftyp box first whose major brand says what's inside
+pub fn is_heif(buffer: &[u8]) -> bool {
+    const HEIF_BRANDS: [&[u8]; 8] = [
+        b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+    ];
+    buffer.get(4..8) == Some(b"ftyp".as_slice())
+        && buffer
+            .get(8..12)
+            .is_some_and(|brand| HEIF_BRANDS.contains(&brand))
+}
+
+// libheif applies the rotation and mirroring boxes itself, so there's no orientation left to fix
+#[cfg(feature = "heif")]
+pub fn decode_heif(
+    heif: &[u8],
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, Vec<MetadataSegment>)> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let decode_error = |err: libheif_rs::HeifError| {
+        OptimizeError::Decode(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name("HEIF".to_string()),
+            err,
+        )))
+    };
+    let context = HeifContext::read_from_bytes(heif).map_err(decode_error)?;
+    let handle = context.primary_image_handle().map_err(decode_error)?;
+    limits.check((handle.width(), handle.height()))?;
+    let (chroma, channels) = if handle.has_alpha_channel() {
+        (RgbChroma::Rgba, 4)
+    } else {
+        (RgbChroma::Rgb, 3)
+    };
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(decode_error)?;
+    let planes = image.planes();
+    let plane = planes.interleaved.ok_or(OptimizeError::Unreachable(
+        "Decoded HEIF image has no interleaved plane".to_string(),
+    ))?;
+
+    // Rows may be padded past the last pixel
+    let row_len = plane.width as usize * channels;
+    let pixels: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+    let mismatch =
+        || OptimizeError::Unreachable("HEIF pixel data doesn't match its dimensions".to_string());
+    let img = if channels == 4 {
+        let rgba = RgbaImage::from_raw(plane.width, plane.height, pixels).ok_or_else(mismatch)?;
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        let rgb = RgbImage::from_raw(plane.width, plane.height, pixels).ok_or_else(mismatch)?;
+        DynamicImage::ImageRgb8(rgb)
+    };
+
+    // Phones embed Display P3, carried along like a JPEG's profile so it gets converted the same way
+    let metadata = handle
+        .color_profile_raw()
+        .map(|profile| icc_segments(&profile.data))
+        .unwrap_or_default();
+    Ok((img, metadata))
+}
+
+pub fn is_svg(img_src: &str, buffer: &[u8]) -> bool {
+    let extension = Path::new(img_src).extension();
+    if extension.is_some_and(|ext| ext.eq_ignore_ascii_case("svg")) {
+        return true;
+    }
+    let start = &buffer[..buffer.len().min(256)];
+    let start = String::from_utf8_lossy(start);
+    let start = start.trim_start();
+    start.starts_with("<svg") || (start.starts_with("<?xml") && start.contains("<svg"))
+}
+
+fn parse_svg(svg: &[u8]) -> Result<resvg::usvg::Tree> {
+    use resvg::usvg::{self, TreeParsing};
+
+    usvg::Tree::from_data(svg, &usvg::Options::default()).map_err(|err| {
+        OptimizeError::Decode(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name("SVG".to_string()),
+            err,
+        )))
+    })
+}
+
+pub fn svg_size(svg: &[u8]) -> Result<(u32, u32)> {
+    let size = parse_svg(svg)?.size;
+    Ok((
+        (size.width().round() as u32).max(1),
+        (size.height().round() as u32).max(1),
+    ))
+}
+
+// Stretches the drawing to fill width x height, callers keep the aspect ratio if they want it
+pub fn render_svg(svg: &[u8], width: u32, height: u32) -> Result<RgbaImage> {
+    use resvg::tiny_skia;
+
+    let tree = parse_svg(svg)?;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or(OptimizeError::InvalidTarget(
+        format!("Unable to render an SVG at {width}x{height}"),
+    ))?;
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / tree.size.width(),
+        height as f32 / tree.size.height(),
+    );
+    resvg::Tree::from_usvg(&tree).render(transform, &mut pixmap.as_mut());
+    // tiny-skia works in premultiplied alpha, everything here expects straight alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels).ok_or(OptimizeError::Unreachable(
+        "Rendered SVG doesn't match its dimensions".to_string(),
+    ))
+}
+
+// The inverse of icc_profile, split into APP2 segments of at most 64KB each
+pub fn icc_segments(icc: &[u8]) -> Vec<MetadataSegment> {
+    const CHUNK_LEN: usize = 65519;
+    let count = icc.len().div_ceil(CHUNK_LEN);
+    icc.chunks(CHUNK_LEN)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut data = b"ICC_PROFILE\0".to_vec();
+            data.extend_from_slice(&[i as u8 + 1, count as u8]);
+            data.extend_from_slice(chunk);
+            MetadataSegment {
+                marker: APP2_MARKER,
+                data,
+            }
+        })
+        .collect()
+}
+
+pub fn read_jpeg_metadata(jpeg: &[u8]) -> Vec<MetadataSegment> {
+    let mut segments = vec![];
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return segments;
+    }
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+        let marker = jpeg[pos + 1];
+        // Metadata segments all precede the start of scan
+        if marker == 0xDA {
+            break;
+        }
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > jpeg.len() {
+            break;
+        }
+        if (APP1_MARKER..=0xEF).contains(&marker) || marker == COM_MARKER {
+            segments.push(MetadataSegment {
+                marker,
+                data: jpeg[pos + 4..end].to_vec(),
+            });
+        }
+        pos = end;
+    }
+    segments
+}
+
+pub fn icc_profile(segments: &[MetadataSegment]) -> Option<Vec<u8>> {
+    // Large profiles are split across several APP2 segments with a sequence number
+    let mut chunks: Vec<(u8, &[u8])> = segments
+        .iter()
+        .filter(|segment| segment.marker == APP2_MARKER)
+        .filter_map(|segment| segment.data.strip_prefix(b"ICC_PROFILE\0"))
+        .filter(|data| data.len() > 2)
+        .map(|data| (data[0], &data[2..]))
+        .collect();
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|(sequence, _)| *sequence);
+    Some(
+        chunks
+            .into_iter()
+            .flat_map(|(_, data)| data.to_vec())
+            .collect(),
+    )
+}
+
+// Reads the EXIF Orientation tag and resets it to 1, as the pixels are about to be rotated
+pub fn take_exif_orientation(segments: &mut [MetadataSegment]) -> Option<u16> {
+    let tiff = segments
+        .iter_mut()
+        .filter(|segment| segment.marker == APP1_MARKER && segment.data.starts_with(b"Exif\0\0"))
+        .find_map(|segment| segment.data.get_mut(6..))?;
+    let big_endian = match tiff.get(0..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let read_u16 = |bytes: &[u8], pos: usize| -> Option<u16> {
+        let bytes = bytes.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |bytes: &[u8], pos: usize| -> Option<u32> {
+        let bytes = bytes.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = read_u32(tiff, 4)? as usize;
+    for index in 0..read_u16(tiff, ifd)? as usize {
+        let entry = ifd + 2 + index * 12;
+        if read_u16(tiff, entry)? != 0x0112 {
+            continue;
+        }
+        let value = entry + 8;
+        let orientation = read_u16(tiff, value)?;
+        let upright = if big_endian {
+            1u16.to_be_bytes()
+        } else {
+            1u16.to_le_bytes()
+        };
+        tiff[value..value + 2].copy_from_slice(&upright);
+        return Some(orientation);
+    }
+    None
+}
+
+pub fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+fn app1_payload<'a>(segments: &'a [MetadataSegment], prefix: &[u8]) -> Option<&'a [u8]> {
+    segments
+        .iter()
+        .filter(|segment| segment.marker == APP1_MARKER)
+        .find_map(|segment| segment.data.strip_prefix(prefix))
+}
+
+fn riff_chunks(webp: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return Err(OptimizeError::encode(
+            Encoder::WebP,
+            "Encoded image is not a WebP file",
+        ));
+    }
+    let mut chunks = vec![];
+    let mut pos = 12;
+    while pos + 8 <= webp.len() {
+        let fourcc = [webp[pos], webp[pos + 1], webp[pos + 2], webp[pos + 3]];
+        let size = u32::from_le_bytes([webp[pos + 4], webp[pos + 5], webp[pos + 6], webp[pos + 7]])
+            as usize;
+        let end = pos + 8 + size;
+        if end > webp.len() {
+            return Err(OptimizeError::encode(
+                Encoder::WebP,
+                "Encoded WebP has a truncated chunk",
+            ));
+        }
+        chunks.push((fourcc, &webp[pos + 8..end]));
+        // Chunks are padded to an even length
+        pos = end + size % 2;
+    }
+    Ok(chunks)
+}
+
+fn push_riff_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+pub fn embed_webp_metadata(
+    webp: &[u8],
+    width: u32,
+    height: u32,
+    segments: &[MetadataSegment],
+) -> Result<Vec<u8>> {
+    let icc = icc_profile(segments);
+    let exif = app1_payload(segments, b"Exif\0\0");
+    let xmp = app1_payload(segments, b"http://ns.adobe.com/xap/1.0/\0");
+    if icc.is_none() && exif.is_none() && xmp.is_none() {
+        return Ok(webp.to_vec());
+    }
+
+    let mut flags = 0u8;
+    let mut image_chunks = vec![];
+    for (fourcc, data) in riff_chunks(webp)? {
+        match &fourcc {
+            b"VP8X" => flags |= data.first().copied().unwrap_or_default(),
+            b"ICCP" | b"EXIF" | b"XMP " => {}
+            b"ALPH" => {
+                flags |= 0x10;
+                image_chunks.push((fourcc, data));
+            }
+            b"VP8L" if data.len() >= 5 && data[4] & 0x10 != 0 => {
+                flags |= 0x10;
+                image_chunks.push((fourcc, data));
+            }
+            _ => image_chunks.push((fourcc, data)),
+        }
+    }
+    if icc.is_some() {
+        flags |= 0x20;
+    }
+    if exif.is_some() {
+        flags |= 0x08;
+    }
+    if xmp.is_some() {
+        flags |= 0x04;
+    }
+
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let mut body = b"WEBP".to_vec();
+    push_riff_chunk(&mut body, b"VP8X", &vp8x);
+    if let Some(icc) = &icc {
+        push_riff_chunk(&mut body, b"ICCP", icc);
+    }
+    for (fourcc, data) in image_chunks {
+        push_riff_chunk(&mut body, &fourcc, data);
+    }
+    if let Some(exif) = exif {
+        push_riff_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = xmp {
+        push_riff_chunk(&mut body, b"XMP ", xmp);
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+pub struct Animation {
+    // Full-canvas frames with their display time in milliseconds
+    pub frames: Vec<(RgbaImage, u32)>,
+    pub loop_count: u16,
+}
+
+// GIFs without a NETSCAPE2.0 extension play once, GIF and WebP both use 0 for forever
+fn gif_loop_count(gif: &[u8]) -> u16 {
+    let marker = b"NETSCAPE2.0";
+    gif.windows(marker.len())
+        .position(|window| window == marker)
+        .and_then(|pos| gif.get(pos + marker.len()..pos + marker.len() + 4))
+        .filter(|block| block[0] == 3 && block[1] == 1)
+        .map_or(1, |block| u16::from_le_bytes([block[2], block[3]]))
+}
+
+pub fn decode_gif_animation(gif: &[u8]) -> Result<Option<Animation>> {
+    let decoder = GifDecoder::new(Cursor::new(gif))?;
+    let frames = decoder.into_frames().collect_frames()?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            (frame.into_buffer(), numer / denom.max(1))
+        })
+        .collect();
+    Ok(Some(Animation {
+        frames,
+        loop_count: gif_loop_count(gif),
+    }))
+}
+
+pub fn compress_animated_webp(
+    frames: &[(Vec<u8>, u32)],
+    width: u32,
+    height: u32,
+    quality: f32,
+    lossless: bool,
+    options: &WebPOptions,
+    loop_count: u16,
+) -> Result<Vec<u8>> {
+    // Animation and alpha flags, then the canvas size
+    let mut vp8x = vec![0x02 | 0x10, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    // Transparent background color, then the loop count
+    let mut anim = vec![0, 0, 0, 0];
+    anim.extend_from_slice(&loop_count.to_le_bytes());
+
+    let mut body = b"WEBP".to_vec();
+    push_riff_chunk(&mut body, b"VP8X", &vp8x);
+    push_riff_chunk(&mut body, b"ANIM", &anim);
+    for (rgba, duration) in frames {
+        let still = compress_webp(rgba, width, height, quality, lossless, options, &[])?;
+        // Every frame covers the whole canvas from the origin and replaces the previous one
+        let mut anmf = vec![0; 6];
+        anmf.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        anmf.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        anmf.extend_from_slice(&(*duration).min(0xFF_FFFF).to_le_bytes()[..3]);
+        anmf.push(0x02);
+        for (fourcc, data) in riff_chunks(&still)? {
+            if matches!(&fourcc, b"ALPH" | b"VP8 " | b"VP8L") {
+                push_riff_chunk(&mut anmf, &fourcc, data);
+            }
+        }
+        push_riff_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Serialize)]
+pub enum AvifBitDepth {
+    #[value(name = "8")]
+    Eight,
+    #[default]
+    #[value(name = "10")]
+    Ten,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Serialize)]
+pub enum ChromaSubsampling {
+    #[value(name = "444")]
+    Yuv444,
+    #[value(name = "422")]
+    Yuv422,
+    #[value(name = "420")]
+    Yuv420,
+}
+
+impl ChromaSubsampling {
+    // Sampling factors of the luma component, chroma always stays at 1x1
+    #[cfg(feature = "native")]
+    fn luma_factors(&self) -> (i32, i32) {
+        match self {
+            ChromaSubsampling::Yuv444 => (1, 1),
+            ChromaSubsampling::Yuv422 => (2, 1),
+            ChromaSubsampling::Yuv420 => (2, 2),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MozJpegOptions {
+    pub progressive: bool,
+    pub subsampling: Option<ChromaSubsampling>,
+    pub trellis_scans: bool,
+}
+
+#[cfg(feature = "native")]
 pub fn compress_mozjpeg(
     img: &[u8],
     width: usize,
     height: usize,
     quality: f32,
-) -> Result<Vec<u8>, anyhow::Error> {
+    options: &MozJpegOptions,
+    metadata: &[MetadataSegment],
+) -> Result<Vec<u8>> {
     std::panic::catch_unwind(|| {
         let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
 
         comp.set_size(width, height);
         comp.set_mem_dest();
         comp.set_quality(quality);
+        if options.progressive {
+            comp.set_progressive_mode();
+            comp.set_optimize_scans(true);
+        }
+        if let Some(subsampling) = options.subsampling {
+            let (h, v) = subsampling.luma_factors();
+            let luma = &mut comp.components_mut()[0];
+            luma.h_samp_factor = h;
+            luma.v_samp_factor = v;
+        }
+        comp.set_use_scans_in_trellis(options.trellis_scans);
         comp.start_compress();
 
+        for segment in metadata {
+            let marker = match segment.marker {
+                COM_MARKER => mozjpeg::Marker::COM,
+                marker => mozjpeg::Marker::APP(marker - 0xE0),
+            };
+            comp.write_marker(marker, &segment.data);
+        }
+
         assert!(comp.write_scanlines(img));
 
         comp.finish_compress();
         let jpeg_bytes = comp
             .data_to_vec()
-            .map_err(|_| anyhow!("Image compression failed"))?;
+            .map_err(|_| OptimizeError::encode(Encoder::MozJpeg, "Image compression failed"))?;
 
         Ok(jpeg_bytes)
     })
-    .map_err(|_| anyhow!("Error compressing image"))?
+    .map_err(|_| OptimizeError::encode(Encoder::MozJpeg, "the encoder panicked"))?
+}
+
+// The image crate's encoder stands in for mozjpeg where C can't be built. It has none of the
+// tuning options and writes no metadata.
+#[cfg(not(feature = "native"))]
+pub fn compress_mozjpeg(
+    img: &[u8],
+    width: usize,
+    height: usize,
+    quality: f32,
+    _options: &MozJpegOptions,
+    _metadata: &[MetadataSegment],
+) -> Result<Vec<u8>> {
+    let mut jpeg = vec![];
+    let quality = quality.round().clamp(1.0, 100.0) as u8;
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode(img, width as u32, height as u32, image::ColorType::Rgb8)
+        .map_err(|err| OptimizeError::encode(Encoder::MozJpeg, err))?;
+    Ok(jpeg)
+}
+
+#[cfg(not(feature = "native"))]
+fn native_only(encoder: Encoder) -> OptimizeError {
+    OptimizeError::encode(encoder, "not available without the native feature")
+}
+
+pub fn compress_avif(
+    img: &[u8],
+    width: usize,
+    height: usize,
+    quality: f32,
+    speed: u8,
+    bit_depth: AvifBitDepth,
+) -> Result<Vec<u8>> {
+    let bit_depth = match bit_depth {
+        AvifBitDepth::Eight => ravif::BitDepth::Eight,
+        AvifBitDepth::Ten => ravif::BitDepth::Ten,
+    };
+    let encoder = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_speed(speed)
+        .with_bit_depth(bit_depth);
+    let encoded = if has_alpha(img, width, height) {
+        encoder.encode_rgba(ravif::Img::new(img.as_rgba(), width, height))
+    } else {
+        encoder.encode_rgb(ravif::Img::new(img.as_rgb(), width, height))
+    }
+    .map_err(|err| OptimizeError::encode(Encoder::Avif, err))?;
+    Ok(encoded.avif_file)
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PngOptions {
+    pub zopfli: bool,
+    pub max_colors: Option<u32>,
+    pub dithering: Option<f32>,
+}
+
+fn quantize(
+    img: &[u8],
+    width: usize,
+    height: usize,
+    max_colors: u32,
+    dithering: f32,
+) -> Result<(Vec<RGBA<u8>>, Vec<u8>)> {
+    let rgba: Vec<RGBA<u8>> = if has_alpha(img, width, height) {
+        img.as_rgba().to_vec()
+    } else {
+        img.as_rgb()
+            .iter()
+            .map(|px| RGBA::new(px.r, px.g, px.b, 255))
+            .collect()
+    };
+    let png_error = |err: imagequant::Error| OptimizeError::encode(Encoder::Png, err);
+    let mut attributes = imagequant::new();
+    attributes.set_max_colors(max_colors).map_err(png_error)?;
+    let mut image = attributes
+        .new_image_borrowed(&rgba, width, height, 0.0)
+        .map_err(png_error)?;
+    let mut quantized = attributes.quantize(&mut image).map_err(png_error)?;
+    quantized
+        .set_dithering_level(dithering)
+        .map_err(png_error)?;
+    quantized.remapped(&mut image).map_err(png_error)
+}
+
+pub fn compress_png(
+    img: &[u8],
+    width: usize,
+    height: usize,
+    options: &PngOptions,
+) -> Result<Vec<u8>> {
+    let png_error = |err: oxipng::PngError| OptimizeError::encode(Encoder::Png, err);
+    let raw = match options.max_colors {
+        Some(max_colors) => {
+            let dithering = options.dithering.unwrap_or(1.0);
+            let (palette, indices) = quantize(img, width, height, max_colors, dithering)?;
+            oxipng::RawImage::new(
+                width as u32,
+                height as u32,
+                oxipng::ColorType::Indexed { palette },
+                oxipng::BitDepth::Eight,
+                indices,
+            )
+            .map_err(png_error)?
+        }
+        None => oxipng::RawImage::new(
+            width as u32,
+            height as u32,
+            if has_alpha(img, width, height) {
+                oxipng::ColorType::RGBA
+            } else {
+                oxipng::ColorType::RGB {
+                    transparent_color: None,
+                }
+            },
+            oxipng::BitDepth::Eight,
+            img.to_vec(),
+        )
+        .map_err(png_error)?,
+    };
+    let mut oxipng_options = oxipng::Options::from_preset(2);
+    if options.zopfli {
+        oxipng_options.deflate = oxipng::Deflaters::Zopfli {
+            iterations: NonZeroU8::new(15).unwrap(),
+        };
+    }
+    raw.create_optimized_png(&oxipng_options).map_err(png_error)
+}
+
+#[cfg(feature = "native")]
+fn jxl_distance(quality: f32) -> f32 {
+    // Same mapping as libjxl's JxlEncoderDistanceFromQuality
+    if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        53.0 / 3000.0 * quality * quality - 23.0 / 20.0 * quality + 25.0
+    }
+}
+
+#[cfg(feature = "native")]
+fn jxl_error(err: jpegxl_rs::EncodeError) -> OptimizeError {
+    OptimizeError::encode(Encoder::JpegXl, err)
+}
+
+#[cfg(feature = "native")]
+pub fn compress_jpegxl(
+    img: &[u8],
+    width: usize,
+    height: usize,
+    quality: f32,
+    lossless: bool,
+) -> Result<Vec<u8>> {
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .has_alpha(has_alpha(img, width, height))
+        .lossless(lossless)
+        .quality(jxl_distance(quality))
+        .build()
+        .map_err(jxl_error)?;
+    let encoded: jpegxl_rs::encode::EncoderResult<u8> = encoder
+        .encode(img, width as u32, height as u32)
+        .map_err(jxl_error)?;
+    Ok(encoded.data)
+}
+
+#[cfg(feature = "native")]
+pub fn transcode_jpeg_to_jxl(jpeg: &[u8]) -> Result<Vec<u8>> {
+    // The container keeps the reconstruction data needed to restore the original JPEG
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .use_container(true)
+        .build()
+        .map_err(jxl_error)?;
+    Ok(encoder.encode_jpeg(jpeg).map_err(jxl_error)?.data)
+}
+
+#[cfg(not(feature = "native"))]
+pub fn compress_jpegxl(
+    _img: &[u8],
+    _width: usize,
+    _height: usize,
+    _quality: f32,
+    _lossless: bool,
+) -> Result<Vec<u8>> {
+    Err(native_only(Encoder::JpegXl))
+}
+
+#[cfg(not(feature = "native"))]
+pub fn transcode_jpeg_to_jxl(_jpeg: &[u8]) -> Result<Vec<u8>> {
+    Err(native_only(Encoder::JpegXl))
+}
+
+pub fn is_output_up_to_date(src: &Path, output: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(src), modified(output)) {
+        (Ok(src_modified), Ok(output_modified)) => src_modified <= output_modified,
+        _ => false,
+    }
+}
+
+pub struct NameFields<'a> {
+    pub stem: &'a str,
+    pub width: usize,
+    pub height: usize,
+    pub quality: Option<f32>,
+    pub encoder: Option<&'a str>,
+    pub density: &'a str,
+    pub ext: &'a str,
+}
+
+const NAME_FIELDS: [&str; 7] = [
+    "stem", "width", "height", "quality", "encoder", "density", "ext",
+];
+
+pub fn validate_name_template(template: &str) -> Result<()> {
+    for (start, _) in template.match_indices('{') {
+        let field = template[start + 1..]
+            .split_once('}')
+            .map(|(field, _)| field)
+            .ok_or(OptimizeError::InvalidOption(
+                "Unclosed placeholder in name template".to_string(),
+            ))?;
+        if !NAME_FIELDS.contains(&field) {
+            return Err(OptimizeError::InvalidOption(format!(
+                "Unknown placeholder {{{field}}} in name template, expected one of {}",
+                NAME_FIELDS.join(", ")
+            )));
+        }
+    }
+    if !template.contains("{stem}") {
+        return Err(OptimizeError::InvalidOption(
+            "Name template must contain {stem}".to_string(),
+        ));
+    }
+    if !template.contains("{ext}") && Path::new(template).extension().is_none() {
+        return Err(OptimizeError::InvalidOption(
+            "Name template must contain {ext} or end in a file extension".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn render_name_template(template: &str, fields: &NameFields) -> String {
+    let quality = fields.quality.map(|q| q.to_string()).unwrap_or_default();
+    // {stem} is substituted last so placeholders in a file name are left alone
+    template
+        .replace("{width}", &fields.width.to_string())
+        .replace("{height}", &fields.height.to_string())
+        .replace("{quality}", &quality)
+        .replace("{encoder}", fields.encoder.unwrap_or_default())
+        .replace("{density}", fields.density)
+        .replace("{ext}", fields.ext)
+        .replace("{stem}", fields.stem)
+}
+
+pub fn verify_encoded(encoded: &[u8], width: usize, height: usize) -> Result<()> {
+    let decoded = image::load_from_memory(encoded).map_err(|err| {
+        OptimizeError::Verification(format!("Encoded image failed to decode: {err}"))
+    })?;
+    let (decoded_w, decoded_h) = decoded.dimensions();
+    if (decoded_w as usize, decoded_h as usize) != (width, height) {
+        return Err(OptimizeError::Verification(format!(
+            "Encoded image is {decoded_w}x{decoded_h}, expected {width}x{height}"
+        )));
+    }
+    Ok(())
+}
+
+// Decodes an encoded output into the same pixel layout as the original it's compared against
+fn decode_for_comparison(
+    original: &[u8],
+    encoded: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<Vec<u8>> {
+    let decoded = image::load_from_memory(encoded).map_err(|err| {
+        OptimizeError::Verification(format!("Encoded image failed to decode: {err}"))
+    })?;
+    if has_alpha(original, width, height) {
+        Ok(decoded.to_rgba8().into_raw())
+    } else {
+        Ok(decoded.to_rgb8().into_raw())
+    }
+}
+
+fn dssim(original: &[u8], decoded: &[u8], width: usize, height: usize) -> Result<f64> {
+    let dssim = dssim_core::Dssim::new();
+    let (original, modified) = if has_alpha(original, width, height) {
+        (
+            dssim.create_image_rgba(original.as_rgba(), width, height),
+            dssim.create_image_rgba(decoded.as_rgba(), width, height),
+        )
+    } else {
+        (
+            dssim.create_image_rgb(original.as_rgb(), width, height),
+            dssim.create_image_rgb(decoded.as_rgb(), width, height),
+        )
+    };
+    let original = original.ok_or(OptimizeError::Verification(
+        "Unable to prepare original image for comparison".to_string(),
+    ))?;
+    let modified = modified.ok_or(OptimizeError::Verification(
+        "Unable to prepare encoded image for comparison".to_string(),
+    ))?;
+    let (dssim_value, _) = dssim.compare(&original, modified);
+    Ok(f64::from(dssim_value))
+}
+
+// Identical images have an infinite PSNR, which JSON can't hold, so it tops out at 100 dB
+fn psnr(original: &[u8], decoded: &[u8]) -> f64 {
+    let squared_error: f64 = original
+        .iter()
+        .zip(decoded)
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mse = squared_error / original.len().max(1) as f64;
+    if mse == 0.0 {
+        return 100.0;
+    }
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(100.0)
+}
+
+pub fn ssim(original: &[u8], encoded: &[u8], width: usize, height: usize) -> Result<f64> {
+    let decoded = decode_for_comparison(original, encoded, width, height)?;
+    let dssim_value = dssim(original, &decoded, width, height)?;
+    Ok(1.0 / (1.0 + dssim_value))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QualityMetrics {
+    pub psnr: f64,
+    pub dssim: f64,
+}
+
+pub fn quality_metrics(
+    original: &[u8],
+    encoded: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<QualityMetrics> {
+    let decoded = decode_for_comparison(original, encoded, width, height)?;
+    Ok(QualityMetrics {
+        psnr: psnr(original, &decoded),
+        dssim: dssim(original, &decoded, width, height)?,
+    })
 }
 
 pub fn ensure_parent_directory_exists(path: &Path) -> io::Result<()> {
@@ -70,13 +1756,461 @@ pub fn ensure_parent_directory_exists(path: &Path) -> io::Result<()> {
     Ok(())
 }
 
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WebPOptions {
+    pub method: Option<u8>,
+    pub alpha_quality: Option<u8>,
+    pub near_lossless: Option<u8>,
+    pub sharp_yuv: bool,
+}
+
+#[cfg(not(feature = "native"))]
+pub fn compress_webp(
+    _img: &[u8],
+    _width: u32,
+    _height: u32,
+    _quality: f32,
+    _lossless: bool,
+    _options: &WebPOptions,
+    _metadata: &[MetadataSegment],
+) -> Result<Vec<u8>> {
+    Err(native_only(Encoder::WebP))
+}
+
+#[cfg(feature = "native")]
 pub fn compress_webp(
     img: &[u8],
     width: u32,
     height: u32,
     quality: f32,
-) -> Result<Vec<u8>, anyhow::Error> {
-    let encoder = webp::Encoder::from_rgb(img, width, height);
-    let encoded_img = (*encoder.encode(quality)).to_vec();
-    Ok(encoded_img)
+    lossless: bool,
+    options: &WebPOptions,
+    metadata: &[MetadataSegment],
+) -> Result<Vec<u8>> {
+    if !quality.is_finite() {
+        return Err(OptimizeError::InvalidOption(format!(
+            "WebP quality must be a finite number, got {quality}"
+        )));
+    }
+    // Lossy quality 100 is bigger than lossless output while still not being lossless
+    let lossless = lossless || quality >= 100.0;
+
+    let encoder = if has_alpha(img, width as usize, height as usize) {
+        webp::Encoder::from_rgba(img, width, height)
+    } else {
+        webp::Encoder::from_rgb(img, width, height)
+    };
+    if *options == WebPOptions::default() {
+        let encoded_img = if lossless {
+            encoder.encode_lossless()
+        } else {
+            encoder.encode(quality)
+        };
+        return embed_webp_metadata(&encoded_img, width, height, metadata);
+    }
+
+    let config = webp_config(quality, lossless, options)?;
+    let encoded_img = encoder
+        .encode_advanced(&config)
+        .map_err(|err| OptimizeError::encode(Encoder::WebP, format!("{err:?}")))?;
+    embed_webp_metadata(&encoded_img, width, height, metadata)
+}
+
+#[cfg(feature = "native")]
+fn webp_config(quality: f32, lossless: bool, options: &WebPOptions) -> Result<webp::WebPConfig> {
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| OptimizeError::encode(Encoder::WebP, "Error creating WebP encoder config"))?;
+    config.quality = quality;
+    if let Some(method) = options.method {
+        config.method = method.into();
+    }
+    if let Some(alpha_quality) = options.alpha_quality {
+        config.alpha_quality = alpha_quality.into();
+    }
+    // libwebp only applies near-lossless preprocessing in lossless mode
+    if lossless || options.near_lossless.is_some() {
+        config.lossless = 1;
+    }
+    if let Some(near_lossless) = options.near_lossless {
+        config.near_lossless = near_lossless.into();
+    }
+    // Slower RGB to YUV conversion that keeps thin colored edges crisp
+    config.use_sharp_yuv = options.sharp_yuv.into();
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn reducing_16_bit_dithers_between_neighbouring_levels() {
+        // Halfway between 100 and 101 in 8 bits
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(8, 8, Rgb([25828; 3])));
+        let DynamicImage::ImageRgb8(reduced) = reduce_to_8_bit(img) else {
+            panic!("expected 8-bit RGB");
+        };
+        let values = reduced.as_raw();
+        assert!(values.iter().all(|&value| value == 100 || value == 101));
+        assert!(values.contains(&100) && values.contains(&101));
+        let mean = values.iter().map(|&value| value as f64).sum::<f64>() / values.len() as f64;
+        assert!((mean - 100.5).abs() < 0.1, "{mean}");
+    }
+
+    #[test]
+    fn reducing_16_bit_keeps_alpha() {
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(4, 4, Rgba([0, 0, 0, 32768])));
+        let DynamicImage::ImageRgba8(reduced) = reduce_to_8_bit(img) else {
+            panic!("expected 8-bit RGBA");
+        };
+        assert!(reduced.pixels().all(|pixel| pixel[3] == 128));
+    }
+
+    #[test]
+    fn reducing_hdr_rolls_off_highlights() {
+        let img = DynamicImage::ImageRgb32F(ImageBuffer::from_fn(4, 1, |x, _| {
+            Rgb([[0.0, 0.5, 4.0, 100.0][x as usize]; 3])
+        }));
+        let DynamicImage::ImageRgb8(reduced) = reduce_to_8_bit(img) else {
+            panic!("expected 8-bit RGB");
+        };
+        let levels: Vec<u8> = reduced.pixels().map(|pixel| pixel[0]).collect();
+        assert_eq!(levels[0], 0);
+        assert!(
+            levels.windows(2).all(|pair| pair[0] < pair[1]),
+            "{levels:?}"
+        );
+        assert!(levels[3] < 255, "{levels:?}");
+    }
+
+    #[test]
+    fn eight_bit_sources_are_left_alone() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([1, 2, 3])));
+        assert_eq!(reduce_to_8_bit(img.clone()), img);
+    }
+
+    #[test]
+    fn name_templates_fill_every_placeholder() {
+        let fields = NameFields {
+            stem: "hero",
+            width: 640,
+            height: 360,
+            quality: Some(75.0),
+            encoder: Some("webp"),
+            density: "@2x",
+            ext: "webp",
+        };
+        let render = |template| {
+            validate_name_template(template).unwrap();
+            render_name_template(template, &fields)
+        };
+        assert_eq!(render("{stem}-{width}w.{ext}"), "hero-640w.webp");
+        assert_eq!(
+            render("{stem}_{width}x{height}_q{quality}.{ext}"),
+            "hero_640x360_q75.webp"
+        );
+        assert_eq!(
+            render("{encoder}/{stem}{density}.{ext}"),
+            "webp/hero@2x.webp"
+        );
+        assert_eq!(render("{stem}-{width}.jpg"), "hero-640.jpg");
+    }
+
+    #[test]
+    fn name_templates_need_a_stem_and_an_extension() {
+        let message = |template| validate_name_template(template).unwrap_err().to_string();
+        assert!(message("{width}w.{ext}").contains("{stem}"));
+        assert!(message("{stem}-{width}w").contains("{ext}"));
+        assert!(message("{stem}-{size}.{ext}").contains("{size}"));
+        assert!(message("{stem}-{width.{ext}").contains("placeholder"));
+    }
+
+    #[test]
+    fn sharpening_raises_contrast_at_edges() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(16, 8, |x, _| {
+            Rgb([if x < 8 { 100 } else { 150 }; 3])
+        }));
+        assert_eq!(sharpen(&img, 0.0), img);
+
+        let sharpened = sharpen(&img, 1.0).into_rgb8();
+        let (dark, bright) = (sharpened.get_pixel(7, 4)[0], sharpened.get_pixel(8, 4)[0]);
+        assert!(dark < 100 && bright > 150, "{dark} {bright}");
+        // Flat areas away from the edge stay as they were
+        assert_eq!(sharpened.get_pixel(1, 4)[0], 100);
+        assert_eq!(sharpened.get_pixel(14, 4)[0], 150);
+    }
+
+    fn resize_config(src: (usize, usize), dest: (usize, usize)) -> ResizeConfig {
+        ResizeConfig {
+            src_width: src.0,
+            src_height: src.1,
+            dest_width: dest.0,
+            dest_height: dest.1,
+            filter: ResizeFilter::default(),
+        }
+    }
+
+    #[test]
+    fn resizing_odd_dimensions_fills_the_whole_buffer() {
+        let img = vec![128; 101 * 67 * 3];
+        let resized = resize(&img, resize_config((101, 67), (37, 25))).unwrap();
+        assert_eq!(resized.len(), 37 * 25 * 3);
+        let img = vec![128; 101 * 67 * 4];
+        let resized = resize_rgba(&img, resize_config((101, 67), (37, 25))).unwrap();
+        assert_eq!(resized.len(), 37 * 25 * 4);
+    }
+
+    #[test]
+    fn wrong_length_buffers_are_rejected() {
+        // RGBA bytes handed over as RGB
+        let rgba = vec![0; 10 * 10 * 4];
+        let err = resize(&rgba, resize_config((10, 10), (5, 5))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected 300 bytes for a 10x10 RGB image, got 400"
+        );
+        let err = resize_rgba(&rgba[1..], resize_config((10, 10), (5, 5))).unwrap_err();
+        assert!(matches!(err, OptimizeError::Resize(_)), "{err}");
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn webp_options_reach_the_encoder_config() {
+        let options = WebPOptions {
+            method: Some(6),
+            alpha_quality: Some(50),
+            ..WebPOptions::default()
+        };
+        let config = webp_config(80.0, false, &options).unwrap();
+        assert_eq!(
+            (
+                config.quality,
+                config.method,
+                config.alpha_quality,
+                config.lossless
+            ),
+            (80.0, 6, 50, 0)
+        );
+
+        let img = vec![200; 16 * 16 * 4];
+        let encoded = compress_webp(&img, 16, 16, 80.0, false, &options, &[]).unwrap();
+        assert_eq!(
+            (&encoded[..4], &encoded[8..12]),
+            (&b"RIFF"[..], &b"WEBP"[..])
+        );
+    }
+
+    // Luma sampling factors from the start of frame, chroma is always 1x1
+    #[cfg(feature = "native")]
+    fn luma_sampling(jpeg: &[u8]) -> u8 {
+        let mut i = 2;
+        loop {
+            let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+            if (0xC0..=0xC2).contains(&jpeg[i + 1]) {
+                return jpeg[i + 11];
+            }
+            i += 2 + length;
+        }
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn chroma_subsampling_reaches_mozjpeg() {
+        let img = crate::testing::gradient(64, 48).into_rgb8();
+        let encode = |subsampling| {
+            let options = MozJpegOptions {
+                subsampling,
+                ..Default::default()
+            };
+            compress_mozjpeg(img.as_raw(), 64, 48, 75.0, &options, &[]).unwrap()
+        };
+        let full = encode(Some(ChromaSubsampling::Yuv444));
+        assert_ne!(full, encode(None));
+        assert_eq!(luma_sampling(&full), 0x11);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn slower_webp_methods_compress_better() {
+        let img = crate::testing::gradient(128, 96).into_bytes();
+        let size = |method| {
+            let options = WebPOptions {
+                method: Some(method),
+                ..WebPOptions::default()
+            };
+            compress_webp(&img, 128, 96, 80.0, false, &options, &[])
+                .unwrap()
+                .len()
+        };
+        assert!(size(6) < size(0), "{} >= {}", size(6), size(0));
+    }
+
+    #[test]
+    fn verification_catches_broken_and_mis_sized_outputs() {
+        let mut png = vec![];
+        DynamicImage::new_rgb8(32, 24)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        verify_encoded(&png, 32, 24).unwrap();
+
+        let err = verify_encoded(&png[..png.len() / 2], 32, 24).unwrap_err();
+        assert!(matches!(err, OptimizeError::Verification(_)), "{err}");
+        let err = verify_encoded(&png, 32, 25).unwrap_err();
+        assert_eq!(err.to_string(), "Encoded image is 32x24, expected 32x25");
+    }
+
+    #[test]
+    fn rgb_bytes_have_three_bytes_per_pixel_whatever_the_source() {
+        let rgba = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(3, 2, Rgba([10, 20, 30, 40])));
+        assert_eq!(to_rgb8_bytes(&rgba), ([10, 20, 30].repeat(6), 3, 2));
+        let luma = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(2, 2, image::Luma([77])));
+        assert_eq!(to_rgb8_bytes(&luma), (vec![77; 12], 2, 2));
+
+        let resized = resize_image(&luma, 1, 1, ResizeFilter::default()).unwrap();
+        assert_eq!(resized.as_rgb8().unwrap().as_raw(), &[77, 77, 77]);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn webp_quality_100_is_encoded_lossless_and_nan_is_rejected() {
+        let img: Vec<u8> = (0..32 * 32 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let encode = |quality, lossless| {
+            compress_webp(
+                &img,
+                32,
+                32,
+                quality,
+                lossless,
+                &WebPOptions::default(),
+                &[],
+            )
+        };
+        let lossless = encode(75.0, true).unwrap();
+        assert_eq!(encode(100.0, false).unwrap(), lossless);
+        let lossy = encode(80.0, false).unwrap();
+        assert_ne!(lossy, lossless);
+        assert_eq!(&lossy[8..12], b"WEBP");
+
+        for quality in [f32::NAN, f32::INFINITY] {
+            let err = encode(quality, false).unwrap_err();
+            assert!(matches!(err, OptimizeError::InvalidOption(_)), "{err}");
+        }
+    }
+
+    // A minimal v2 matrix/TRC profile with the Display P3 primaries and a 2.2 gamma
+    fn display_p3_profile() -> Vec<u8> {
+        let xyz = |[x, y, z]: [f64; 3]| {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for value in [x, y, z] {
+                tag.extend(((value * 65536.0).round() as i32).to_be_bytes());
+            }
+            tag
+        };
+        let gamma = b"curv\0\0\0\0\0\0\0\x01\x02\x33\0\0".to_vec();
+        let tags = [
+            (b"wtpt", xyz([0.9642, 1.0, 0.8249])),
+            (b"rXYZ", xyz([0.5151, 0.2412, -0.0011])),
+            (b"gXYZ", xyz([0.2920, 0.6922, 0.0419])),
+            (b"bXYZ", xyz([0.1571, 0.0666, 0.7841])),
+            (b"rTRC", gamma.clone()),
+            (b"gTRC", gamma.clone()),
+            (b"bTRC", gamma),
+        ];
+
+        let mut header = vec![0; 128];
+        header[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        header[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = vec![];
+        let data_start = header.len() + 4 + tags.len() * 12;
+        for (signature, tag) in &tags {
+            table.extend_from_slice(*signature);
+            table.extend(((data_start + data.len()) as u32).to_be_bytes());
+            table.extend((tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+        }
+        let mut profile = [header, table, data].concat();
+        let len = profile.len() as u32;
+        profile[..4].copy_from_slice(&len.to_be_bytes());
+        profile
+    }
+
+    #[test]
+    fn display_p3_colors_are_converted_to_srgb() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([180, 120, 120])
+            } else {
+                Rgb([128, 128, 128])
+            }
+        }));
+        let converted = convert_to_srgb(img, &display_p3_profile())
+            .unwrap()
+            .into_rgb8();
+        // P3 covers more saturated reds, so the same values are more saturated in sRGB
+        let Rgb([r, g, b]) = *converted.get_pixel(0, 0);
+        assert!(r > 185 && g < 120 && b < 120, "{r} {g} {b}");
+        let grey = converted.get_pixel(1, 0);
+        assert!(
+            grey.0.iter().all(|&value| value.abs_diff(128) <= 2),
+            "{grey:?}"
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn near_lossless_switches_libwebp_to_lossless_mode() {
+        let near_lossless = WebPOptions {
+            near_lossless: Some(60),
+            ..WebPOptions::default()
+        };
+        let config = webp_config(80.0, false, &near_lossless).unwrap();
+        assert_eq!((config.lossless, config.near_lossless), (1, 60));
+        // Left at libwebp's default of 100, which is off
+        let config = webp_config(80.0, true, &WebPOptions::default()).unwrap();
+        assert_eq!((config.lossless, config.near_lossless), (1, 100));
+    }
+
+    #[test]
+    fn flattening_blends_with_the_background() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([0, 0, 0, 128]),
+            1 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([255, 0, 0, 0]),
+        }));
+        let white = flatten(&img, [255, 255, 255]).into_rgb8();
+        assert_eq!(white.get_pixel(0, 0), &Rgb([127, 127, 127]));
+        assert_eq!(white.get_pixel(1, 0), &Rgb([255, 0, 0]));
+        assert_eq!(white.get_pixel(2, 0), &Rgb([255, 255, 255]));
+
+        let background = parse_hex_color("#336699").unwrap();
+        let colored = flatten(&img, background).into_rgb8();
+        assert_eq!(colored.get_pixel(0, 0), &Rgb([25, 51, 76]));
+        assert_eq!(colored.get_pixel(2, 0), &Rgb([0x33, 0x66, 0x99]));
+    }
+
+    #[test]
+    fn hex_colors_accept_short_and_long_forms() {
+        assert_eq!(parse_hex_color("#fff").unwrap(), [255, 255, 255]);
+        assert_eq!(parse_hex_color("336699").unwrap(), [0x33, 0x66, 0x99]);
+        for invalid in ["#ffff", "#gggggg", "", "#ü12"] {
+            assert!(parse_hex_color(invalid).is_err(), "{invalid}");
+        }
+        assert_eq!(format_hex_color([0x33, 0x66, 0x99]), "#336699");
+    }
+
+    #[test]
+    fn scales_are_percentages_or_fractions() {
+        assert_eq!(parse_scale("50%").unwrap(), 50.0);
+        assert_eq!(parse_scale(" 75 ").unwrap(), 75.0);
+        assert_eq!(parse_scale("0.5").unwrap(), 50.0);
+        assert_eq!(parse_scale("1").unwrap(), 1.0);
+        assert_eq!(parse_scale("1.5").unwrap(), 1.5);
+        for invalid in ["half", "NaN%", "inf", "%"] {
+            assert!(parse_scale(invalid).is_err(), "{invalid}");
+        }
+    }
 }
@@ -0,0 +1,89 @@
+use image::DynamicImage;
+use serde::Deserialize;
+
+use crate::decode;
+use crate::error::Result;
+use crate::optimizer::{self, Encoder, Optimizer, UpscalePolicy, VariantSpec};
+use crate::utils::DecodeLimits;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VariantOptions {
+    pub widths: Vec<usize>,
+    pub encoders: Vec<Encoder>,
+    pub quality: Option<f32>,
+    #[serde(skip)]
+    pub limits: DecodeLimits,
+}
+
+// One output per width and encoder. Widths past the source are skipped, and with none left the
+// source size is encoded. Without encoders this is mozjpeg, and without a quality each encoder
+// uses its own default.
+pub fn optimize_image(
+    img: DynamicImage,
+    options: &VariantOptions,
+) -> Result<Vec<(VariantSpec, Vec<u8>)>> {
+    optimize(Optimizer::new(img, "input"), options)
+}
+
+// Decodes the same way the CLI does, with the limits checked against the header first.
+// Metadata from the upload is not carried over to the outputs.
+pub fn optimize_bytes(
+    bytes: &[u8],
+    options: &VariantOptions,
+) -> Result<Vec<(VariantSpec, Vec<u8>)>> {
+    optimize(
+        decode::optimizer_for_bytes(bytes, &options.limits)?,
+        options,
+    )
+}
+
+fn optimize(
+    mut optimizer: Optimizer,
+    options: &VariantOptions,
+) -> Result<Vec<(VariantSpec, Vec<u8>)>> {
+    let dimensions = optimizer.dimensions();
+    optimizer.set_encoders(options.encoders.clone());
+    if let Some(quality) = options.quality {
+        optimizer.set_quality(quality);
+    }
+    let targets = optimizer::responsive_dimensions(dimensions, &options.widths);
+    let targets = optimizer::apply_upscale_policy(dimensions, targets, &UpscalePolicy::Skip)?;
+    optimizer.set_targets(targets)?;
+    optimizer.optimize_to_variants()
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use image::ImageFormat;
+
+    #[test]
+    fn uploads_get_a_variant_per_width_and_encoder() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 32, |x, _| {
+            image::Rgba([0, 0, 255, if x < 32 { 0 } else { 255 }])
+        }));
+        let options = VariantOptions {
+            widths: vec![16, 128],
+            encoders: vec![Encoder::WebP, Encoder::MozJpeg],
+            ..Default::default()
+        };
+        let variants = optimize_bytes(&testing::encode(&img, ImageFormat::Png), &options).unwrap();
+        let specs: Vec<_> = variants
+            .iter()
+            .map(|(spec, _)| (spec.width, spec.height, spec.encoder.clone()))
+            .collect();
+        assert_eq!(specs, [(16, 8, Encoder::WebP), (16, 8, Encoder::MozJpeg)]);
+
+        let webp = image::load_from_memory(&variants[0].1).unwrap();
+        assert!(webp.color().has_alpha());
+        assert_eq!(webp.to_rgba8().get_pixel(2, 4)[3], 0);
+        let jpeg = image::load_from_memory(&variants[1].1).unwrap();
+        assert_eq!(
+            image::guess_format(&variants[1].1).unwrap(),
+            ImageFormat::Jpeg
+        );
+        assert!(!jpeg.color().has_alpha());
+    }
+}
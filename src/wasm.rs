@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use crate::decode;
+use crate::optimizer::{self, Encoder};
+use crate::utils::DecodeLimits;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Options {
+    width: Option<usize>,
+    encoder: Option<Encoder>,
+    quality: Option<f32>,
+    max_pixels: Option<u64>,
+    max_memory: Option<u64>,
+}
+
+// Options are JSON such as {"width": 1280, "encoder": "avif", "quality": 60}, anything left out
+// gets the same defaults as the command line. WebP and JPEG XL need the native feature, which
+// doesn't build for wasm32.
+#[wasm_bindgen]
+pub fn optimize_bytes(input: &[u8], options: &str) -> Result<Vec<u8>, JsError> {
+    let options: Options = if options.trim().is_empty() {
+        Options::default()
+    } else {
+        serde_json::from_str(options)?
+    };
+    let limits = DecodeLimits {
+        max_pixels: options.max_pixels,
+        max_memory: options.max_memory,
+    };
+    let mut optimizer = decode::optimizer_for_bytes(input, &limits)?;
+    let dimensions = optimizer.dimensions();
+    if let Some(encoder) = options.encoder {
+        optimizer.set_encoder(encoder);
+    }
+    if let Some(quality) = options.quality {
+        optimizer.set_quality(quality);
+    }
+    if let Some(width) = options.width {
+        optimizer.set_targets(optimizer::responsive_dimensions(dimensions, &[width]))?;
+    }
+    Ok(optimizer.optimize_to_bytes()?)
+}
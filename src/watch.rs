@@ -0,0 +1,52 @@
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::batch::SourceFilter;
+
+// Editors often write a file several times in quick succession
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+pub fn watch(
+    roots: &[PathBuf],
+    filter: &SourceFilter,
+    mut on_change: impl FnMut(&str),
+) -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for root in roots {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        info!("Watching {}", root.display());
+    }
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut event = rx.recv()?;
+        loop {
+            match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    changed.extend(event.paths.into_iter().filter(|path| {
+                        path.is_file() && filter.matches(path) && !filter.is_output(path)
+                    }));
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Watch error: {err}"),
+            }
+            event = match rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("File watcher stopped")),
+            };
+        }
+        for path in &changed {
+            on_change(&path.to_string_lossy());
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// The binary needs the native codecs
+#![cfg(feature = "native")]
+
+use std::{
+    io::{Cursor, Write},
+    process::{Command, Stdio},
+};
+
+use image::{DynamicImage, GenericImageView, ImageFormat};
+
+#[test]
+fn piped_sources_come_back_on_stdout() {
+    let mut png = Cursor::new(vec![]);
+    DynamicImage::new_rgb8(64, 48)
+        .write_to(&mut png, ImageFormat::Png)
+        .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_img-optimizer-and-resizer"))
+        .args(["-", "--widths", "32", "--quality", "75"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(png.get_ref())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let piped = image::load_from_memory(&output.stdout).unwrap();
+    assert_eq!(piped.dimensions(), (32, 24));
+}